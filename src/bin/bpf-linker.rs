@@ -2,29 +2,68 @@
 
 use std::{
     env,
-    ffi::CString,
-    fs, io,
+    ffi::{CStr, CString},
+    fmt, fs,
+    io::{self, IsTerminal as _, Read as _},
     path::{Component, Path, PathBuf},
+    process,
     str::FromStr,
 };
 
-use bpf_linker::{Cpu, Linker, LinkerInput, LinkerOptions, OptLevel, OutputType};
+use bpf_linker::{
+    BtfMode, CodeModel, Cpu, ExportSpec, JsonDiagnostic, Linker, LinkerError, LinkerInput,
+    LinkerOptions, MessageFormat, OptLevel, OutputType, RelocModel, Stage, SymbolDefinition,
+};
 use clap::{
     Parser,
     builder::{PathBufValueParser, TypedValueParser as _},
     error::ErrorKind,
 };
 use thiserror::Error;
-use tracing::{Level, info};
-use tracing_subscriber::{EnvFilter, fmt::MakeWriter, prelude::*};
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+    info,
+};
+use tracing_subscriber::{EnvFilter, Layer, fmt::MakeWriter, layer::Context, prelude::*};
 use tracing_tree::HierarchicalLayer;
 
 #[derive(Debug, Error)]
 enum CliError {
     #[error("optimization level needs to be between 0-3, s or z (instead was `{0}`)")]
     InvalidOptimization(String),
-    #[error("unknown emission type: `{0}` - expected one of: `llvm-bc`, `asm`, `llvm-ir`, `obj`")]
+    #[error("endianness needs to be `little` or `big` (instead was `{0}`)")]
+    InvalidEndian(String),
+    #[error("color needs to be `auto`, `always` or `never` (instead was `{0}`)")]
+    InvalidColor(String),
+    #[error(
+        "unknown emission type: `{0}` - expected one of: `llvm-bc` (or `bc`), `asm`, `llvm-ir`, `obj`"
+    )]
     InvalidOutputType(String),
+    #[error("failed to read the symbols to export from `{}`: {error}", path.display())]
+    ExportSymbols { path: PathBuf, error: io::Error },
+    #[error("failed to read the undefined symbols to allow from `{}`: {error}", path.display())]
+    AllowUndefinedFile { path: PathBuf, error: io::Error },
+    #[error("failed to write the dependency file `{}`: {error}", path.display())]
+    Depfile { path: PathBuf, error: io::Error },
+    #[error("failed to read the response file `{}`: {error}", path.display())]
+    ResponseFile { path: PathBuf, error: io::Error },
+    #[error("response file `{}` is nested too deeply", .0.display())]
+    ResponseFileDepth(PathBuf),
+    #[error("failed to read the input from stdin: {0}")]
+    Stdin(io::Error),
+    #[error("stdin (`-`) can only be given once as an input")]
+    DuplicateStdin,
+    #[error("`--emit={0}` is given more than once")]
+    DuplicateEmit(&'static str),
+    #[error("the default output path `{}` would overwrite an input, use `-o` to set it", .0.display())]
+    OutputOverwritesInput(PathBuf),
+    #[error("invalid LLVM argument `{0}`, expected `-option` or `-option=value`")]
+    InvalidLlvmArg(String),
+    #[error(transparent)]
+    UnknownLlvmArg(LinkerError),
+    #[error("`--print-symbols` needs a path when the output is written to stdout")]
+    PrintSymbolsToStdout,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -46,6 +85,68 @@ impl FromStr for CliOptLevel {
     }
 }
 
+/// The endianness selected with `--endian`, mapped to the `bpfel` and `bpfeb` targets.
+#[derive(Copy, Clone, Debug)]
+enum CliEndian {
+    Little,
+    Big,
+}
+
+impl CliEndian {
+    fn target(self) -> &'static CStr {
+        match self {
+            Self::Little => c"bpfel",
+            Self::Big => c"bpfeb",
+        }
+    }
+}
+
+impl FromStr for CliEndian {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "little" => Self::Little,
+            "big" => Self::Big,
+            _ => return Err(CliError::InvalidEndian(s.to_string())),
+        })
+    }
+}
+
+/// When to color the output, selected with `--color`.
+#[derive(Copy, Clone, Debug)]
+enum CliColor {
+    Auto,
+    Always,
+    Never,
+}
+
+impl CliColor {
+    /// Returns whether to color the output written to a stream, given whether the stream is a
+    /// terminal. With `auto`, the output is colored when it goes to a terminal and the `NO_COLOR`
+    /// environment variable is unset or empty.
+    fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => is_terminal && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+        }
+    }
+}
+
+impl FromStr for CliColor {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "always" => Self::Always,
+            "never" => Self::Never,
+            _ => return Err(CliError::InvalidColor(s.to_string())),
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct CliOutputType(OutputType);
 
@@ -54,7 +155,7 @@ impl FromStr for CliOutputType {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(Self(match s {
-            "llvm-bc" => OutputType::Bitcode,
+            "llvm-bc" | "bc" => OutputType::Bitcode,
             "asm" => OutputType::Assembly,
             "llvm-ir" => OutputType::LlvmAssembly,
             "obj" => OutputType::Object,
@@ -63,6 +164,224 @@ impl FromStr for CliOutputType {
     }
 }
 
+impl CliOutputType {
+    /// Returns the canonical `--emit` name of the output type.
+    fn name(self) -> &'static str {
+        match self.0 {
+            OutputType::Bitcode => "llvm-bc",
+            OutputType::Assembly => "asm",
+            OutputType::LlvmAssembly => "llvm-ir",
+            OutputType::Object => "obj",
+        }
+    }
+
+    /// Returns the file extension of the output type.
+    fn extension(self) -> &'static str {
+        match self.0 {
+            OutputType::Bitcode => "bc",
+            OutputType::Assembly => "s",
+            OutputType::LlvmAssembly => "ll",
+            OutputType::Object => "o",
+        }
+    }
+}
+
+/// An `--emit` value: an output type and, optionally, the path to write it to.
+#[derive(Clone, Debug)]
+struct CliEmit {
+    output_type: CliOutputType,
+    path: Option<PathBuf>,
+}
+
+impl FromStr for CliEmit {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (output_type, path) = match s.split_once('=') {
+            Some((output_type, path)) => (output_type, Some(PathBuf::from(path))),
+            None => (s, None),
+        };
+        Ok(Self {
+            output_type: output_type.parse()?,
+            path,
+        })
+    }
+}
+
+/// Returns the path and type of every output. An output without an explicit path is written to
+/// `output` when it is the only one, and to `output` with the extension of its type otherwise.
+fn emit_outputs(emit: &[CliEmit], output: &Path) -> Result<Vec<(OutputType, PathBuf)>, CliError> {
+    for (i, CliEmit { output_type, .. }) in emit.iter().enumerate() {
+        if emit[..i]
+            .iter()
+            .any(|emit| emit.output_type.0 == output_type.0)
+        {
+            return Err(CliError::DuplicateEmit(output_type.name()));
+        }
+    }
+    Ok(emit
+        .iter()
+        .map(
+            |&CliEmit {
+                 output_type,
+                 ref path,
+             }| {
+                let path = match path {
+                    Some(path) => path.clone(),
+                    None if emit.len() == 1 => output.to_owned(),
+                    None => output.with_extension(output_type.extension()),
+                };
+                (output_type.0, path)
+            },
+        )
+        .collect())
+}
+
+/// Returns whether the input path is `-`, which reads the input from stdin.
+fn is_stdin(input: &Path) -> bool {
+    input.as_os_str() == "-"
+}
+
+/// Returns the output path used when `-o` is not given: the file name of the first input with
+/// the extension of the emitted file type, in the current directory.
+fn default_output(inputs: &[PathBuf], output_type: OutputType) -> Result<PathBuf, CliError> {
+    let extension = CliOutputType(output_type).extension();
+    let stem = inputs
+        .first()
+        .filter(|input| !is_stdin(input))
+        .and_then(|input| input.file_stem())
+        .unwrap_or("out".as_ref());
+    let output = Path::new(stem).with_extension(extension);
+    if inputs.contains(&output) {
+        return Err(CliError::OutputOverwritesInput(output));
+    }
+    Ok(output)
+}
+
+/// Joins CPU features into the comma separated string expected by LLVM, keeping only the last
+/// occurrence of every feature.
+fn join_cpu_features<'a, I>(features: I) -> CString
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut joined: Vec<&str> = Vec::new();
+    for feature in features
+        .into_iter()
+        .flat_map(|features| features.split(','))
+        .map(str::trim)
+        .filter(|feature| !feature.is_empty())
+    {
+        let name = feature.trim_start_matches(['+', '-']);
+        joined.retain(|f| f.trim_start_matches(['+', '-']) != name);
+        joined.push(feature);
+    }
+    CString::new(joined.join(",")).unwrap()
+}
+
+/// Parses an argument of `--llvm-args`. The linker rejects the options it doesn't accept when it
+/// is created, see [`Linker::new`].
+fn parse_llvm_arg(arg: &str) -> Result<CString, CliError> {
+    if arg.len() < 2 || !arg.starts_with('-') {
+        return Err(CliError::InvalidLlvmArg(arg.to_owned()));
+    }
+    CString::new(arg).map_err(|_| CliError::InvalidLlvmArg(arg.to_owned()))
+}
+
+/// Returns the symbols listed in an export symbols file, one per line. Surrounding whitespace is
+/// trimmed, and blank lines and lines starting with `#` are ignored.
+fn parse_export_symbols(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// The maximum nesting depth of response files, so that a response file including itself is
+/// reported instead of recursing forever.
+const MAX_RESPONSE_FILE_DEPTH: usize = 8;
+
+/// Replaces every `@path` argument with the arguments read from the response file at `path`.
+/// Response files can reference other response files.
+fn expand_response_files<I>(args: I, depth: usize) -> Result<Vec<String>, CliError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('@') {
+            Some(path) if !path.is_empty() => {
+                let path = PathBuf::from(path);
+                if depth == MAX_RESPONSE_FILE_DEPTH {
+                    return Err(CliError::ResponseFileDepth(path));
+                }
+                let contents = fs::read_to_string(&path)
+                    .map_err(|error| CliError::ResponseFile { path, error })?;
+                expanded.extend(expand_response_files(
+                    split_response_file(&contents),
+                    depth + 1,
+                )?);
+            }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Splits the contents of a response file into arguments. Arguments are separated by whitespace
+/// and can be quoted with `"` or `'`. A `\` escapes the character following it.
+fn split_response_file(contents: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                if let Some(c) = chars.next() {
+                    arg.get_or_insert_default().push(c);
+                }
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => arg.get_or_insert_default().push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                let _: &mut String = arg.get_or_insert_default();
+            }
+            (c, None) if c.is_whitespace() => args.extend(arg.take()),
+            (c, None) => arg.get_or_insert_default().push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// Escapes a path for use in a Makefile rule.
+fn escape_make_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => escaped.push('\\'),
+            '$' => escaped.push('$'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes a Makefile rule making `output` depend on all the `inputs`. Every input file is read
+/// while linking, including archives with no usable members.
+fn write_depfile(path: &Path, output: &Path, inputs: &[PathBuf]) -> io::Result<()> {
+    let mut rule = escape_make_path(output);
+    rule.push(':');
+    for input in inputs.iter().filter(|input| !is_stdin(input)) {
+        rule.push(' ');
+        rule.push_str(&escape_make_path(input));
+    }
+    rule.push('\n');
+    fs::write(path, rule)
+}
+
 fn parent_and_file_name(p: PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
     let mut comps = p.components();
     let file_name = comps
@@ -81,30 +400,113 @@ fn parent_and_file_name(p: PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
 #[command(version)]
 struct CommandLine {
     /// LLVM target triple. When not provided, the target is inferred from the inputs
-    #[clap(long)]
+    #[clap(long, visible_alias = "target-triple")]
     target: Option<CString>,
 
-    /// Target BPF processor. Can be one of `generic`, `probe`, `v1`, `v2`, `v3`
+    /// Target endianness, `little` for the `bpfel` target or `big` for the `bpfeb` target. Inputs
+    /// built for the other endianness are rejected
+    #[clap(long, value_name = "endianness", conflicts_with = "target")]
+    endian: Option<CliEndian>,
+
+    /// Target BPF processor. Can be one of `generic`, `probe`, `auto`, `v1`, `v2`, `v3`, `v4`.
+    /// `auto` selects the newest processor supported by the version of the running kernel
     #[clap(long, default_value = "generic")]
     cpu: Cpu,
 
     /// Enable or disable CPU features. The available features are: alu32, dummy, dwarfris. Use
     /// +feature to enable a feature, or -feature to disable it.  For example
     /// --cpu-features=+alu32,-dwarfris
-    #[clap(long, value_name = "features", default_value = "")]
+    #[clap(
+        long,
+        visible_alias = "features",
+        value_name = "features",
+        default_value = ""
+    )]
     cpu_features: CString,
 
-    /// Write output to <output>
+    /// Enable or disable a single CPU feature, for example `+alu32` or `-alu32`. Can be given
+    /// multiple times and is combined with `--cpu-features`. When a feature is given more than
+    /// once, the last occurrence wins
+    #[clap(long, value_name = "feature", action = clap::ArgAction::Append)]
+    target_feature: Vec<String>,
+
+    /// Relocation model. Can be one of `default`, `static`, `pic`, `dynamic-no-pic`
+    #[clap(long, visible_alias = "relocation-model", default_value = "default")]
+    reloc_model: RelocModel,
+
+    /// Code model. Can be one of `default`, `tiny`, `small`, `kernel`, `medium`, `large`. The BPF
+    /// backend currently doesn't take it into account
+    #[clap(long, default_value = "default")]
+    code_model: CodeModel,
+
+    /// Write output to <output>, or to stdout if <output> is `-`. When not provided, the output
+    /// is written to the current directory, named after the first input with the extension
+    /// matching `--emit`
     #[clap(short, long)]
-    output: PathBuf,
+    output: Option<PathBuf>,
+
+    /// Output type. Can be one of `llvm-bc` (or its alias `bc`), `asm`, `llvm-ir`, `obj`. Can be
+    /// a comma separated list, or given multiple times, to write several outputs from a single
+    /// link. `type=path` writes the output to `path`; otherwise, when there are several outputs,
+    /// the extension of `-o` is replaced with the one of the output type. When writing an output
+    /// fails, the other outputs are still written
+    #[clap(
+        long,
+        default_value = "obj",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Append
+    )]
+    emit: Vec<CliEmit>,
+
+    /// Also write the optimized LLVM IR to `path`, in addition to the `--emit` output
+    #[clap(long, value_name = "path")]
+    emit_ir_to: Option<PathBuf>,
+
+    /// Write a JSON array recording, for every global, alias and function, whether it matched an
+    /// export, whether it was internalized and whether it is undefined to `path`
+    #[clap(long, value_name = "path")]
+    emit_symbol_manifest: Option<PathBuf>,
 
-    /// Output type. Can be one of `llvm-bc`, `asm`, `llvm-ir`, `obj`
-    #[clap(long, default_value = "obj")]
-    emit: Vec<CliOutputType>,
+    /// Write a Makefile rule listing the files read to produce the output to `path`
+    #[clap(long, value_name = "path")]
+    depfile: Option<PathBuf>,
+
+    /// Emit BTF information. Can be one of `full`, `func-only` (the functions and their line
+    /// info, without the types) or `none`. `--btf` alone means `--btf=full`
+    #[clap(
+        long,
+        value_name = "mode",
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "none",
+        default_missing_value = "full"
+    )]
+    btf: BtfMode,
+
+    /// Strip all the debug info, so that neither DWARF nor BTF is emitted even with `--btf`. `-g0`
+    /// is an alias
+    #[clap(long, conflicts_with_all = ["dump_btf", "dump_btf_ext"])]
+    strip_debug: bool,
+
+    /// Strip the debug info like `--strip-debug`, and leave the internal functions which survive
+    /// optimization out of the symbol table. Loaders finding the BPF-to-BPF call targets through
+    /// the symbol table, like libbpf, can't load calls to such functions
+    #[clap(long, conflicts_with_all = ["dump_btf", "dump_btf_ext"])]
+    strip_all: bool,
+
+    /// Drop the BTF line info while keeping the BTF types and functions. Only has an effect with
+    /// `--btf`
+    #[clap(long)]
+    strip_line_info: bool,
+
+    /// Delete the global variables which are neither used nor exported after optimization
+    #[clap(long)]
+    strip_unused_globals: bool,
 
-    /// Emit BTF information
+    /// Drop the debug info of the functions that are not exported, so that only the exported
+    /// functions get BTF func info and line info. Only has an effect with `--btf`
     #[clap(long)]
-    btf: bool,
+    strip_internal_debug_info: bool,
 
     /// Permit automatic insertion of __bpf_trap calls.
     /// See: https://github.com/llvm/llvm-project/commit/ab391beb11f733b526b86f9df23734a34657d876
@@ -119,8 +521,52 @@ struct CommandLine {
     #[clap(short = 'O', default_value = "2")]
     optimize: Vec<CliOptLevel>,
 
-    /// Export the symbols specified in the file `path`. The symbols must be separated by new lines
-    #[clap(long, value_name = "path")]
+    /// LLVM pass pipeline to run instead of the one selected by `-O`, for example
+    /// `default<O2>,sroa,dce`. `default<ON>` must come first in the pipeline, otherwise it is
+    /// ignored. Can't be combined with `-O`
+    #[clap(long, value_name = "pipeline", conflicts_with = "optimize")]
+    passes: Option<String>,
+
+    /// LLVM passes to run at the end of the pipeline selected by `-O` or `--passes`, for example
+    /// `instcombine`
+    #[clap(
+        long,
+        value_name = "passes",
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    extra_passes: Option<String>,
+
+    /// Remove a pass from the pipeline. Can be one of `dce`, `loop-unroll`, `loop-vectorize`,
+    /// `slp-vectorizer`. Can be given multiple times
+    #[clap(long = "disable-pass", value_name = "name")]
+    disabled_passes: Vec<String>,
+
+    /// Don't unroll loops, to keep the programs within the instruction budget. Same as
+    /// `--disable-pass=loop-unroll`
+    #[clap(long, conflicts_with = "unroll_loops")]
+    no_loop_unroll: bool,
+
+    /// Don't vectorize loops. Same as `--disable-pass=loop-vectorize`
+    #[clap(long)]
+    no_vectorize: bool,
+
+    /// Don't vectorize straight-line code. Same as `--disable-pass=slp-vectorizer`
+    #[clap(long)]
+    no_slp_vectorize: bool,
+
+    /// The cost under which the inliner inlines a call, instead of the default of the optimization
+    /// level. Raise it to inline bigger functions
+    #[clap(long, value_name = "n")]
+    inline_threshold: Option<i32>,
+
+    /// Keep the unreferenced functions which are not exported. They stay internal but are not
+    /// deleted by dead code elimination
+    #[clap(long)]
+    disable_dce: bool,
+
+    /// Export the symbols specified in the file `path`. The symbols must be separated by new
+    /// lines. Blank lines and lines starting with `#` are ignored
+    #[clap(long, visible_alias = "export-file", value_name = "path")]
     export_symbols: Option<PathBuf>,
 
     /// Output logs to the given `path`
@@ -136,20 +582,144 @@ struct CommandLine {
     #[clap(long, value_name = "level")]
     log_level: Option<Level>,
 
-    /// Try hard to unroll loops. Useful when targeting kernels that don't support loops
+    /// Try hard to unroll loops. Useful when targeting kernels that don't support loops. With
+    /// `max-count`, unroll a loop at most `max-count` times and warn about the functions which
+    /// still contain a loop
+    #[clap(
+        long,
+        value_name = "max-count",
+        num_args = 0..=1,
+        require_equals = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    unroll_loops: Option<Option<u32>>,
+
+    /// Print how long each LLVM pass took to stderr
+    #[clap(long)]
+    time_passes: bool,
+
+    /// Print the LLVM statistics counters to stderr. Only LLVM builds with assertions or
+    /// `LLVM_FORCE_ENABLE_STATS` collect statistics
     #[clap(long)]
-    unroll_loops: bool,
+    stats: bool,
+
+    /// Only report the LLVM remarks whose message matches `regex`, in the logs and the diagnostics
+    #[clap(long, value_name = "regex")]
+    remarks_filter: Option<String>,
+
+    /// Warn about the functions whose estimated stack usage after optimization exceeds `bytes`.
+    /// The verifier rejects the programs using more than 512 bytes. Internal functions are not
+    /// checked
+    #[clap(long, value_name = "bytes", default_value_t = 512)]
+    stack_size_limit: u64,
+
+    /// Fail instead of warning about the functions exceeding --stack-size-limit
+    #[clap(long)]
+    stack_size_limit_error: bool,
 
     /// Ignore `noinline`/`#[inline(never)]`. Useful when targeting kernels that don't support function calls
     #[clap(long)]
     ignore_inline_never: bool,
 
+    /// Remove the function attribute `name`, like `optnone` or `noinline`, from every function
+    /// before optimizing. Can be given multiple times
+    #[clap(long = "remove-attr", value_name = "name", action = clap::ArgAction::Append)]
+    remove_attributes: Vec<String>,
+
+    /// Keep the `optnone` attributes, which are otherwise removed at `-O2` and higher, including
+    /// `-Os` and `-Oz`, so that unoptimized inputs still get optimized
+    #[clap(long)]
+    keep_optnone: bool,
+
     /// Dump the final IR module to the given `path` before generating the code
     #[clap(long, value_name = "path")]
     dump_module: Option<PathBuf>,
 
-    /// Extra command line arguments to pass to LLVM
-    #[clap(long, value_name = "args", use_value_delimiter = true, action = clap::ArgAction::Append)]
+    /// Write the raw contents of the `.BTF` section of the generated object to the given `path`.
+    /// Fails when no BTF is emitted, see `--btf`
+    #[clap(long, value_name = "path")]
+    dump_btf: Option<PathBuf>,
+
+    /// Write the raw contents of the `.BTF.ext` section of the generated object, holding the func
+    /// info and the line info, to the given `path`. Fails when no BTF is emitted, see `--btf`
+    #[clap(long, value_name = "path")]
+    dump_btf_ext: Option<PathBuf>,
+
+    /// Write the raw contents of the section `name` of the input objects, concatenated in link
+    /// order, to the given `path`. Fails when no input object has the section
+    #[clap(long, num_args = 2, value_names = ["name", "path"])]
+    dump_section: Option<Vec<String>>,
+
+    /// Write every input module as LLVM IR to `dir` before linking it, named after the input and
+    /// its position in the link order, and the linked module as LLVM IR and bitcode after linking,
+    /// after sanitizing the debug info and after optimization, named after the output. Defaults to
+    /// the current directory
+    #[clap(
+        long,
+        value_name = "dir",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "."
+    )]
+    save_temps: Option<PathBuf>,
+
+    /// Cache the optimized module and the object in `cache-dir`, and reuse them instead of
+    /// optimizing and generating the code again when linking the same inputs with the same options
+    #[clap(long, value_name = "cache-dir")]
+    incremental: Option<PathBuf>,
+
+    /// Print the attributes of every function to stderr after optimization
+    #[clap(long)]
+    dump_attributes: bool,
+
+    /// Print the name, linkage and visibility of every externally visible symbol after
+    /// optimization, like `nm`, to stdout or to `path`
+    #[clap(long, value_name = "path", num_args = 0..=1, require_equals = true)]
+    print_symbols: Option<Option<PathBuf>>,
+
+    /// The format of the `--print-symbols` output. Can be one of `human`, `json`. With `json`, the
+    /// symbols are printed as a JSON array of objects with the `name`, `kind`, `linkage`,
+    /// `visibility`, `defined` and `exported` keys
+    #[clap(long, value_name = "format", default_value = "human")]
+    output_format: MessageFormat,
+
+    /// Print the symbols removed by optimization, as `removed: <name>` lines, and the symbols given
+    /// internal linkage which survived, as `internalized: <name>` lines, to stderr
+    #[clap(long)]
+    print_eliminated: bool,
+
+    /// Print the number of functions, defined functions, global variables and instructions of the
+    /// linked module before and after optimization to stderr
+    #[clap(long)]
+    print_stats: bool,
+
+    /// Print the number of LLVM IR instructions of every function which isn't internal after
+    /// optimization, including the internal functions it calls, to stderr
+    #[clap(long)]
+    print_insn_count: bool,
+
+    /// Fail when a function which isn't internal has more than `n` LLVM IR instructions after
+    /// optimization, including the internal functions it calls. This approximates the
+    /// instruction limit of the verifier. The check runs before generating code, so no output is
+    /// written when it fails
+    #[clap(long, visible_alias = "max-instructions", value_name = "n")]
+    max_insns: Option<usize>,
+
+    /// Link and optimize the inputs, then stop before generating code. No output (including the
+    /// depfile) is written; the exit status tells whether the inputs link cleanly
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Extra command line arguments to pass to LLVM. When an option is given several times, the
+    /// last one wins. Only the options of the BPF backend and of the optimizations bpf-linker
+    /// knows, like `-unroll-threshold` or `-bpf-stack-size`, are accepted
+    #[clap(
+        long,
+        value_name = "args",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Append,
+        value_parser = parse_llvm_arg
+    )]
     llvm_args: Vec<CString>,
 
     /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
@@ -162,63 +732,326 @@ struct CommandLine {
     #[clap(long)]
     disable_memory_builtins: bool,
 
-    /// Input files. Can be object files or static libraries
+    /// Warn instead of failing when a symbol passed to `--export` or `--export-symbols` is not
+    /// defined by any input
+    #[clap(long)]
+    allow_missing_exports: bool,
+
+    /// Print how long each stage of the link took to stderr
+    #[clap(long)]
+    time_link: bool,
+
+    /// Run the LLVM module verifier on the linked module before and after optimization, including
+    /// a custom `--passes` pipeline
+    #[clap(long)]
+    verify: bool,
+
+    /// Append LLVM's `verify` pass to the optimization pipeline, including a custom `--passes`
+    /// pipeline. LLVM aborts the link when the optimized module is broken
+    #[clap(long)]
+    verify_module: bool,
+
+    /// Keep every symbol externally visible instead of internalizing the symbols which are not
+    /// exported
+    #[clap(long)]
+    export_all: bool,
+
+    /// Keep the linkage and visibility of every symbol like `--export-all`, for when the symbol
+    /// visibility is handled after linking, for example with `llvm-strip`. Can't be combined with
+    /// the options exporting symbols
+    #[clap(long, conflicts_with_all = ["export", "export_regex", "export_symbols"])]
+    no_internalize: bool,
+
+    /// Fail instead of warning when an `--export` glob pattern or an `--export-regex` matches no
+    /// symbol
+    #[clap(long)]
+    strict_exports: bool,
+
+    /// Fail instead of warning when a symbol is defined by several inputs
+    #[clap(long = "no-duplicate-symbols")]
+    deny_duplicate_symbols: bool,
+
+    /// Fail instead of warning when an input is built for a target other than BPF. Inputs built
+    /// for the host by a rustc without BPF support always are
+    #[clap(long)]
+    strict_triple: bool,
+
+    /// Input files. Can be object files or static libraries. `-` reads an input from stdin
     #[clap(required = true)]
     inputs: Vec<PathBuf>,
 
-    /// Comma separated list of symbols to export. See also `--export-symbols`
+    /// Comma separated list of symbols to export. Symbols containing `*` or `?` are glob
+    /// patterns: `*` matches any sequence of characters and `?` matches any single character. No
+    /// other glob syntax is supported, `[` and `{` match themselves, use `--export-regex` for
+    /// character classes. See also `--export-symbols`
     #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
     export: Vec<String>,
 
+    /// Export the symbols whose whole name matches the given regular expression
+    #[clap(long, value_name = "regex", action = clap::ArgAction::Append)]
+    export_regex: Vec<String>,
+
+    /// Comma separated list of symbols to keep the linkage and visibility of without exporting
+    /// them. Supports the glob patterns of `--export`
+    #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
+    keep_symbols: Vec<String>,
+
+    /// Redirect the references to `symbol` to `__wrap_symbol`, and the references to
+    /// `__real_symbol` to `symbol`, like the `--wrap` option of GNU ld. Can be given multiple
+    /// times
+    #[clap(long, value_name = "symbol", action = clap::ArgAction::Append)]
+    wrap: Vec<String>,
+
+    /// Define the integer global `name` as the constant `value`, given in decimal or as `0x`
+    /// hexadecimal, like the `--defsym` option of GNU ld. The value must fit in the type the
+    /// global is declared with. Can be given multiple times
+    #[clap(long, value_name = "name=value", action = clap::ArgAction::Append)]
+    defsym: Vec<SymbolDefinition>,
+
+    /// Comma separated list of functions which may be called without being defined by any input,
+    /// like kfuncs. Supports the glob patterns of `--export`. The link fails when the optimized
+    /// module calls any other undefined function, except the memory builtins and the BPF helpers,
+    /// whose names start with `bpf_` and which are not declared in the `.ksyms` section
+    #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
+    allow_undefined: Vec<String>,
+
+    /// Allow the undefined functions listed in the file `path`, like `--allow-undefined`. The
+    /// symbols must be separated by new lines. Blank lines and lines starting with `#` are ignored
+    #[clap(long, value_name = "path")]
+    allow_undefined_file: Option<PathBuf>,
+
     /// Whether to treat LLVM errors as fatal.
     #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
     fatal_errors: bool,
 
+    /// Fail the link when LLVM issues any diagnostic with warning or error severity
+    #[clap(long)]
+    fatal_warnings: bool,
+
+    /// Print the number of diagnostics of each severity LLVM issued to stderr at the end of the
+    /// link
+    #[clap(long)]
+    diagnostics_summary: bool,
+
+    /// How to print the diagnostics issued by LLVM. Can be one of `human`, `json`. With `json`,
+    /// each diagnostic, warning and error is printed to stderr as a JSON object with its `level`,
+    /// `message`, `file` and `stage`, one per line, instead of the logs
+    #[clap(long, value_name = "format", default_value = "human")]
+    message_format: MessageFormat,
+
+    /// When to color the logs and the severity of the diagnostics issued by LLVM. Can be one of
+    /// `auto`, `always`, `never`. With `auto`, the output is colored when it goes to a terminal and
+    /// `NO_COLOR` is unset or empty
+    #[clap(long, value_name = "when", default_value = "auto")]
+    color: CliColor,
+
     // The options below are for wasm-ld compatibility
     #[clap(long = "debug", hide = true)]
     _debug: bool,
 }
 
 /// Returns a [`HierarchicalLayer`](tracing_tree::HierarchicalLayer) for the
-/// given `writer`.
-fn tracing_layer<W>(writer: W) -> HierarchicalLayer<W>
+/// given `writer`, which uses ANSI colors when `ansi` is true.
+fn tracing_layer<W>(writer: W, ansi: bool) -> HierarchicalLayer<W>
 where
     W: for<'writer> MakeWriter<'writer> + 'static,
 {
     const TRACING_IDENT: usize = 2;
     HierarchicalLayer::new(TRACING_IDENT)
         .with_indent_lines(true)
+        .with_ansi(ansi)
         .with_writer(writer)
 }
+
+/// A failed link, with the stage it stopped in. It is displayed like the [`LinkerError`].
+#[derive(Debug)]
+struct LinkError {
+    error: LinkerError,
+    stage: Option<Stage>,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for LinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Prints the `WARN` and `ERROR` events to stderr as [`JsonDiagnostic`]s, for
+/// `--message-format=json`.
+struct JsonLayer;
+
+impl<S: Subscriber> Layer<S> for JsonLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        struct MessageVisitor(String);
+
+        impl Visit for MessageVisitor {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+
+        let level = match *event.metadata().level() {
+            Level::ERROR => "error",
+            Level::WARN => "warning",
+            _ => return,
+        };
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        eprintln!(
+            "{}",
+            JsonDiagnostic {
+                level,
+                message: &visitor.0,
+                file: None,
+                stage: None,
+            }
+        );
+    }
+}
+
+/// Returns whether the last `--message-format` of `args` is `json`. The errors of the command line
+/// itself are printed as JSON too, so this is looked up before parsing it.
+fn json_message_format(args: impl IntoIterator<Item = String>) -> bool {
+    let mut json = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let format = match arg.strip_prefix("--message-format") {
+            Some("") => args.next(),
+            Some(format) => format.strip_prefix('=').map(str::to_owned),
+            None => continue,
+        };
+        if let Some(format) = format {
+            json = format == "json";
+        }
+    }
+    json
+}
+
 fn main() -> anyhow::Result<()> {
-    let args = env::args().map(|arg| {
+    // Look `--message-format` up after expanding the response files, so that one given in a
+    // response file is honored. If they can't be expanded, fall back to the raw arguments to print
+    // that error.
+    let args = expand_response_files(env::args(), 0);
+    let json = match &args {
+        Ok(args) => json_message_format(args.iter().cloned()),
+        Err(_) => json_message_format(env::args()),
+    };
+    match args.map_err(anyhow::Error::from).and_then(run) {
+        Err(err) if json => {
+            let link_error = err.downcast_ref::<LinkError>();
+            eprintln!(
+                "{}",
+                JsonDiagnostic {
+                    level: "error",
+                    message: &format!("{err:#}"),
+                    file: link_error.and_then(|err| err.error.path()),
+                    stage: link_error.and_then(|err| err.stage),
+                }
+            );
+            process::exit(1)
+        }
+        result => result,
+    }
+}
+
+fn run(args: Vec<String>) -> anyhow::Result<()> {
+    let args = args.into_iter().map(|arg| {
         if arg == "-flavor" {
             "--flavor".to_string()
+        } else if arg == "-g0" {
+            "--strip-debug".to_string()
         } else {
             arg
         }
     });
     let CommandLine {
         target,
+        endian,
         cpu,
         cpu_features,
+        target_feature,
+        reloc_model,
+        code_model,
         output,
         emit,
+        emit_ir_to,
+        emit_symbol_manifest,
+        depfile,
         btf,
+        strip_debug,
+        strip_all,
+        strip_line_info,
+        strip_unused_globals,
+        strip_internal_debug_info,
         allow_bpf_trap,
         optimize,
+        passes,
+        extra_passes,
+        mut disabled_passes,
+        no_loop_unroll,
+        no_vectorize,
+        no_slp_vectorize,
+        disable_dce,
+        inline_threshold,
         export_symbols,
         log_file,
         log_level,
         unroll_loops,
+        time_passes,
+        stats,
+        remarks_filter,
+        stack_size_limit,
+        stack_size_limit_error,
         ignore_inline_never,
+        remove_attributes,
+        keep_optnone,
         dump_module,
+        dump_btf,
+        dump_btf_ext,
+        dump_section,
+        save_temps,
+        incremental,
+        dump_attributes,
+        print_symbols,
+        output_format,
+        print_eliminated,
+        print_insn_count,
+        max_insns,
+        print_stats,
+        time_link,
+        dry_run,
         llvm_args,
         disable_expand_memcpy_in_order,
         disable_memory_builtins,
+        allow_missing_exports,
+        mut allow_undefined,
+        allow_undefined_file,
+        keep_symbols,
+        wrap,
+        defsym,
+        export_all,
+        strict_exports,
+        no_internalize,
+        verify,
+        verify_module,
+        deny_duplicate_symbols,
+        strict_triple,
         inputs,
         export,
+        export_regex,
         fatal_errors,
+        fatal_warnings,
+        diagnostics_summary,
+        message_format,
+        color,
         _debug,
         _libs,
     } = match Parser::try_parse_from(args) {
@@ -232,10 +1065,21 @@ fn main() -> anyhow::Result<()> {
         },
     };
 
+    let output_to_stdout = output.as_deref() == Some(Path::new("-"));
+
+    let stderr_color = color.enabled(io::stderr().is_terminal());
+    // The diagnostics are logged to the log file too, which must not get escape codes.
+    let color_diagnostics = stderr_color && log_file.is_none();
+
+    // With `--message-format=json`, the warnings and errors are printed as JSON instead of the
+    // human readable logs.
+    let json = message_format == MessageFormat::Json;
+
     // Configure tracing.
     let _guard = {
         let filter = EnvFilter::from_default_env();
         let filter = match log_level {
+            None if json => filter.add_directive(Level::WARN.into()),
             None => filter,
             Some(log_level) => filter.add_directive(log_level.into()),
         };
@@ -244,14 +1088,24 @@ fn main() -> anyhow::Result<()> {
             Some((parent, file_name)) => {
                 let file_appender = tracing_appender::rolling::never(parent, file_name);
                 let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+                // Keep stdout clean when it receives the output.
                 let subscriber = subscriber_registry
-                    .with(tracing_layer(io::stdout))
-                    .with(tracing_layer(non_blocking));
+                    .with((!json && !output_to_stdout).then(|| {
+                        tracing_layer(io::stdout, color.enabled(io::stdout().is_terminal()))
+                    }))
+                    .with(
+                        (!json && output_to_stdout)
+                            .then(|| tracing_layer(io::stderr, stderr_color)),
+                    )
+                    .with(json.then_some(JsonLayer))
+                    .with(tracing_layer(non_blocking, false));
                 tracing::subscriber::set_global_default(subscriber)?;
                 Some(guard)
             }
             None => {
-                let subscriber = subscriber_registry.with(tracing_layer(io::stderr));
+                let subscriber = subscriber_registry
+                    .with((!json).then(|| tracing_layer(io::stderr, stderr_color)))
+                    .with(json.then_some(JsonLayer));
                 tracing::subscriber::set_global_default(subscriber)?;
                 None
             }
@@ -263,52 +1117,200 @@ fn main() -> anyhow::Result<()> {
         env::args().collect::<Vec<_>>().join(" ")
     );
 
-    let export_symbols = export_symbols.map(fs::read_to_string).transpose()?;
+    if let Some(path) = allow_undefined_file {
+        let contents = fs::read_to_string(&path)
+            .map_err(|error| CliError::AllowUndefinedFile { path, error })?;
+        allow_undefined.extend(parse_export_symbols(&contents).map(str::to_owned));
+    }
+
+    let export_symbols = export_symbols
+        .map(|path| {
+            fs::read_to_string(&path).map_err(|error| CliError::ExportSymbols { path, error })
+        })
+        .transpose()?;
 
     let export_symbols = export_symbols
         .as_deref()
         .into_iter()
-        .flat_map(str::lines)
-        .chain(export.iter().map(String::as_str));
+        .flat_map(parse_export_symbols)
+        .chain(export.iter().map(String::as_str))
+        .map(ExportSpec::from)
+        .chain(export_regex.iter().map(|regex| ExportSpec::Regex(regex)));
 
-    let output_type = match *emit.as_slice() {
-        [] => unreachable!("emit has a default value"),
-        [CliOutputType(output_type), ..] => output_type,
+    let stdin = match inputs.iter().filter(|input| is_stdin(input)).count() {
+        0 => None,
+        1 => {
+            let mut bytes = Vec::new();
+            let _: usize = io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(CliError::Stdin)?;
+            Some(bytes)
+        }
+        _ => return Err(CliError::DuplicateStdin.into()),
     };
+
+    let cpu_features = cpu_features.to_string_lossy();
+    let cpu_features = join_cpu_features(
+        std::iter::once(cpu_features.as_ref()).chain(target_feature.iter().map(String::as_str)),
+    );
+
+    let output = match (output, emit.as_slice()) {
+        (Some(output), _) => output,
+        (None, []) => unreachable!("emit has a default value"),
+        (None, [CliEmit { output_type, .. }, ..]) => default_output(&inputs, output_type.0)?,
+    };
+    let outputs = emit_outputs(&emit, &output)?;
     let optimize = match *optimize.as_slice() {
         [] => unreachable!("emit has a default value"),
         [.., CliOptLevel(optimize)] => optimize,
     };
 
+    for (disabled, pass) in [
+        (no_loop_unroll, "loop-unroll"),
+        (no_vectorize, "loop-vectorize"),
+        (no_slp_vectorize, "slp-vectorizer"),
+    ] {
+        if disabled {
+            disabled_passes.push(pass.to_owned());
+        }
+    }
+
+    let target = target.or_else(|| endian.map(|endian| endian.target().to_owned()));
     let mut linker = Linker::new(LinkerOptions {
         target,
         cpu,
         cpu_features,
+        reloc_model,
+        code_model,
         optimize,
-        unroll_loops,
+        passes,
+        extra_passes,
+        disabled_passes,
+        disable_dce,
+        inline_threshold,
+        unroll_loops: unroll_loops.is_some(),
+        unroll_max_count: unroll_loops.flatten(),
+        time_passes,
+        stats,
+        allow_undefined,
+        max_insns,
+        stack_size_limit: Some(stack_size_limit),
+        stack_size_limit_error,
         ignore_inline_never,
+        remove_attributes,
+        keep_optnone,
         llvm_args,
         disable_expand_memcpy_in_order,
         disable_memory_builtins,
+        allow_missing_exports,
+        keep_symbols,
+        wrap,
+        defsym,
+        strict_exports,
+        export_all: export_all || no_internalize,
+        verify,
+        verify_module,
+        deny_duplicate_symbols,
+        strict_triple,
         btf,
+        strip_debug,
+        strip_all,
+        strip_line_info,
+        strip_unused_globals,
+        strip_internal_debug_info,
         allow_bpf_trap,
-    });
+    })
+    .map_err(|err| match err {
+        LinkerError::InvalidLlvmArg(arg) => CliError::InvalidLlvmArg(arg).into(),
+        err @ LinkerError::UnknownLlvmArg(_) => CliError::UnknownLlvmArg(err).into(),
+        err => anyhow::Error::from(err),
+    })?;
 
     if let Some(path) = dump_module {
         linker.set_dump_module_path(path);
     }
+    if let Some(path) = dump_btf {
+        linker.set_dump_btf_path(path);
+    }
+    if let Some(path) = dump_btf_ext {
+        linker.set_dump_btf_ext_path(path);
+    }
+    if let Some([name, path]) = dump_section.as_deref() {
+        linker.set_dump_section(name, path)?;
+    }
+    if let Some(path) = save_temps {
+        linker.set_save_temps_path(path);
+    }
+    if let Some(path) = incremental {
+        linker.set_incremental_path(path);
+    }
+    if let Some(path) = emit_symbol_manifest {
+        linker.set_symbol_manifest_path(path);
+    }
+    linker.set_dump_attributes(dump_attributes);
+    linker.set_print_eliminated(print_eliminated);
+    linker.set_print_stats(print_stats);
+    linker.set_print_insn_count(print_insn_count);
+    linker.set_time_link(time_link);
+    linker.set_message_format(message_format);
+    linker.set_color(color_diagnostics);
+    if let Some(pattern) = remarks_filter {
+        linker.set_remarks_filter(&pattern)?;
+    }
+    linker.set_print_symbols_format(output_format);
+    match print_symbols {
+        None => {}
+        Some(None) if output_to_stdout => return Err(CliError::PrintSymbolsToStdout.into()),
+        Some(None) => linker.set_print_symbols(true),
+        Some(Some(path)) => linker.set_print_symbols_path(path),
+    }
 
-    let inputs = inputs
-        .iter()
-        .map(|p| LinkerInput::new_from_file(p.as_path()));
+    let linker_inputs = inputs.iter().map(|p| match stdin.as_deref() {
+        Some(bytes) if is_stdin(p) => LinkerInput::new_from_buffer("<stdin>", bytes),
+        _ => LinkerInput::new_from_file(p.as_path()),
+    });
+    let result = if dry_run {
+        linker.link_dry_run(linker_inputs, export_symbols)
+    } else {
+        let outputs = outputs
+            .iter()
+            .map(|(output_type, path)| (*output_type, path.as_path()))
+            .chain(
+                emit_ir_to
+                    .as_deref()
+                    .map(|path| (OutputType::LlvmAssembly, path)),
+            );
+        linker.link_to_files(linker_inputs, outputs, export_symbols)
+    };
 
-    linker.link_to_file(inputs, &output, output_type, export_symbols)?;
+    let counts = linker.diagnostic_counts();
+    if diagnostics_summary {
+        eprintln!("LLVM diagnostics: {counts}");
+    }
+    result.map_err(|error| LinkError {
+        error,
+        stage: linker.last_stage(),
+    })?;
 
-    if fatal_errors && linker.has_errors() {
+    if (fatal_errors || fatal_warnings) && counts.errors > 0 {
         return Err(anyhow::anyhow!(
             "LLVM issued diagnostic with error severity"
         ));
     }
+    if fatal_warnings && counts.warnings > 0 {
+        return Err(anyhow::anyhow!(
+            "LLVM issued diagnostic with warning severity: {}",
+            linker.warnings().join("; ")
+        ));
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    if let Some(path) = depfile {
+        write_depfile(&path, &output, &inputs)
+            .map_err(|error| CliError::Depfile { path, error })?;
+    }
 
     Ok(())
 }
@@ -382,4 +1384,180 @@ mod test {
             [PathBuf::from("symbols.o"), PathBuf::from("rcgu.o")]
         );
     }
+
+    #[test]
+    fn test_emit_delimiter() {
+        let args = [
+            "bpf-linker",
+            "--emit=obj,llvm-ir",
+            "--emit",
+            "bc=lib/prog.bc",
+            "-o",
+            "prog.o",
+            "prog.ll",
+        ];
+        let CommandLine {
+            inputs,
+            emit,
+            output,
+            ..
+        } = Parser::parse_from(args);
+        assert_eq!(inputs, [PathBuf::from("prog.ll")]);
+        assert_eq!(
+            emit_outputs(&emit, &output.unwrap()).unwrap(),
+            [
+                (OutputType::Object, PathBuf::from("prog.o")),
+                (OutputType::LlvmAssembly, PathBuf::from("prog.ll")),
+                (OutputType::Bitcode, PathBuf::from("lib/prog.bc")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_output() {
+        let inputs = [PathBuf::from("target/bpf/prog.o"), PathBuf::from("lib.a")];
+        for (output_type, expected) in [
+            (OutputType::Object, "prog.o"),
+            (OutputType::Assembly, "prog.s"),
+            (OutputType::LlvmAssembly, "prog.ll"),
+            (OutputType::Bitcode, "prog.bc"),
+        ] {
+            assert_eq!(
+                default_output(&inputs, output_type).unwrap(),
+                Path::new(expected)
+            );
+        }
+
+        let inputs = [PathBuf::from("-"), PathBuf::from("prog.o")];
+        assert_eq!(
+            default_output(&inputs, OutputType::Object).unwrap(),
+            Path::new("out.o")
+        );
+
+        let inputs = [PathBuf::from("prog.o")];
+        assert!(matches!(
+            default_output(&inputs, OutputType::Object),
+            Err(CliError::OutputOverwritesInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_emit_outputs() {
+        let emit = |args: &[&str]| {
+            args.iter()
+                .map(|arg| arg.parse::<CliEmit>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let output = Path::new("prog.o");
+
+        assert_eq!(
+            emit_outputs(&emit(&["asm"]), output).unwrap(),
+            [(OutputType::Assembly, PathBuf::from("prog.o"))]
+        );
+        assert_eq!(
+            emit_outputs(&emit(&["obj", "llvm-ir", "bc=lib/prog.bc"]), output).unwrap(),
+            [
+                (OutputType::Object, PathBuf::from("prog.o")),
+                (OutputType::LlvmAssembly, PathBuf::from("prog.ll")),
+                (OutputType::Bitcode, PathBuf::from("lib/prog.bc")),
+            ]
+        );
+        assert!(matches!(
+            emit_outputs(&emit(&["obj", "llvm-bc", "bc=prog.bc"]), output),
+            Err(CliError::DuplicateEmit("llvm-bc"))
+        ));
+        assert!(matches!(
+            "elf=prog.o".parse::<CliEmit>(),
+            Err(CliError::InvalidOutputType(_))
+        ));
+    }
+
+    #[test]
+    fn test_split_response_file() {
+        assert_eq!(
+            split_response_file(
+                "--export test_foo\n  input.ll -o 'my out.o'\n\"a \\\"b\\\"\" c\\ d ''\n"
+            ),
+            [
+                "--export", "test_foo", "input.ll", "-o", "my out.o", "a \"b\"", "c d", ""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_response_files() {
+        let dir = env::temp_dir().join(format!("bpf-linker-response-files-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let inner = dir.join("inner.rsp");
+        fs::write(&inner, "-o out.o").unwrap();
+        let outer = dir.join("outer.rsp");
+        fs::write(&outer, format!("input.ll @{}", inner.display())).unwrap();
+
+        let args = ["bpf-linker".to_owned(), format!("@{}", outer.display())];
+        assert_eq!(
+            expand_response_files(args, 0).unwrap(),
+            ["bpf-linker", "input.ll", "-o", "out.o"]
+        );
+
+        let missing = dir.join("missing.rsp");
+        assert!(matches!(
+            expand_response_files([format!("@{}", missing.display())], 0),
+            Err(CliError::ResponseFile { path, .. }) if path == missing
+        ));
+
+        let looping = dir.join("loop.rsp");
+        fs::write(&looping, format!("@{}", looping.display())).unwrap();
+        assert!(matches!(
+            expand_response_files([format!("@{}", looping.display())], 0),
+            Err(CliError::ResponseFileDepth(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_escape_make_path() {
+        assert_eq!(escape_make_path(Path::new("a/b.o")), "a/b.o");
+        assert_eq!(
+            escape_make_path(Path::new("my dir/lib#1$.rlib")),
+            r"my\ dir/lib\#1$$.rlib"
+        );
+    }
+
+    #[test]
+    fn test_parse_llvm_arg() {
+        assert_eq!(
+            parse_llvm_arg("--unroll-threshold=100").unwrap().as_c_str(),
+            c"--unroll-threshold=100"
+        );
+        for arg in ["", "-", "unroll-threshold=100"] {
+            assert!(
+                matches!(parse_llvm_arg(arg), Err(CliError::InvalidLlvmArg(a)) if a == arg),
+                "{arg}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_export_symbols() {
+        let contents = "# programs\nfoo \t\n\n  # maps\r\n  bar\r\n";
+        assert_eq!(
+            parse_export_symbols(contents).collect::<Vec<_>>(),
+            ["foo", "bar"]
+        );
+    }
+
+    #[test]
+    fn test_join_cpu_features() {
+        assert_eq!(join_cpu_features([""]).as_bytes(), b"");
+        assert_eq!(join_cpu_features(["", "+alu32"]).as_bytes(), b"+alu32");
+        assert_eq!(
+            join_cpu_features(["+alu32,-dwarfris", "-alu32"]).as_bytes(),
+            b"-dwarfris,-alu32"
+        );
+        assert_eq!(
+            join_cpu_features(["+alu32", "-dwarfris", "+alu32"]).as_bytes(),
+            b"-dwarfris,+alu32"
+        );
+    }
 }
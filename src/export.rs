@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+
+use regex::bytes::Regex;
+use sha2::Sha256;
+
+use crate::linker::hash_field;
+
+/// A symbol, or a pattern matching symbols, to export from the linked module.
+#[derive(Clone, Copy, Debug)]
+pub enum ExportSpec<'a> {
+    /// Export the symbol with exactly this name.
+    Exact(&'a str),
+    /// Export the symbols matching a glob pattern. `*` matches any sequence of characters and `?`
+    /// matches any single character. No other glob syntax is supported, all the other characters
+    /// match themselves.
+    Glob(&'a str),
+    /// Export the symbols whose whole name matches a regular expression.
+    Regex(&'a str),
+}
+
+impl<'a> From<&'a str> for ExportSpec<'a> {
+    /// Strings containing `*` or `?` are treated as glob patterns, everything else is an exact
+    /// symbol name.
+    fn from(s: &'a str) -> Self {
+        if s.contains(['*', '?']) {
+            Self::Glob(s)
+        } else {
+            Self::Exact(s)
+        }
+    }
+}
+
+/// The set of symbols to keep external when internalizing the linked module.
+#[derive(Debug, Default)]
+pub(crate) struct ExportSymbols<'a> {
+    exact: HashSet<&'a [u8]>,
+    optional: HashSet<&'a [u8]>,
+    globs: Vec<&'a [u8]>,
+    /// The regular expressions as given and compiled to match whole names.
+    regexes: Vec<(&'a str, Regex)>,
+}
+
+impl<'a> ExportSymbols<'a> {
+    /// Feeds the export set to `hasher` as one field per symbol or pattern, see [`hash_field`],
+    /// independently of the order of the symbols.
+    pub(crate) fn hash_into(&self, hasher: &mut Sha256) {
+        let Self {
+            exact,
+            optional,
+            globs,
+            regexes,
+        } = self;
+        for (name, symbols) in [("export", exact), ("optional-export", optional)] {
+            let mut symbols = symbols.iter().collect::<Vec<_>>();
+            symbols.sort_unstable();
+            for symbol in symbols {
+                hash_field(hasher, name, symbol);
+            }
+        }
+        for glob in globs {
+            hash_field(hasher, "export-glob", glob);
+        }
+        for (pattern, _) in regexes {
+            hash_field(hasher, "export-regex", pattern.as_bytes());
+        }
+    }
+
+    /// Builds the export set, returning the offending pattern and the error if a regular
+    /// expression fails to compile.
+    pub(crate) fn new<I, S>(specs: I) -> Result<Self, (&'a str, regex::Error)>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<ExportSpec<'a>>,
+    {
+        let mut export_symbols = Self::default();
+        for spec in specs {
+            match spec.into() {
+                ExportSpec::Exact(name) => {
+                    let _: bool = export_symbols.exact.insert(name.as_bytes());
+                }
+                ExportSpec::Glob(pattern) => export_symbols.globs.push(pattern.as_bytes()),
+                ExportSpec::Regex(pattern) => {
+                    let regex =
+                        Regex::new(&format!("^(?:{pattern})$")).map_err(|err| (pattern, err))?;
+                    export_symbols.regexes.push((pattern, regex));
+                }
+            }
+        }
+        Ok(export_symbols)
+    }
+
+    /// Exports the symbol with exactly the given name if the module defines it.
+    pub(crate) fn insert_optional(&mut self, name: &'a [u8]) {
+        let _: bool = self.optional.insert(name);
+    }
+
+    /// Returns the exact symbol names that were explicitly requested and must be defined by the
+    /// module.
+    pub(crate) fn required(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.exact.iter().copied()
+    }
+
+    /// Returns whether the symbol with the given name is exported.
+    ///
+    /// Patterns never match LLVM intrinsics, only exact names do.
+    pub(crate) fn contains(&self, name: &[u8]) -> bool {
+        let Self {
+            exact,
+            optional,
+            globs,
+            regexes,
+        } = self;
+        if exact.contains(name) || optional.contains(name) {
+            return true;
+        }
+        if name.starts_with(b"llvm.") {
+            return false;
+        }
+        globs.iter().any(|glob| glob_matches(glob, name))
+            || regexes.iter().any(|(_, regex)| regex.is_match(name))
+    }
+
+    /// Returns the glob and regex patterns which match none of the given names. Like in
+    /// [`ExportSymbols::contains`], patterns never match LLVM intrinsics.
+    pub(crate) fn unmatched_patterns(&self, names: &[&[u8]]) -> Vec<String> {
+        let names: Vec<_> = names
+            .iter()
+            .copied()
+            .filter(|name| !name.starts_with(b"llvm."))
+            .collect();
+        let globs = self
+            .globs
+            .iter()
+            .filter(|glob| !names.iter().any(|name| glob_matches(glob, name)))
+            .map(|glob| String::from_utf8_lossy(glob).into_owned());
+        let regexes = self
+            .regexes
+            .iter()
+            .filter(|(_, regex)| !names.iter().any(|name| regex.is_match(name)))
+            .map(|(pattern, _)| (*pattern).to_owned());
+        globs.chain(regexes).collect()
+    }
+}
+
+/// Matches `name` against a glob `pattern` supporting `*` and `?`. Symbol names are bytes and have
+/// no path separators, so this doesn't use the `glob` crate, which matches strings and paths.
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` in the pattern and the position in the name it was matched at,
+    // so that we can backtrack and let the `*` consume one more byte on mismatch.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches(b"xdp_*", b"xdp_"));
+        assert!(glob_matches(b"xdp_*", b"xdp_pass"));
+        assert!(!glob_matches(b"xdp_*", b"tc_pass"));
+        assert!(glob_matches(b"*_prog", b"my_prog"));
+        assert!(!glob_matches(b"*_prog", b"my_prog2"));
+        assert!(glob_matches(b"handle_event_?", b"handle_event_1"));
+        assert!(!glob_matches(b"handle_event_?", b"handle_event_10"));
+        assert!(glob_matches(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_matches(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_matches(b"*", b""));
+        assert!(glob_matches(b"kprobe__*", b"kprobe__tcp_connect"));
+        assert!(!glob_matches(b"kprobe__*", b"kprobe_tcp_connect"));
+        assert!(glob_matches(b"kprobe__??p*", b"kprobe__tcp_connect"));
+        assert!(glob_matches(b"map_[ab]*", b"map_[ab]_1"));
+        assert!(!glob_matches(b"map_[ab]*", b"map_a_1"));
+    }
+
+    #[test]
+    fn test_export_symbols() {
+        let export_symbols =
+            ExportSymbols::new(["exact", "test_*", "*"].into_iter().chain(["llvm.memcpy"]))
+                .unwrap();
+        assert!(export_symbols.contains(b"exact"));
+        assert!(export_symbols.contains(b"test_a"));
+        assert!(export_symbols.contains(b"llvm.memcpy"));
+        assert!(!export_symbols.contains(b"llvm.memset"));
+
+        let mut export_symbols = ExportSymbols::new(["exact", "test_*"]).unwrap();
+        export_symbols.insert_optional(b"memcpy");
+        assert!(export_symbols.contains(b"memcpy"));
+        assert_eq!(export_symbols.required().collect::<Vec<_>>(), [b"exact"]);
+
+        let export_symbols = ExportSymbols::new([ExportSpec::Regex("test_[ab]")]).unwrap();
+        assert!(export_symbols.contains(b"test_a"));
+        assert!(!export_symbols.contains(b"test_c"));
+        assert!(!export_symbols.contains(b"my_test_a"));
+
+        let export_symbols = ExportSymbols::new([ExportSpec::Regex("(?i)test_a")]).unwrap();
+        assert!(export_symbols.contains(b"TEST_A"));
+
+        assert_eq!(
+            ExportSymbols::new([ExportSpec::Regex("(")]).unwrap_err().0,
+            "("
+        );
+
+        let export_symbols = ExportSymbols::new([
+            ExportSpec::Exact("exact"),
+            ExportSpec::Glob("test_*"),
+            ExportSpec::Glob("llvm.*"),
+            ExportSpec::Regex("prog_[0-9]"),
+            ExportSpec::Regex("map_.*"),
+        ])
+        .unwrap();
+        assert_eq!(
+            export_symbols.unmatched_patterns(&[b"test_a", b"map_a", b"llvm.memcpy"]),
+            ["llvm.*", "prog_[0-9]"]
+        );
+    }
+}
@@ -42,7 +42,9 @@ pub extern crate llvm_sys_20 as llvm_sys;
 #[cfg(feature = "llvm-21")]
 pub extern crate llvm_sys_21 as llvm_sys;
 
+mod export;
 mod linker;
 mod llvm;
 
+pub use export::ExportSpec;
 pub use linker::*;
@@ -1,24 +1,35 @@
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, hash_map::Entry},
     ffi::{CStr, CString, OsStr},
     fs,
-    io::{self, Read as _},
+    io::{self, Read as _, Write as _},
     ops::Deref,
     os::unix::ffi::OsStrExt as _,
     path::{Path, PathBuf},
+    process,
     str::{self, FromStr},
+    time::{Duration, Instant},
 };
 
 use ar::Archive;
 use llvm_sys::{
     error_handling::{LLVMEnablePrettyStackTrace, LLVMInstallFatalErrorHandler},
-    target_machine::LLVMCodeGenFileType,
+    target_machine::{LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMRelocMode},
 };
+use regex::Regex;
+use sha2::{Digest as _, Sha256};
 use thiserror::Error;
-use tracing::{debug, error, info, warn};
-
-use crate::llvm::{self, LLVMContext, LLVMModule, LLVMTargetMachine, MemoryBuffer};
+use tracing::{debug, debug_span, error, info, warn};
+
+use crate::{
+    export::{ExportSpec, ExportSymbols},
+    llvm::{
+        self, InternalizedSymbol, LLVMContext, LLVMModule, LLVMTargetMachine, MemoryBuffer,
+        ModuleStats, ModuleSymbol,
+    },
+};
 
 /// Linker error
 #[derive(Debug, Error)]
@@ -27,10 +38,132 @@ pub enum LinkerError {
     #[error("invalid CPU {0}")]
     InvalidCpu(String),
 
+    /// Invalid relocation model.
+    #[error(
+        "invalid relocation model {0}, expected one of `default`, `static`, `pic`, `dynamic-no-pic`"
+    )]
+    InvalidRelocModel(String),
+
+    /// Invalid code model.
+    #[error(
+        "invalid code model {0}, expected one of `default`, `tiny`, `small`, `kernel`, `medium`, \
+         `large`"
+    )]
+    InvalidCodeModel(String),
+
+    /// Invalid BTF mode.
+    #[error("invalid BTF mode {0}")]
+    InvalidBtfMode(String),
+
+    /// Invalid message format.
+    #[error("invalid message format {0}, expected one of `human`, `json`")]
+    InvalidMessageFormat(String),
+
+    /// Invalid symbol definition.
+    #[error(
+        "invalid symbol definition {0}, expected `name=value` with a decimal or `0x` hexadecimal value"
+    )]
+    InvalidSymbolDefinition(String),
+
+    /// A symbol of [`LinkerOptions::defsym`] couldn't be defined.
+    #[error("failed to define the symbol {0}: {1}")]
+    DefineSymbolError(String, String),
+
+    /// A pass which can't be disabled was given to [`LinkerOptions::disabled_passes`].
+    #[error(
+        "pass `{}` can't be disabled, the passes which can be disabled are: {}",
+        .0,
+        llvm::DISABLEABLE_PASSES.join(", ")
+    )]
+    InvalidDisabledPass(String),
+
     /// Invalid LLVM target.
     #[error("invalid LLVM target {0}")]
     InvalidTarget(String),
 
+    /// LLVM cannot create a target machine for the target, CPU, features, relocation model and
+    /// code model.
+    #[error(
+        "failed to create a target machine for {triple} with CPU {cpu}, features `{features}`, relocation model {reloc_model} and code model {code_model}"
+    )]
+    CreateTargetMachineError {
+        triple: String,
+        cpu: Cpu,
+        features: String,
+        reloc_model: RelocModel,
+        code_model: CodeModel,
+    },
+
+    /// Invalid export pattern.
+    #[error("invalid export pattern `{0}`: {1}")]
+    InvalidExportPattern(String, String),
+
+    /// Functions use more stack than the limit.
+    #[error(
+        "functions use more than {limit} bytes of stack: {}",
+        functions
+            .iter()
+            .map(|(name, size)| format!("{name} ({size} bytes)"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )]
+    StackSizeLimitExceeded {
+        limit: u64,
+        functions: Vec<(String, u64)>,
+    },
+
+    /// Functions have more instructions than the limit.
+    #[error(
+        "functions have more than {limit} instructions: {}",
+        functions
+            .iter()
+            .map(|(name, count)| format!("{name} ({count} instructions)"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )]
+    InstructionLimitExceeded {
+        limit: usize,
+        functions: Vec<(String, usize)>,
+    },
+
+    /// Functions call functions which no input defines.
+    #[error(
+        "calls to undefined functions: {}",
+        .0.iter()
+            .map(|(caller, callee)| format!("{caller} calls {callee}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )]
+    UndefinedCalls(Vec<(String, String)>),
+
+    /// Export patterns match no symbol.
+    #[error("export patterns match no symbol: {}", .0.join(", "))]
+    UnmatchedExportPatterns(Vec<String>),
+
+    /// Invalid remarks filter.
+    #[error("invalid remarks filter `{0}`: {1}")]
+    InvalidRemarksFilter(String, String),
+
+    /// An argument of [`LinkerOptions::llvm_args`] is not an option.
+    #[error("invalid LLVM argument `{0}`, expected `-option` or `-option=value`")]
+    InvalidLlvmArg(String),
+
+    /// An argument of [`LinkerOptions::llvm_args`] is not one of the options bpf-linker accepts.
+    #[error("unknown LLVM option `{}`, expected one of: {}", .0, LLVM_OPTIONS.join(", "))]
+    UnknownLlvmArg(String),
+
+    /// Invalid name of the section to dump.
+    #[error("invalid section name `{0}`")]
+    InvalidSectionName(String),
+
+    /// No input object has the section to dump.
+    #[error("no input object has a {0} section")]
+    MissingDumpedSection(String),
+
+    /// There was an error reading the section to dump from an input object.
+    #[error("error reading the {1} section of `{0}`: {2}")]
+    DumpSectionError(PathBuf, String, String),
+
     /// An IO Error occurred while linking a module.
     #[error("`{0}`: {1}")]
     IoError(PathBuf, io::Error),
@@ -39,25 +172,55 @@ pub enum LinkerError {
     #[error("invalid input file `{0}`")]
     InvalidInputType(PathBuf),
 
-    /// Linking a module failed.
-    #[error("failure linking module {0}")]
-    LinkModuleError(PathBuf),
+    /// Linking a module failed. Contains the errors reported by LLVM.
+    #[error("failure linking module {0}: {1}")]
+    LinkModuleError(PathBuf, String),
 
     /// Parsing an IR module failed.
     #[error("failure parsing IR module `{0}`: {1}")]
     IRParseError(PathBuf, String),
 
+    /// An input targets neither `bpfel` nor `bpfeb`.
+    #[error("{} targets {triple}, expected a bpf target", path.display())]
+    NonBpfInput { path: PathBuf, triple: String },
+
+    /// An input targets `bpfel` when the target is `bpfeb`, or the other way around.
+    #[error(
+        "{} targets {triple}, which doesn't have the endianness of the target {target}",
+        path.display()
+    )]
+    EndiannessMismatch {
+        path: PathBuf,
+        triple: String,
+        target: String,
+    },
+
+    /// A symbol is defined by several inputs.
+    #[error("duplicate definition of {name} in {} and {}", first.display(), second.display())]
+    DuplicateSymbol {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+
     /// Linking a module included in an archive failed.
     #[error("failure linking module {1} from {0}")]
-    LinkArchiveModuleError(PathBuf, PathBuf),
+    LinkArchiveModuleError(PathBuf, PathBuf, #[source] Box<Self>),
+
+    /// The LLVM module verifier found invalid constructs in the linked module.
+    #[error("the linked module is invalid {stage}: {message}")]
+    InvalidModule {
+        stage: &'static str,
+        message: String,
+    },
 
-    /// Optimizing the BPF code failed.
-    #[error("LLVMRunPasses failed: {0}")]
-    OptimizeError(String),
+    /// Running the optimization pass pipeline failed.
+    #[error("LLVMRunPasses failed running `{pipeline}`: {message}")]
+    PassError { pipeline: String, message: String },
 
     /// Generating the BPF code failed.
-    #[error("LLVMTargetMachineEmitToFile failed: {0}")]
-    EmitCodeError(String),
+    #[error("generating the BPF code failed: {0}")]
+    CodegenError(String),
 
     /// Writing the bitcode failed.
     #[error("LLVMWriteBitcodeToFile failed: {0}")]
@@ -71,33 +234,138 @@ pub enum LinkerError {
     #[error("error reading embedded bitcode: {0}")]
     EmbeddedBitcodeError(String),
 
+    /// There was an error extracting the BTF of the generated object.
+    #[error("error reading the BTF of the generated object: {0}")]
+    EmbeddedBtfError(String),
+
+    /// The generated object has no BTF.
+    #[error("no .BTF section found in the generated object, BTF is only emitted with debug info")]
+    MissingBtfSection,
+
+    /// The generated object has no BTF relocation information.
+    #[error(
+        "no .BTF.ext section found in the generated object, BTF is only emitted with debug info"
+    )]
+    MissingBtfExtSection,
+
     /// The input object file does not have embedded bitcode.
-    #[error("no bitcode section found in {0}")]
+    #[error(
+        "no bitcode section found in {0}, the object must embed its bitcode in a `.llvmbc` section, for example by building it with clang's `-fembed-bitcode`"
+    )]
     MissingBitcodeSection(PathBuf),
 
+    /// Writing several of the outputs failed. The other outputs were written.
+    #[error(
+        "failed to write {} outputs: {}",
+        .0.len(),
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    )]
+    WriteOutputsError(Vec<Self>),
+
+    /// Symbols explicitly requested to be exported are not defined by any input.
+    #[error("exported symbols are not defined: {}", .0.join(", "))]
+    UndefinedExports(Vec<String>),
+
     /// LLVM cannot create a module for linking.
     #[error("failed to create module")]
     CreateModuleError,
 }
 
+impl LinkerError {
+    /// Returns the input or output file the error is about, if any. For a module of an archive,
+    /// this is the archive.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::DumpSectionError(path, ..)
+            | Self::IoError(path, _)
+            | Self::InvalidInputType(path)
+            | Self::LinkModuleError(path, _)
+            | Self::IRParseError(path, _)
+            | Self::NonBpfInput { path, .. }
+            | Self::EndiannessMismatch { path, .. }
+            | Self::DuplicateSymbol { second: path, .. }
+            | Self::LinkArchiveModuleError(path, ..)
+            | Self::MissingBitcodeSection(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
 /// BPF Cpu type
 #[derive(Clone, Copy, Debug)]
 pub enum Cpu {
     Generic,
     Probe,
+    /// The newest CPU supported by the version of the running kernel, see [`Cpu::resolve`].
+    Auto,
     V1,
     V2,
     V3,
+    V4,
 }
 
+/// The environment variable overriding the kernel release [`Cpu::Auto`] resolves the CPU from.
+pub const KERNEL_RELEASE_ENV: &str = "BPF_LINKER_KERNEL_RELEASE";
+
 impl Cpu {
+    /// Resolves [`Cpu::Auto`] to the newest CPU supported by the running kernel, for the target
+    /// `triple`. The other CPUs are returned as is.
+    ///
+    /// The CPU is selected from the kernel release, read from the [`KERNEL_RELEASE_ENV`]
+    /// environment variable when set and from `/proc/sys/kernel/osrelease` otherwise: `v4` from
+    /// 6.6, `v3` from 5.1, `v2` from 4.14 and `v1` before. `generic` is selected when the release
+    /// can't be read, on hosts other than Linux and when the endianness of `triple` isn't the
+    /// one of the host, as the output then can't be meant for the running kernel.
+    pub fn resolve(self, triple: &CStr) -> Self {
+        let Self::Auto = self else {
+            return self;
+        };
+        let triple = triple.to_bytes();
+        let cross_endian = if cfg!(target_endian = "little") {
+            triple.starts_with(b"bpfeb")
+        } else {
+            triple.starts_with(b"bpfel")
+        };
+        let cpu = if cross_endian {
+            info!("not probing the kernel when cross-compiling to a different endianness");
+            Self::Generic
+        } else {
+            match kernel_release() {
+                Some(release) => Self::for_kernel_release(&release).unwrap_or_else(|| {
+                    warn!("failed to parse the kernel release `{release}`");
+                    Self::Generic
+                }),
+                None => Self::Generic,
+            }
+        };
+        info!("resolved CPU auto to {cpu}");
+        cpu
+    }
+
+    /// Returns the newest CPU supported by the kernel `release`, like `6.8.0-45-generic`.
+    fn for_kernel_release(release: &str) -> Option<Self> {
+        let mut version = release
+            .split(|c: char| !c.is_ascii_digit())
+            .map(str::parse::<u32>);
+        let major = version.next()?.ok()?;
+        let minor = version.next()?.ok()?;
+        Some(match (major, minor) {
+            (6.., 6..) | (7.., _) => Self::V4,
+            (5.., 1..) | (6.., _) => Self::V3,
+            (4, 14..) | (5.., _) => Self::V2,
+            _ => Self::V1,
+        })
+    }
+
     fn as_c_str(&self) -> &'static CStr {
         match self {
             Self::Generic => c"generic",
             Self::Probe => c"probe",
+            Self::Auto => c"generic",
             Self::V1 => c"v1",
             Self::V2 => c"v2",
             Self::V3 => c"v3",
+            Self::V4 => c"v4",
         }
     }
 }
@@ -107,9 +375,11 @@ impl std::fmt::Display for Cpu {
         f.pad(match self {
             Self::Generic => "generic",
             Self::Probe => "probe",
+            Self::Auto => "auto",
             Self::V1 => "v1",
             Self::V2 => "v2",
             Self::V3 => "v3",
+            Self::V4 => "v4",
         })
     }
 }
@@ -121,14 +391,186 @@ impl FromStr for Cpu {
         Ok(match s {
             "generic" => Self::Generic,
             "probe" => Self::Probe,
+            "auto" => Self::Auto,
             "v1" => Self::V1,
             "v2" => Self::V2,
             "v3" => Self::V3,
+            "v4" => Self::V4,
             _ => return Err(LinkerError::InvalidCpu(s.to_string())),
         })
     }
 }
 
+/// Returns the release of the running kernel.
+fn kernel_release() -> Option<String> {
+    if let Some(release) = std::env::var_os(KERNEL_RELEASE_ENV) {
+        return Some(release.to_string_lossy().into_owned());
+    }
+    if !cfg!(target_os = "linux") {
+        warn!("can't probe the kernel on this host, selecting CPU generic");
+        return None;
+    }
+    let path = Path::new("/proc/sys/kernel/osrelease");
+    match fs::read_to_string(path) {
+        Ok(release) => Some(release.trim().to_owned()),
+        Err(err) => {
+            warn!(
+                "failed to read the kernel release from {}: {err}",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+/// Relocation model
+#[derive(Clone, Copy, Debug)]
+pub enum RelocModel {
+    /// The target's default relocation model.
+    Default,
+    /// Non-relocatable code.
+    Static,
+    /// Position independent code.
+    Pic,
+    /// Non position independent code that references external symbols indirectly.
+    DynamicNoPic,
+}
+
+impl RelocModel {
+    fn as_llvm(&self) -> LLVMRelocMode {
+        match self {
+            Self::Default => LLVMRelocMode::LLVMRelocDefault,
+            Self::Static => LLVMRelocMode::LLVMRelocStatic,
+            Self::Pic => LLVMRelocMode::LLVMRelocPIC,
+            Self::DynamicNoPic => LLVMRelocMode::LLVMRelocDynamicNoPic,
+        }
+    }
+}
+
+impl std::fmt::Display for RelocModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Default => "default",
+            Self::Static => "static",
+            Self::Pic => "pic",
+            Self::DynamicNoPic => "dynamic-no-pic",
+        })
+    }
+}
+
+impl FromStr for RelocModel {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => Self::Default,
+            "static" => Self::Static,
+            "pic" => Self::Pic,
+            "dynamic-no-pic" => Self::DynamicNoPic,
+            _ => return Err(LinkerError::InvalidRelocModel(s.to_string())),
+        })
+    }
+}
+
+/// Code model
+///
+/// The BPF backend loads addresses of globals with 64-bit immediate loads and doesn't currently
+/// take the code model into account. It is exposed for parity with other LLVM targets and for
+/// experimentation.
+#[derive(Clone, Copy, Debug)]
+pub enum CodeModel {
+    /// The target's default code model.
+    Default,
+    /// Tiny code model.
+    Tiny,
+    /// Small code model.
+    Small,
+    /// Kernel code model.
+    Kernel,
+    /// Medium code model.
+    Medium,
+    /// Large code model.
+    Large,
+}
+
+impl CodeModel {
+    fn as_llvm(&self) -> LLVMCodeModel {
+        match self {
+            Self::Default => LLVMCodeModel::LLVMCodeModelDefault,
+            Self::Tiny => LLVMCodeModel::LLVMCodeModelTiny,
+            Self::Small => LLVMCodeModel::LLVMCodeModelSmall,
+            Self::Kernel => LLVMCodeModel::LLVMCodeModelKernel,
+            Self::Medium => LLVMCodeModel::LLVMCodeModelMedium,
+            Self::Large => LLVMCodeModel::LLVMCodeModelLarge,
+        }
+    }
+}
+
+impl std::fmt::Display for CodeModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Default => "default",
+            Self::Tiny => "tiny",
+            Self::Small => "small",
+            Self::Kernel => "kernel",
+            Self::Medium => "medium",
+            Self::Large => "large",
+        })
+    }
+}
+
+impl FromStr for CodeModel {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => Self::Default,
+            "tiny" => Self::Tiny,
+            "small" => Self::Small,
+            "kernel" => Self::Kernel,
+            "medium" => Self::Medium,
+            "large" => Self::Large,
+            _ => return Err(LinkerError::InvalidCodeModel(s.to_string())),
+        })
+    }
+}
+
+/// BTF emission mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BtfMode {
+    /// Strip the debug info and emit no BTF.
+    #[default]
+    None,
+    /// Keep the functions and their line info, dropping the types. This produces the BTF func
+    /// info and line info without the type BTF, which older kernels may reject.
+    FuncOnly,
+    /// Emit the BTF for the functions, their line info and the types.
+    Full,
+}
+
+impl std::fmt::Display for BtfMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::None => "none",
+            Self::FuncOnly => "func-only",
+            Self::Full => "full",
+        })
+    }
+}
+
+impl FromStr for BtfMode {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => Self::None,
+            "func-only" => Self::FuncOnly,
+            "full" => Self::Full,
+            _ => return Err(LinkerError::InvalidBtfMode(s.to_string())),
+        })
+    }
+}
+
 /// Optimization level
 #[derive(Clone, Copy, Debug)]
 pub enum OptLevel {
@@ -146,6 +588,21 @@ pub enum OptLevel {
     SizeMin,
 }
 
+impl OptLevel {
+    /// Returns the code generation level used by the target machine. Like clang, the size levels
+    /// use the default code generation level and rely on the IR passes to reduce the code size.
+    fn as_llvm_codegen(&self) -> LLVMCodeGenOptLevel {
+        match self {
+            Self::No => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            Self::Less => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            Self::Default | Self::Size | Self::SizeMin => {
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault
+            }
+            Self::Aggressive => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        }
+    }
+}
+
 pub enum LinkerInput<'a> {
     File { path: &'a Path },
     Buffer { name: &'a str, bytes: &'a [u8] },
@@ -161,6 +618,13 @@ impl<'a> LinkerInput<'a> {
     }
 }
 
+/// An in-memory input, named `name` in the diagnostics.
+impl<'a> From<(&'a str, &'a [u8])> for LinkerInput<'a> {
+    fn from((name, bytes): (&'a str, &'a [u8])) -> Self {
+        Self::new_from_buffer(name, bytes)
+    }
+}
+
 enum LinkerInputKind {
     Bitcode,
     Elf,
@@ -205,7 +669,7 @@ impl std::fmt::Display for InputKind {
 }
 
 /// Output type
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum OutputType {
     /// LLVM bitcode.
     Bitcode,
@@ -217,6 +681,112 @@ pub enum OutputType {
     Object,
 }
 
+/// How the diagnostics reported by LLVM are printed, as set by [`Linker::set_message_format`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MessageFormat {
+    /// Log the diagnostics through `tracing`.
+    #[default]
+    Human,
+    /// Print each diagnostic to stderr as a [`JsonDiagnostic`] on its own line.
+    Json,
+}
+
+impl std::fmt::Display for MessageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Human => "human",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// A diagnostic printed with [`MessageFormat::Json`]: a JSON object on a single line with the
+/// `level`, `message`, `file` and `stage` keys. `file` and `stage` are `null` when unknown.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonDiagnostic<'a> {
+    /// The severity of the diagnostic: `error`, `warning`, `remark` or `note`.
+    pub level: &'a str,
+    /// The message of the diagnostic.
+    pub message: &'a str,
+    /// The input being processed when the diagnostic was reported.
+    pub file: Option<&'a Path>,
+    /// The stage of the link the diagnostic was reported in.
+    pub stage: Option<Stage>,
+}
+
+impl std::fmt::Display for JsonDiagnostic<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            level,
+            message,
+            file,
+            stage,
+        } = self;
+        let file = file.map_or("null".to_owned(), |file| {
+            json_string(&file.to_string_lossy())
+        });
+        let stage = stage.map_or("null".to_owned(), |stage| json_string(stage.name()));
+        write!(
+            f,
+            r#"{{"level":{},"message":{},"file":{file},"stage":{stage}}}"#,
+            json_string(level),
+            json_string(message.trim_end())
+        )
+    }
+}
+
+impl FromStr for MessageFormat {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "human" => Self::Human,
+            "json" => Self::Json,
+            _ => return Err(LinkerError::InvalidMessageFormat(s.to_string())),
+        })
+    }
+}
+
+/// An integer symbol defined on the command line, like with the `--defsym` option of GNU ld.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SymbolDefinition {
+    /// The name of the global.
+    pub name: String,
+    /// The value of the global, which must fit in its integer type.
+    pub value: u64,
+}
+
+impl std::fmt::Display for SymbolDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { name, value } = self;
+        write!(f, "{name}={value}")
+    }
+}
+
+impl FromStr for SymbolDefinition {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LinkerError::InvalidSymbolDefinition(s.to_string());
+        let (name, value) = s.split_once('=').ok_or_else(invalid)?;
+        let value = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| invalid())?;
+        if name.is_empty() || name.contains('\0') {
+            return Err(invalid());
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            value,
+        })
+    }
+}
+
 /// Options to configure the linker
 #[derive(Debug)]
 pub struct LinkerOptions {
@@ -227,14 +797,75 @@ pub struct LinkerOptions {
     pub cpu: Cpu,
     /// Cpu features.
     pub cpu_features: CString,
+    /// Relocation model.
+    pub reloc_model: RelocModel,
+    /// Code model.
+    pub code_model: CodeModel,
     /// Optimization level.
     pub optimize: OptLevel,
+    /// LLVM pass pipeline to run instead of the one selected by `optimize`, in the syntax of
+    /// `opt -passes`. `default<ON>` must come first in the pipeline, otherwise it is ignored. An
+    /// empty pipeline runs the one selected by `optimize`.
+    pub passes: Option<String>,
+    /// LLVM passes to run at the end of the pipeline, after the `dce` pass of the pipeline
+    /// selected by `optimize` or after [`LinkerOptions::passes`], in the syntax of `opt -passes`.
+    pub extra_passes: Option<String>,
+    /// Passes to remove from the pipeline. Can contain `dce`, `loop-unroll`, `loop-vectorize` and
+    /// `slp-vectorizer`.
+    pub disabled_passes: Vec<String>,
+    /// Keep the unreferenced functions which aren't exported. The trailing `dce` pass is removed
+    /// from the pipeline and the functions given internal linkage are kept alive through
+    /// `llvm.compiler.used`, so they stay internal but aren't deleted.
+    pub disable_dce: bool,
+    /// The cost under which the inliner inlines a call, instead of the default of the
+    /// optimization level. Also applies to a custom [`LinkerOptions::passes`] pipeline.
+    pub inline_threshold: Option<i32>,
     /// Whether to aggressively unroll loops. Useful for older kernels that don't support loops.
     pub unroll_loops: bool,
+    /// The maximum number of times a loop is unrolled when [`LinkerOptions::unroll_loops`] is set.
+    /// The loops with a larger trip count are only partially unrolled, and the functions which
+    /// still contain a loop are reported with a warning.
+    pub unroll_max_count: Option<u32>,
+    /// Make LLVM print how long each pass took to stderr, after running the optimization passes
+    /// and after generating the code.
+    pub time_passes: bool,
+    /// Make LLVM print its statistics counters, like the number of instructions combined, to
+    /// stderr when the process exits. Only LLVM builds with assertions or
+    /// `LLVM_FORCE_ENABLE_STATS` collect statistics.
+    pub stats: bool,
+    /// Report the functions whose estimated stack usage after optimization exceeds the given
+    /// number of bytes. The verifier rejects the programs using more than 512 bytes. The
+    /// estimate is the total size of the allocas of the function, so it includes the inlined
+    /// callees but not the spilled registers. Internal functions are not checked.
+    pub stack_size_limit: Option<u64>,
+    /// The functions, or glob patterns matching functions, which may be called without being
+    /// defined by any input, like the kfuncs resolved by the loader. Their declarations keep
+    /// external linkage and survive the optimization passes. The link fails with
+    /// [`LinkerError::UndefinedCalls`] when the optimized module calls any other undefined
+    /// function, except the memory builtins and the BPF helpers, whose names start with `bpf_`.
+    /// The functions declared in the `.ksyms` section, like the kfuncs declared with libbpf's
+    /// `__ksym`, are not helpers.
+    pub allow_undefined: Vec<String>,
+    /// Fail with [`LinkerError::InstructionLimitExceeded`] when a function which isn't internal
+    /// has more than the given number of LLVM IR instructions after optimization, counting the
+    /// instructions of the internal functions it calls. The number of IR instructions only
+    /// approximates the number of BPF instructions the verifier limits.
+    pub max_insns: Option<usize>,
+    /// Fail with [`LinkerError::StackSizeLimitExceeded`] instead of warning about the functions
+    /// exceeding [`LinkerOptions::stack_size_limit`].
+    pub stack_size_limit_error: bool,
     /// Remove `noinline` attributes from functions. Useful for kernels before 5.8 that don't
     /// support function calls.
     pub ignore_inline_never: bool,
-    /// Extra command line args to pass to LLVM.
+    /// The function attributes, like `optnone` or `noinline`, to remove from every function
+    /// before optimizing. Names which aren't LLVM enum attributes are ignored with a warning.
+    pub remove_attributes: Vec<String>,
+    /// Keep the `optnone` attributes, which disable the optimization of the functions they're on.
+    /// They are otherwise removed at [`OptLevel::Default`] and higher, including the size levels,
+    /// so that unoptimized inputs still get optimized.
+    pub keep_optnone: bool,
+    /// Extra command line args to pass to LLVM. When an option is given several times, including
+    /// the options the linker passes to LLVM, the last one wins.
     pub llvm_args: Vec<CString>,
     /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
     pub disable_expand_memcpy_in_order: bool,
@@ -242,32 +873,173 @@ pub struct LinkerOptions {
     /// those is commonly needed when LLVM does not manage to expand memory
     /// intrinsics to a sequence of loads and stores.
     pub disable_memory_builtins: bool,
-    /// Emit BTF information
-    pub btf: bool,
+    /// Warn instead of failing when a symbol explicitly requested to be exported is not defined.
+    pub allow_missing_exports: bool,
+    /// Run the LLVM module verifier on the linked module before and after optimization, failing
+    /// with the invalid constructs it finds instead of letting the optimizer or the code
+    /// generator choke on them.
+    pub verify: bool,
+    /// Append LLVM's `verify` pass to the optimization pipeline, including a custom
+    /// [`LinkerOptions::passes`] pipeline, so that the optimized module is verified before
+    /// generating code. LLVM aborts the link when the module is broken.
+    pub verify_module: bool,
+    /// Keep the linkage and visibility of every symbol instead of internalizing the symbols which
+    /// are not exported, so that they all remain visible in the output.
+    pub export_all: bool,
+    /// Fail with [`LinkerError::UnmatchedExportPatterns`] instead of warning when an export glob
+    /// or regex pattern matches no symbol defined by the linked module.
+    pub strict_exports: bool,
+    /// The symbols, or glob patterns matching symbols, to keep the linkage and visibility of
+    /// without exporting them. Unlike the exported symbols, they don't have to be defined and
+    /// their functions get static linkage in the BTF func info, like the internal functions. The
+    /// exported symbols are always kept.
+    pub keep_symbols: Vec<String>,
+    /// The symbols to wrap, like the `--wrap` option of GNU ld. Once the inputs are linked, the
+    /// references to each symbol `foo` are redirected to `__wrap_foo`, and the references to
+    /// `__real_foo` to `foo`. Symbols which, or whose wrapper, are not in the linked module are
+    /// reported with a warning.
+    pub wrap: Vec<String>,
+    /// The integer globals to define once the inputs are linked. A declared global gets the value
+    /// as its initializer, which fails with [`LinkerError::DefineSymbolError`] when the global
+    /// isn't an integer or the value doesn't fit in its type. A missing global is created as an
+    /// internal `i64` constant.
+    pub defsym: Vec<SymbolDefinition>,
+    /// Fail instead of warning when a symbol with external linkage is defined by several inputs.
+    pub deny_duplicate_symbols: bool,
+    /// Fail instead of warning when an input module has a target triple other than `bpf*`. Inputs
+    /// built for the host by a rustc without BPF support always do, so only set this when the
+    /// inputs are built for a BPF target.
+    pub strict_triple: bool,
+    /// Which BTF information to emit.
+    pub btf: BtfMode,
+    /// Strip all the debug info before generating the code, so that neither DWARF nor BTF is
+    /// emitted, overriding [`LinkerOptions::btf`].
+    pub strip_debug: bool,
+    /// Strip the debug info like [`LinkerOptions::strip_debug`], and give the internal functions
+    /// which survive optimization private linkage, so that their names are left out of the
+    /// symbol table of the object. Loaders which find the BPF-to-BPF call targets through the
+    /// symbol table, like libbpf, can't load calls to such functions.
+    pub strip_all: bool,
+    /// Strip the source locations of instructions when emitting any BTF information, dropping the
+    /// BTF line info while keeping the types and functions.
+    pub strip_line_info: bool,
+    /// Delete the global variables which are neither used nor exported after optimization. This
+    /// complements the dead code elimination of the optimization passes for the globals they keep,
+    /// like the ones which aren't internalized with [`LinkerOptions::export_all`].
+    pub strip_unused_globals: bool,
+    /// Detach the debug info of the functions given internal linkage when emitting any BTF
+    /// information, so that only the exported functions get BTF func info and line info.
+    pub strip_internal_debug_info: bool,
     /// Permit automatic insertion of __bpf_trap calls.
     /// See: https://github.com/llvm/llvm-project/commit/ab391beb11f733b526b86f9df23734a34657d876
     pub allow_bpf_trap: bool,
 }
 
+impl Default for LinkerOptions {
+    /// Returns the options of a link for the generic CPU with the default optimizations and no
+    /// BTF, inferring the target from the inputs.
+    fn default() -> Self {
+        Self {
+            target: None,
+            cpu: Cpu::Generic,
+            cpu_features: CString::default(),
+            reloc_model: RelocModel::Default,
+            code_model: CodeModel::Default,
+            optimize: OptLevel::Default,
+            passes: None,
+            extra_passes: None,
+            disabled_passes: Vec::new(),
+            disable_dce: false,
+            inline_threshold: None,
+            unroll_loops: false,
+            unroll_max_count: None,
+            time_passes: false,
+            stats: false,
+            stack_size_limit: None,
+            allow_undefined: Vec::new(),
+            max_insns: None,
+            stack_size_limit_error: false,
+            ignore_inline_never: false,
+            remove_attributes: Vec::new(),
+            keep_optnone: false,
+            llvm_args: Vec::new(),
+            disable_expand_memcpy_in_order: false,
+            disable_memory_builtins: false,
+            allow_missing_exports: false,
+            verify: false,
+            verify_module: false,
+            export_all: false,
+            strict_exports: false,
+            keep_symbols: Vec::new(),
+            wrap: Vec::new(),
+            defsym: Vec::new(),
+            deny_duplicate_symbols: false,
+            strict_triple: false,
+            btf: BtfMode::None,
+            strip_debug: false,
+            strip_all: false,
+            strip_line_info: false,
+            strip_unused_globals: false,
+            strip_internal_debug_info: false,
+            allow_bpf_trap: false,
+        }
+    }
+}
+
 /// BPF Linker
 pub struct Linker {
     options: LinkerOptions,
     context: LLVMContext,
     diagnostic_handler: llvm::InstalledDiagnosticHandler<DiagnosticHandler>,
     dump_module: Option<PathBuf>,
+    dump_btf: Option<PathBuf>,
+    dump_btf_ext: Option<PathBuf>,
+    dump_section: Option<(CString, PathBuf)>,
+    save_temps: Option<PathBuf>,
+    symbol_manifest: Option<PathBuf>,
+    dump_attributes: bool,
+    print_symbols: Option<SymbolTableOutput>,
+    print_symbols_format: MessageFormat,
+    print_eliminated: bool,
+    print_stats: bool,
+    print_insn_count: bool,
+    time_link: bool,
+    incremental: Option<PathBuf>,
+}
+
+/// Where the linker prints the symbol table.
+enum SymbolTableOutput {
+    Stdout,
+    File(PathBuf),
 }
 
 impl Linker {
     /// Create a new linker instance with the given options.
-    pub fn new(options: LinkerOptions) -> Self {
-        let (context, diagnostic_handler) = llvm_init(&options);
+    ///
+    /// Fails when [`LinkerOptions::llvm_args`] holds an argument which is not one of the LLVM
+    /// options bpf-linker accepts.
+    pub fn new(options: LinkerOptions) -> Result<Self, LinkerError> {
+        let (context, diagnostic_handler) = llvm_init(&options)?;
 
-        Self {
+        Ok(Self {
             options,
             context,
             diagnostic_handler,
             dump_module: None,
-        }
+            dump_btf: None,
+            dump_btf_ext: None,
+            dump_section: None,
+            save_temps: None,
+            symbol_manifest: None,
+            dump_attributes: false,
+            print_symbols: None,
+            print_symbols_format: MessageFormat::Human,
+            print_eliminated: false,
+            print_stats: false,
+            print_insn_count: false,
+            time_link: false,
+            incremental: None,
+        })
     }
 
     /// Set the directory where the linker will dump the linked LLVM IR before and after
@@ -281,30 +1053,188 @@ impl Linker {
         self.dump_module = Some(path.as_ref().to_path_buf())
     }
 
-    /// Link and generate the output code to file.
+    /// Set the path where the linker writes the raw contents of the `.BTF` section of the
+    /// generated object. The object is generated once more for this, from a copy of the optimized
+    /// module. Fails with [`LinkerError::MissingBtfSection`] when no BTF is emitted, see
+    /// [`LinkerOptions::btf`].
+    pub fn set_dump_btf_path(&mut self, path: impl AsRef<Path>) {
+        self.dump_btf = Some(path.as_ref().to_path_buf())
+    }
+
+    /// Set the path where the linker writes the raw contents of the `.BTF.ext` section of the
+    /// generated object, like [`Linker::set_dump_btf_path`]. Fails with
+    /// [`LinkerError::MissingBtfExtSection`] when no BTF is emitted.
+    pub fn set_dump_btf_ext_path(&mut self, path: impl AsRef<Path>) {
+        self.dump_btf_ext = Some(path.as_ref().to_path_buf())
+    }
+
+    /// Set the path where the linker writes the raw contents of the section `name` of the input
+    /// objects, concatenated in link order. The link fails with
+    /// [`LinkerError::MissingDumpedSection`] when no input object has the section.
+    pub fn set_dump_section(
+        &mut self,
+        name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<(), LinkerError> {
+        let name =
+            CString::new(name).map_err(|_| LinkerError::InvalidSectionName(name.to_owned()))?;
+        self.dump_section = Some((name, path.as_ref().to_path_buf()));
+        Ok(())
+    }
+
+    /// Set the directory where the linker will write every input module and the linked module at
+    /// each stage of the pipeline, for debugging and inspection purposes.
+    ///
+    /// When set:
+    /// - The directory is created if it does not already exist.
+    /// - Each input module is written as LLVM IR to `<input file stem>.<index>.ll`, where `index`
+    ///   counts the input modules in the order they are linked. Archive members are named after
+    ///   the member.
+    /// - The linked module is written as LLVM IR and bitcode to `<output file stem>.post-link`,
+    ///   `<output file stem>.post-disanitize` and `<output file stem>.post-opt`, with the `.ll` and
+    ///   `.bc` extensions, after linking, after sanitizing or stripping the debug info and after
+    ///   optimization. The stem is `out` when linking to a buffer or to stdout.
+    pub fn set_save_temps_path(&mut self, path: impl AsRef<Path>) {
+        self.save_temps = Some(path.as_ref().to_path_buf())
+    }
+
+    /// Set the directory where the linker caches the optimized modules and the objects, to skip
+    /// optimizing and generating the code again when linking the same inputs with the same options.
+    ///
+    /// When set:
+    /// - The directory is created if it does not already exist.
+    /// - The linked module is hashed with SHA-256, along with the export symbols, the versions of
+    ///   the linker and LLVM, and the options changing the optimized module or the generated code,
+    ///   like the optimization level, the pass pipeline and the resolved CPU. The optimized module
+    ///   is cached as `<hash>.bc` and the object as `<hash>.o`, and reused by the following links
+    ///   with the same hash.
+    /// - The entries are written to a temporary file and renamed into place. Failing to write them
+    ///   is reported as a warning and doesn't fail the link.
+    /// - The diagnostics LLVM issues while optimizing and generating the code are not reported
+    ///   again when the cache is used.
+    /// - The cache isn't used when saving the temps, writing the symbol manifest or printing the
+    ///   eliminated symbols, which need the optimization passes to run.
+    pub fn set_incremental_path(&mut self, path: impl AsRef<Path>) {
+        self.incremental = Some(path.as_ref().to_path_buf())
+    }
+
+    /// Set the path where the linker will write a JSON manifest of the linkage decisions taken
+    /// while internalizing.
+    ///
+    /// The manifest is an array with an object for every global, alias and function of the
+    /// linked module, recording its `name`, its `kind`, whether it matched an export
+    /// (`exported`), whether it was given internal linkage (`internalized`) and whether it is only
+    /// declared (`undefined`). Undefined symbols are never internalized.
+    pub fn set_symbol_manifest_path(&mut self, path: impl AsRef<Path>) {
+        self.symbol_manifest = Some(path.as_ref().to_path_buf())
+    }
+
+    /// Set whether the linker prints the attributes of every function to stderr after
+    /// optimization, one `<function>: <attributes>` line per function. This helps finding the
+    /// functions that attributes like `noinline` or `optnone` kept from being optimized.
+    pub fn set_dump_attributes(&mut self, dump_attributes: bool) {
+        self.dump_attributes = dump_attributes
+    }
+
+    /// Set whether the linker prints the externally visible symbols of the linked module to stdout
+    /// after optimization.
+    ///
+    /// Like `nm`, every function, global and alias which is not `internal` or `private` is printed
+    /// on its own `<name> <linkage> <visibility>` line. This helps finding out why a symbol is
+    /// missing from the output.
+    pub fn set_print_symbols(&mut self, print_symbols: bool) {
+        self.print_symbols = print_symbols.then_some(SymbolTableOutput::Stdout)
+    }
+
+    /// Set whether the linker reports to stderr what optimization threw away, like the
+    /// `--print-gc-sections` option of other linkers.
+    ///
+    /// Every symbol removed by the optimization passes is printed on a `removed: <name>` line,
+    /// and every symbol given internal linkage which survived on an `internalized: <name>` line.
+    pub fn set_print_eliminated(&mut self, print_eliminated: bool) {
+        self.print_eliminated = print_eliminated
+    }
+
+    /// Set whether the linker prints to stderr how many functions, defined functions, global
+    /// variables and instructions the linked module has before and after optimization.
+    pub fn set_print_stats(&mut self, print_stats: bool) {
+        self.print_stats = print_stats
+    }
+
+    /// Set whether the linker prints to stderr the number of instructions of every function which
+    /// isn't internal after optimization, including the instructions of the internal functions it
+    /// calls, as described in [`LinkerOptions::max_insns`].
+    pub fn set_print_insn_count(&mut self, print_insn_count: bool) {
+        self.print_insn_count = print_insn_count
+    }
+
+    /// Set whether the linker prints to stderr how long each stage of the link took, once the
+    /// outputs are written.
+    ///
+    /// The stages are parsing the input modules, extracting the bitcode embedded in object
+    /// files, linking the modules, sanitizing or stripping the debug info, running the
+    /// optimization passes and generating the outputs. The time of a stage excludes the time
+    /// of the stages it runs, like parsing the bitcode extracted from an object file. Each stage
+    /// is also traced in a `stage` span.
+    pub fn set_time_link(&mut self, time_link: bool) {
+        self.time_link = time_link
+    }
+
+    /// Set how the diagnostics reported by LLVM are printed. Defaults to
+    /// [`MessageFormat::Human`].
+    pub fn set_message_format(&mut self, message_format: MessageFormat) {
+        self.diagnostic_handler
+            .with_view(|h| h.message_format.set(message_format))
+    }
+
+    /// Set whether the severity of the diagnostics reported by LLVM is colored with ANSI escape
+    /// codes in the [`MessageFormat::Human`] output. Defaults to `false`.
+    pub fn set_color(&mut self, color: bool) {
+        self.diagnostic_handler.with_view(|h| h.color.set(color))
+    }
+
+    /// Only report the remarks of LLVM whose message matches the regex `pattern`. The other
+    /// remarks are dropped: they are neither logged nor counted.
+    pub fn set_remarks_filter(&mut self, pattern: &str) -> Result<(), LinkerError> {
+        let regex = Regex::new(pattern).map_err(|err| {
+            LinkerError::InvalidRemarksFilter(pattern.to_owned(), err.to_string())
+        })?;
+        self.diagnostic_handler
+            .with_view(|h| *h.remarks_filter.borrow_mut() = Some(regex));
+        Ok(())
+    }
+
+    /// Set the path where the linker writes the symbol table described in
+    /// [`Linker::set_print_symbols`], instead of stdout.
+    pub fn set_print_symbols_path(&mut self, path: impl AsRef<Path>) {
+        self.print_symbols = Some(SymbolTableOutput::File(path.as_ref().to_path_buf()))
+    }
+
+    /// Set the format of the symbol table described in [`Linker::set_print_symbols`]. With
+    /// [`MessageFormat::Json`], the symbols are printed as a JSON array of objects with the `name`,
+    /// `kind`, `linkage`, `visibility`, `defined` and `exported` keys.
+    pub fn set_print_symbols_format(&mut self, format: MessageFormat) {
+        self.print_symbols_format = format
+    }
+
+    /// Link and generate the output code to file. An `output` of `-` writes the output to stdout.
+    ///
+    /// The inputs are [`LinkerInput`]s, or `(name, bytes)` tuples for the inputs in memory, which
+    /// are parsed from `bytes` without being written to a file and named `name` in the diagnostics.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// # use std::{collections::HashSet, path::Path, borrow::Cow, ffi::CString};
-    /// # use bpf_linker::{Cpu, Linker, LinkerInput, LinkerOptions, OptLevel, OutputType};
+    /// # use std::path::Path;
+    /// # use bpf_linker::{Linker, LinkerInput, LinkerOptions, OptLevel, OutputType};
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// let path = Path::new("/path/to/object-or-bitcode");
     /// let bytes: &[u8] = &[]; // An in memory object/bitcode
     /// # let options = LinkerOptions {
-    /// #     target: None,
-    /// #     cpu: Cpu::Generic,
-    /// #     cpu_features: CString::default(),
     /// #     optimize: OptLevel::Default,
-    /// #     unroll_loops: false,
-    /// #     ignore_inline_never: false,
-    /// #     llvm_args: vec![],
-    /// #     disable_expand_memcpy_in_order: false,
-    /// #     disable_memory_builtins: false,
-    /// #     allow_bpf_trap: false,
-    /// #     btf: false,
+    /// #     ..Default::default()
     /// # };
-    /// # let linker = Linker::new(options);
+    /// # let linker = Linker::new(options)?;
     ///
     /// let export_symbols = ["my_sym_1", "my_sym_2"];
     ///
@@ -320,7 +1250,7 @@ impl Linker {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn link_to_file<'i, 'a, I, P, E>(
+    pub fn link_to_file<'i, 'a, I, T, P, E, S>(
         &self,
         inputs: I,
         output: P,
@@ -328,17 +1258,80 @@ impl Linker {
         export_symbols: E,
     ) -> Result<(), LinkerError>
     where
-        I: IntoIterator<Item = LinkerInput<'i>>,
-        E: IntoIterator<Item = &'a str>,
+        I: IntoIterator<Item = T>,
+        T: Into<LinkerInput<'i>>,
+        E: IntoIterator<Item = S>,
+        S: Into<ExportSpec<'a>>,
         P: AsRef<Path>,
     {
-        let (linked_module, target_machine) = self.link(inputs, export_symbols)?;
-        codegen_to_file(
-            &linked_module,
-            &target_machine,
-            output.as_ref(),
-            output_type,
-        )?;
+        self.link_to_files(inputs, [(output_type, output)], export_symbols)
+    }
+
+    /// Link once and write every one of the given outputs to file.
+    ///
+    /// This is equivalent to calling [`Linker::link_to_file`] for each output, without linking
+    /// and optimizing the inputs more than once. Every output is written from the same optimized
+    /// module. When writing an output fails, the other outputs are still written and the error
+    /// is returned afterwards, wrapped in [`LinkerError::WriteOutputsError`] when several outputs
+    /// failed.
+    pub fn link_to_files<'i, 'a, I, T, O, P, E, S>(
+        &self,
+        inputs: I,
+        outputs: O,
+        export_symbols: E,
+    ) -> Result<(), LinkerError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<LinkerInput<'i>>,
+        O: IntoIterator<Item = (OutputType, P)>,
+        P: AsRef<Path>,
+        E: IntoIterator<Item = S>,
+        S: Into<ExportSpec<'a>>,
+    {
+        let mut outputs = outputs.into_iter().peekable();
+        let output_name = outputs
+            .peek()
+            .map(|(_, output)| output.as_ref())
+            .filter(|output| *output != Path::new("-"))
+            .and_then(Path::file_stem)
+            .unwrap_or(OsStr::new("out"))
+            .to_owned();
+        let start = Instant::now();
+        let timings = Timings::new(&self.diagnostic_handler);
+        let (linked_module, target_machine, cache) =
+            self.link(inputs, &output_name, export_symbols, &timings)?;
+        let codegen = |module: &LLVMModule<'_>, output: &Path, output_type| match &cache {
+            Some(cache) if output_type == OutputType::Object && output != Path::new("-") => {
+                cache.codegen_object(module, &target_machine, output)
+            }
+            _ => codegen_to_file(module, &target_machine, output, output_type),
+        };
+
+        timings.time(Stage::Codegen, || -> Result<(), LinkerError> {
+            // Code generation modifies the module, so write the IR outputs first and generate all
+            // the assembly and object outputs but the last one from a copy of the module.
+            let (ir_outputs, codegen_outputs): (Vec<_>, Vec<_>) =
+                outputs.partition(|(output_type, _)| {
+                    matches!(output_type, OutputType::Bitcode | OutputType::LlvmAssembly)
+                });
+            let mut errors = Vec::new();
+            for (output_type, output) in ir_outputs {
+                errors.extend(codegen(&linked_module, output.as_ref(), output_type).err());
+            }
+            if let Some(((output_type, output), rest)) = codegen_outputs.split_last() {
+                for (output_type, output) in rest {
+                    errors.extend(
+                        codegen(&linked_module.clone_module(), output.as_ref(), *output_type).err(),
+                    );
+                }
+                errors.extend(codegen(&linked_module, output.as_ref(), *output_type).err());
+            }
+            if errors.len() > 1 {
+                return Err(LinkerError::WriteOutputsError(errors));
+            }
+            errors.pop().map_or(Ok(()), Err)
+        })?;
+        self.report_timings(&timings, start.elapsed());
         Ok(())
     }
 
@@ -347,25 +1340,16 @@ impl Linker {
     /// # Example
     ///
     /// ```rust,no_run
-    /// # use std::{collections::HashSet, path::Path, borrow::Cow, ffi::CString};
-    /// # use bpf_linker::{Cpu, Linker, LinkerInput, LinkerOptions, OptLevel, OutputType};
+    /// # use std::path::Path;
+    /// # use bpf_linker::{Linker, LinkerInput, LinkerOptions, OptLevel, OutputType};
     /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
     /// let path = Path::new("/path/to/object-or-bitcode");
     /// let bytes: &[u8] = &[]; // An in memory object/bitcode
     /// # let options = LinkerOptions {
-    /// #     target: None,
-    /// #     cpu: Cpu::Generic,
-    /// #     cpu_features: CString::default(),
     /// #     optimize: OptLevel::Default,
-    /// #     unroll_loops: false,
-    /// #     ignore_inline_never: false,
-    /// #     llvm_args: vec![],
-    /// #     disable_expand_memcpy_in_order: false,
-    /// #     disable_memory_builtins: false,
-    /// #     allow_bpf_trap: false,
-    /// #     btf: false,
+    /// #     ..Default::default()
     /// # };
-    /// # let linker = Linker::new(options);
+    /// # let linker = Linker::new(options)?;
     ///
     /// let export_symbols = ["my_sym_1", "my_sym_2"];
     ///
@@ -385,79 +1369,336 @@ impl Linker {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn link_to_buffer<'i, 'a, I, E>(
+    pub fn link_to_buffer<'i, 'a, I, T, E, S>(
         &self,
         inputs: I,
         output_type: OutputType,
         export_symbols: E,
     ) -> Result<LinkerOutput, LinkerError>
     where
-        I: IntoIterator<Item = LinkerInput<'i>>,
-        E: IntoIterator<Item = &'a str>,
+        I: IntoIterator<Item = T>,
+        T: Into<LinkerInput<'i>>,
+        E: IntoIterator<Item = S>,
+        S: Into<ExportSpec<'a>>,
+    {
+        let start = Instant::now();
+        let timings = Timings::new(&self.diagnostic_handler);
+        let (linked_module, target_machine, _cache) =
+            self.link(inputs, OsStr::new("out"), export_symbols, &timings)?;
+        let output = timings.time(Stage::Codegen, || {
+            codegen_to_buffer(&linked_module, &target_machine, output_type)
+        })?;
+        self.report_timings(&timings, start.elapsed());
+        Ok(output)
+    }
+
+    /// Link and optimize the inputs without generating any output.
+    ///
+    /// This parses, links and optimizes the inputs exactly like [`Linker::link_to_file`] does,
+    /// reporting the same errors and LLVM diagnostics, but stops before code generation. It is
+    /// cheaper than a full link when only checking that the inputs link cleanly.
+    pub fn link_dry_run<'i, 'a, I, T, E, S>(
+        &self,
+        inputs: I,
+        export_symbols: E,
+    ) -> Result<(), LinkerError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<LinkerInput<'i>>,
+        E: IntoIterator<Item = S>,
+        S: Into<ExportSpec<'a>>,
     {
-        let (linked_module, target_machine) = self.link(inputs, export_symbols)?;
-        codegen_to_buffer(&linked_module, &target_machine, output_type)
+        let start = Instant::now();
+        let timings = Timings::new(&self.diagnostic_handler);
+        let (_module, _target_machine, _cache) =
+            self.link(inputs, OsStr::new("out"), export_symbols, &timings)?;
+        self.report_timings(&timings, start.elapsed());
+        Ok(())
+    }
+
+    fn report_timings(&self, timings: &Timings<'_>, total: Duration) {
+        if self.time_link {
+            eprint!("{}", timings.format(total));
+        }
     }
 
-    /// Link and generate the output code.
-    fn link<'ctx, 'i, 'a, I, E>(
+    /// Link and generate the output code. `output_name` names the files written by save-temps.
+    /// Also returns the incremental cache entry of the link, if any.
+    fn link<'ctx, 'i, 'a, I, T, E, S>(
         &'ctx self,
         inputs: I,
+        output_name: &OsStr,
         export_symbols: E,
-    ) -> Result<(LLVMModule<'ctx>, LLVMTargetMachine), LinkerError>
+        timings: &Timings<'_>,
+    ) -> Result<
+        (
+            LLVMModule<'ctx>,
+            LLVMTargetMachine,
+            Option<IncrementalCache>,
+        ),
+        LinkerError,
+    >
     where
-        I: IntoIterator<Item = LinkerInput<'i>>,
-        E: IntoIterator<Item = &'a str>,
+        I: IntoIterator<Item = T>,
+        T: Into<LinkerInput<'i>>,
+        E: IntoIterator<Item = S>,
+        S: Into<ExportSpec<'a>>,
     {
         let Self {
             options,
             context,
+            diagnostic_handler,
             dump_module,
-            ..
+            dump_btf,
+            dump_btf_ext,
+            dump_section,
+            save_temps,
+            symbol_manifest,
+            dump_attributes,
+            print_symbols,
+            print_symbols_format,
+            print_eliminated,
+            print_stats,
+            print_insn_count,
+            time_link: _,
+            incremental,
         } = self;
 
-        let mut module = link_modules(context, inputs)?;
+        let mut export_symbols = ExportSymbols::new(export_symbols).map_err(|(pattern, err)| {
+            LinkerError::InvalidExportPattern(pattern.to_owned(), err.to_string())
+        })?;
+        if !options.disable_memory_builtins {
+            for symbol in ["memcpy", "memmove", "memset", "memcmp", "bcmp"] {
+                export_symbols.insert_optional(symbol.as_bytes());
+            }
+        };
+
+        let mut save_temps = save_temps
+            .as_deref()
+            .map(|dir| SaveTemps::new(dir, output_name))
+            .transpose()?;
+        let mut section_dump = dump_section.as_ref().map(|(name, _)| SectionDump {
+            name,
+            contents: None,
+        });
+        let mut module = link_modules(
+            context,
+            diagnostic_handler,
+            inputs.into_iter().map(Into::into),
+            save_temps.as_mut(),
+            section_dump.as_mut(),
+            timings,
+            InputChecks {
+                target: options.target.as_deref(),
+                definitions: HashMap::new(),
+                deny_duplicate_symbols: options.deny_duplicate_symbols,
+                strict_triple: options.strict_triple,
+            },
+        )?;
+        if let (Some(SectionDump { name, contents }), Some((_, path))) =
+            (section_dump, dump_section)
+        {
+            let contents = contents.ok_or_else(|| {
+                LinkerError::MissingDumpedSection(name.to_string_lossy().into_owned())
+            })?;
+            fs::write(path, contents).map_err(|err| LinkerError::IoError(path.to_owned(), err))?;
+        }
+        for name in llvm::wrap_symbols(&mut module, &options.wrap) {
+            warn!("cannot wrap symbols: `{name}` is not in the linked module");
+        }
+        for SymbolDefinition { name, value } in &options.defsym {
+            llvm::define_symbol(&mut module, name, *value)
+                .map_err(|err| LinkerError::DefineSymbolError(name.clone(), err))?;
+        }
+        if let Some(save_temps) = &save_temps {
+            save_temps.save_stage("post-link", &module)?;
+        }
 
         let target_machine = create_target_machine(options, &module)?;
 
-        if let Some(path) = dump_module {
-            fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.to_owned(), err))?;
+        if options.verify {
+            verify_module(&module, "after linking")?;
         }
+
         if let Some(path) = dump_module {
+            fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.to_owned(), err))?;
             // dump IR before optimization
-            let path = path.join("pre-opt.ll");
-            let path = CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
-            module
-                .write_ir_to_path(&path)
-                .map_err(LinkerError::WriteIRError)?;
+            write_ir(&module, &path.join("pre-opt.ll"))?;
         };
-        optimize(
-            options,
-            context,
-            &target_machine,
-            &mut module,
-            export_symbols,
-        )?;
+        let pre_opt_stats = print_stats.then(|| llvm::module_statistics(&module));
+        let cache = match incremental {
+            Some(_) if save_temps.is_some() || symbol_manifest.is_some() || *print_eliminated => {
+                info!(
+                    "not using the incremental cache, the optimization passes must run to save the temps, write the symbol manifest or print the eliminated symbols"
+                );
+                None
+            }
+            Some(dir) => Some(IncrementalCache::new(
+                dir,
+                options,
+                &export_symbols,
+                &target_machine,
+                &module,
+            )?),
+            None => None,
+        };
+        let cached = cache
+            .as_ref()
+            .map(|cache| cache.load_module(context))
+            .transpose()?
+            .flatten();
+        let symbols = match cached {
+            Some(cached) => {
+                module = cached;
+                Vec::new()
+            }
+            None => {
+                let symbols = optimize(
+                    options,
+                    context,
+                    &target_machine,
+                    &mut module,
+                    &export_symbols,
+                    save_temps.as_ref(),
+                    timings,
+                )?;
+                if let Some(cache) = &cache {
+                    cache.store_module(&module);
+                }
+                symbols
+            }
+        };
+        if let Some(save_temps) = &save_temps {
+            save_temps.save_stage("post-opt", &module)?;
+        }
+        if options.verify {
+            verify_module(&module, "after optimization")?;
+        }
+        check_undefined_calls(&module, &options.allow_undefined)?;
+        if let Some(limit) = options.stack_size_limit {
+            check_stack_sizes(&module, limit, options.stack_size_limit_error)?;
+        }
+        if *print_insn_count || options.max_insns.is_some() {
+            let counts = llvm::program_instruction_counts(&module);
+            if *print_insn_count {
+                eprint!("{}", format_insn_counts(&counts));
+            }
+            if let Some(limit) = options.max_insns {
+                let functions: Vec<_> = counts
+                    .into_iter()
+                    .filter(|(_, count)| *count > limit)
+                    .collect();
+                if !functions.is_empty() {
+                    return Err(LinkerError::InstructionLimitExceeded { limit, functions });
+                }
+            }
+        }
+        if let Some(pre_opt_stats) = pre_opt_stats {
+            let post_opt_stats = llvm::module_statistics(&module);
+            eprint!("{}", format_module_stats(&pre_opt_stats, &post_opt_stats));
+        }
+        if let Some(path) = symbol_manifest {
+            write_symbol_manifest(path, &symbols)?;
+        }
+        if *print_eliminated {
+            let surviving: HashSet<_> = llvm::symbol_table(&module, &export_symbols)
+                .into_iter()
+                .map(|symbol| symbol.name)
+                .collect();
+            for InternalizedSymbol {
+                name, internalized, ..
+            } in &symbols
+            {
+                if !surviving.contains(name) {
+                    eprintln!("removed: {name}");
+                } else if *internalized {
+                    eprintln!("internalized: {name}");
+                }
+            }
+        }
         if let Some(path) = dump_module {
-            // dump IR before optimization
-            let path = path.join("post-opt.ll");
-            let path = CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
-            module
-                .write_ir_to_path(&path)
-                .map_err(LinkerError::WriteIRError)?;
+            // dump IR after optimization
+            write_ir(&module, &path.join("post-opt.ll"))?;
         };
+        if *dump_attributes {
+            for (function, attributes) in llvm::function_attributes(&module) {
+                eprintln!("{function}: {}", attributes.join(" "));
+            }
+        }
+        if let Some(output) = print_symbols {
+            let symbols = llvm::symbol_table(&module, &export_symbols)
+                .into_iter()
+                .filter(|symbol| !matches!(symbol.linkage, "internal" | "private"))
+                .collect::<Vec<_>>();
+            let table = match print_symbols_format {
+                MessageFormat::Human => format_symbol_table(&symbols),
+                MessageFormat::Json => format_symbol_table_json(&symbols),
+            };
+            match output {
+                SymbolTableOutput::Stdout => io::stdout()
+                    .lock()
+                    .write_all(table.as_bytes())
+                    .map_err(|err| LinkerError::IoError(PathBuf::from("-"), err))?,
+                SymbolTableOutput::File(path) => fs::write(path, table)
+                    .map_err(|err| LinkerError::IoError(path.to_owned(), err))?,
+            }
+        }
+        if dump_btf.is_some() || dump_btf_ext.is_some() {
+            // Code generation modifies the module, so generate the object from a copy.
+            let object =
+                codegen_to_buffer(&module.clone_module(), &target_machine, OutputType::Object)?;
+            if let Some(path) = dump_btf {
+                let btf = llvm::find_embedded_btf(context, &object)
+                    .map_err(LinkerError::EmbeddedBtfError)?
+                    .ok_or(LinkerError::MissingBtfSection)?;
+                fs::write(path, btf).map_err(|err| LinkerError::IoError(path.to_owned(), err))?;
+            }
+            if let Some(path) = dump_btf_ext {
+                let btf_ext = llvm::find_embedded_btf_ext(context, &object)
+                    .map_err(LinkerError::EmbeddedBtfError)?
+                    .ok_or(LinkerError::MissingBtfExtSection)?;
+                fs::write(path, btf_ext)
+                    .map_err(|err| LinkerError::IoError(path.to_owned(), err))?;
+            }
+        }
 
-        Ok((module, target_machine))
+        Ok((module, target_machine, cache))
     }
 
     pub fn has_errors(&self) -> bool {
-        self.diagnostic_handler.with_view(|h| h.has_errors)
+        self.diagnostic_handler.with_view(|h| h.counts.errors > 0)
+    }
+
+    /// Returns the number of diagnostics of each severity LLVM reported so far.
+    pub fn diagnostic_counts(&self) -> DiagnosticCounts {
+        self.diagnostic_handler.with_view(|h| h.counts)
+    }
+
+    /// Returns the last stage the latest link entered, which is the stage a failed link stopped
+    /// in. `None` when the link failed before parsing any input.
+    pub fn last_stage(&self) -> Option<Stage> {
+        self.diagnostic_handler.with_view(|h| h.stage.get())
+    }
+
+    /// Returns the messages of the diagnostics with error severity LLVM reported so far.
+    pub fn errors(&self) -> Vec<String> {
+        self.diagnostic_handler.with_view(|h| h.errors.clone())
+    }
+
+    /// Returns the messages of the diagnostics with warning severity LLVM reported so far.
+    pub fn warnings(&self) -> Vec<String> {
+        self.diagnostic_handler.with_view(|h| h.warnings.clone())
     }
 }
 
 fn link_modules<'ctx, 'i, I>(
     context: &'ctx LLVMContext,
+    diagnostic_handler: &llvm::InstalledDiagnosticHandler<DiagnosticHandler>,
     inputs: I,
+    mut save_temps: Option<&mut SaveTemps<'_>>,
+    mut section_dump: Option<&mut SectionDump<'_>>,
+    timings: &Timings<'_>,
+    mut checks: InputChecks<'_>,
 ) -> Result<LLVMModule<'ctx>, LinkerError>
 where
     I: IntoIterator<Item = LinkerInput<'i>>,
@@ -468,23 +1709,23 @@ where
 
     let mut buf = Vec::new();
     for input in inputs {
-        let (path, input) = match input {
+        // Name the LLVM buffers after the input, so that the parse errors point at it.
+        let (path, buffer_name, input) = match input {
             LinkerInput::File { path } => {
                 let data = fs::read(path).map_err(|e| LinkerError::IoError(path.to_owned(), e))?;
-                (path.to_owned(), Cow::Owned(data))
+                let buffer_name = CString::new(path.as_os_str().as_bytes()).unwrap_or_default();
+                (path.to_owned(), buffer_name, Cow::Owned(data))
             }
             LinkerInput::Buffer { name, bytes } => (
                 PathBuf::from(format!("in_memory::{}", name)),
+                CString::new(name).unwrap_or_default(),
                 Cow::Borrowed(bytes),
             ),
         };
 
         // determine whether the input is bitcode, ELF with embedded bitcode, an archive file
-        // or an invalid file
-        let in_type = InputKind::detect(input.as_ref())
-            .ok_or_else(|| LinkerError::InvalidInputType(path.clone()))?;
-
-        match in_type {
+        // or LLVM IR
+        match InputKind::detect(input.as_ref()) {
             InputKind::Archive => {
                 info!("linking archive {}", path.display());
 
@@ -499,30 +1740,39 @@ where
                     let _: usize = item
                         .read_to_end(&mut buf)
                         .map_err(|e| LinkerError::IoError(name.to_owned(), e))?;
-                    let in_type = match LinkerInputKind::detect(&buf) {
-                        Some(in_type) => in_type,
-                        None => {
-                            info!("ignoring archive item {}: invalid type", name.display());
-                            continue;
-                        }
-                    };
-
-                    let prepared_input = match in_type {
+                    let prepared_input = match LinkerInputKind::detect(&buf) {
                         LinkerInputKind::Bitcode => PreparedLinkerInput::Bitcode(&buf),
                         LinkerInputKind::Elf => PreparedLinkerInput::Elf(&buf),
                         LinkerInputKind::MachO => PreparedLinkerInput::MachO(&buf),
                         LinkerInputKind::Ir => {
                             buf.push(b'\0');
-                            PreparedLinkerInput::Ir(CStr::from_bytes_with_nul(&buf).map_err(
-                                |err| LinkerError::IRParseError(name.to_owned(), err.to_string()),
-                            )?)
+                            match CStr::from_bytes_with_nul(&buf) {
+                                Ok(ir) => PreparedLinkerInput::Ir(ir),
+                                // Binary data which is neither bitcode nor an object file can't be
+                                // IR either.
+                                Err(err) => {
+                                    debug!("ignoring archive item {}: {err}", name.display());
+                                    continue;
+                                }
+                            }
                         }
                     };
 
-                    match link_data(context, &mut module, &name, prepared_input) {
+                    match link_data(
+                        context,
+                        diagnostic_handler,
+                        &mut module,
+                        &name,
+                        &CString::new(name.as_os_str().as_bytes()).unwrap_or_default(),
+                        prepared_input,
+                        &mut save_temps,
+                        &mut section_dump,
+                        timings,
+                        &mut checks,
+                    ) {
                         Ok(()) => continue,
                         Err(LinkerError::InvalidInputType(name)) => {
-                            info!("ignoring archive item {}: invalid type", name.display());
+                            debug!("ignoring archive item {}: invalid type", name.display());
                             continue;
                         }
                         Err(LinkerError::MissingBitcodeSection(name)) => {
@@ -532,11 +1782,11 @@ where
                             );
                             continue;
                         }
-                        // TODO: this discards the underlying error.
-                        Err(_) => {
+                        Err(err) => {
                             return Err(LinkerError::LinkArchiveModuleError(
                                 path.to_owned(),
                                 name.to_owned(),
+                                Box::new(err),
                             ));
                         }
                     };
@@ -557,61 +1807,476 @@ where
                     }
                 };
                 info!("linking file {} type {kind}", path.display());
-                match link_data(context, &mut module, &path, prepared_input) {
+                match link_data(
+                    context,
+                    diagnostic_handler,
+                    &mut module,
+                    &path,
+                    &buffer_name,
+                    prepared_input,
+                    &mut save_temps,
+                    &mut section_dump,
+                    timings,
+                    &mut checks,
+                ) {
                     Ok(()) => {}
                     Err(LinkerError::InvalidInputType(path)) => {
                         info!("ignoring file {}: invalid type", path.display());
                         continue;
                     }
-                    Err(LinkerError::MissingBitcodeSection(path)) => {
-                        warn!("ignoring file {}: no embedded bitcode", path.display());
-                    }
                     Err(err) => return Err(err),
                 }
             }
         }
     }
+    // Whatever LLVM reports from now on is about the linked module.
+    diagnostic_handler.with_view(|h| *h.input.borrow_mut() = None);
 
     Ok(module)
 }
 
+#[expect(clippy::too_many_arguments)]
 fn link_data<'ctx>(
     context: &'ctx LLVMContext,
+    diagnostic_handler: &llvm::InstalledDiagnosticHandler<DiagnosticHandler>,
     module: &mut LLVMModule<'ctx>,
     path: &Path,
+    buffer_name: &CStr,
     data: PreparedLinkerInput<'_>,
+    save_temps: &mut Option<&mut SaveTemps<'_>>,
+    section_dump: &mut Option<&mut SectionDump<'_>>,
+    timings: &Timings<'_>,
+    checks: &mut InputChecks<'_>,
 ) -> Result<(), LinkerError> {
-    let mut link_data = |data: &[u8]| {
-        if !llvm::link_bitcode_buffer(context, module, data) {
-            Err(LinkerError::LinkModuleError(path.to_owned()))
+    diagnostic_handler.with_view(|h| *h.input.borrow_mut() = Some(path.to_owned()));
+    // LLVM reports why parsing or linking a module failed through the diagnostic handler.
+    let reported_errors = diagnostic_handler.with_view(|h| h.errors.len());
+    let link_error = || {
+        LinkerError::LinkModuleError(
+            path.to_owned(),
+            diagnostic_handler.with_view(|h| h.errors_since(reported_errors)),
+        )
+    };
+    let mut link_module = |input_module: LLVMModule<'ctx>| {
+        if let Some(save_temps) = save_temps {
+            save_temps.save(path, &input_module)?;
+        }
+        checks.check(path, &input_module)?;
+        if !timings.time(Stage::Link, || module.link(input_module)) {
+            Err(link_error())
         } else {
             Ok(())
         }
     };
+    let mut link_data = |data: &[u8]| match timings
+        .time(Stage::Parse, || context.parse_bitcode(data, buffer_name))
+    {
+        Some(input_module) => link_module(input_module),
+        None => Err(link_error()),
+    };
     match data {
         PreparedLinkerInput::Bitcode(data) => link_data(data),
-        PreparedLinkerInput::Elf(data) => llvm::with_embedded_bitcode(context, data, link_data)
-            .map_err(LinkerError::EmbeddedBitcodeError)
-            .and_then(|opt| {
-                opt.unwrap_or_else(|| Err(LinkerError::MissingBitcodeSection(path.to_owned())))
-            }),
+        PreparedLinkerInput::Elf(data) => {
+            if let Some(section_dump) = section_dump {
+                section_dump.extract(context, path, data)?;
+            }
+            timings
+                .time(Stage::ExtractBitcode, || {
+                    llvm::with_embedded_bitcode(context, data, link_data)
+                })
+                .map_err(LinkerError::EmbeddedBitcodeError)
+                .and_then(|opt| {
+                    opt.unwrap_or_else(|| Err(LinkerError::MissingBitcodeSection(path.to_owned())))
+                })
+        }
         // we need to handle this here since archive files could contain
         // mach-o files, eg somecrate.rlib containing lib.rmeta which is
         // mach-o on macos
         PreparedLinkerInput::MachO(_data) => Err(LinkerError::InvalidInputType(path.to_owned())),
         PreparedLinkerInput::Ir(data) => {
-            let linked = llvm::link_ir_buffer(context, module, data)
+            let input_module = timings
+                .time(Stage::Parse, || context.parse_ir(data, buffer_name))
                 .map_err(|e| LinkerError::IRParseError(path.to_owned(), e))?;
+            link_module(input_module)
+        }
+    }
+}
 
-            if linked {
-                Ok(())
-            } else {
-                Err(LinkerError::LinkModuleError(path.to_owned()))
+/// Checks the input modules before LLVM links them. Records the input defining every symbol with
+/// external linkage, to report the symbols defined by several inputs, and reports the inputs
+/// built for a target other than BPF.
+struct InputChecks<'a> {
+    target: Option<&'a CStr>,
+    definitions: HashMap<Vec<u8>, PathBuf>,
+    deny_duplicate_symbols: bool,
+    strict_triple: bool,
+}
+
+impl InputChecks<'_> {
+    fn check(&mut self, name: &Path, module: &LLVMModule<'_>) -> Result<(), LinkerError> {
+        self.check_triple(name, module)?;
+        self.record_definitions(name, module)
+    }
+
+    /// Reports the module parsed from the input `name` when its target triple is set to something
+    /// other than `bpf*`, as a warning, or as an error when `strict_triple` is set. A `bpfel` or
+    /// `bpfeb` module is an error when the target has the other endianness.
+    fn check_triple(&self, name: &Path, module: &LLVMModule<'_>) -> Result<(), LinkerError> {
+        let triple = unsafe { CStr::from_ptr(module.get_target()) };
+        let triple = triple.to_bytes();
+        if triple.starts_with(b"bpf") {
+            /// Returns the architecture of `triple`, like `bpfel` in `bpfel-unknown-none`.
+            fn arch(triple: &[u8]) -> &[u8] {
+                triple.split(|&c| c == b'-').next().unwrap_or_default()
+            }
+            let target = self.target.map(CStr::to_bytes).unwrap_or_default();
+            if matches!(
+                (arch(triple), arch(target)),
+                (b"bpfel", b"bpfeb") | (b"bpfeb", b"bpfel")
+            ) {
+                return Err(LinkerError::EndiannessMismatch {
+                    path: name.to_owned(),
+                    triple: String::from_utf8_lossy(triple).into_owned(),
+                    target: String::from_utf8_lossy(target).into_owned(),
+                });
+            }
+            return Ok(());
+        }
+        if triple.is_empty() {
+            return Ok(());
+        }
+        let err = LinkerError::NonBpfInput {
+            path: name.to_owned(),
+            triple: String::from_utf8_lossy(triple).into_owned(),
+        };
+        if self.strict_triple {
+            return Err(err);
+        }
+        warn!("{err}");
+        Ok(())
+    }
+
+    /// Records the symbols defined by the module parsed from the input `name`. A symbol already
+    /// defined by a previous input is reported as a warning, or as an error when
+    /// `deny_duplicate_symbols` is set.
+    fn record_definitions(
+        &mut self,
+        name: &Path,
+        module: &LLVMModule<'_>,
+    ) -> Result<(), LinkerError> {
+        for symbol in llvm::external_definitions(module) {
+            let symbol = match self.definitions.entry(symbol) {
+                Entry::Occupied(entry) => entry,
+                Entry::Vacant(entry) => {
+                    let _: &mut PathBuf = entry.insert(name.to_owned());
+                    continue;
+                }
+            };
+            let err = LinkerError::DuplicateSymbol {
+                name: String::from_utf8_lossy(symbol.key()).into_owned(),
+                first: symbol.get().clone(),
+                second: name.to_owned(),
+            };
+            if self.deny_duplicate_symbols {
+                return Err(err);
+            }
+            warn!("{err}");
+        }
+        Ok(())
+    }
+}
+
+/// Collects the contents of the section set with [`Linker::set_dump_section`] from the input
+/// objects.
+struct SectionDump<'a> {
+    name: &'a CStr,
+    contents: Option<Vec<u8>>,
+}
+
+impl SectionDump<'_> {
+    fn extract(
+        &mut self,
+        context: &LLVMContext,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(), LinkerError> {
+        let Self { name, contents } = self;
+        let section = llvm::extract_section(context, data, name).map_err(|err| {
+            LinkerError::DumpSectionError(path.to_owned(), name.to_string_lossy().into_owned(), err)
+        })?;
+        if let Some(section) = section {
+            contents.get_or_insert_default().extend(section);
+        }
+        Ok(())
+    }
+}
+
+/// Writes every input module to a directory as LLVM IR before it is linked, for debugging.
+struct SaveTemps<'a> {
+    dir: &'a Path,
+    output_name: &'a OsStr,
+    index: usize,
+}
+
+impl<'a> SaveTemps<'a> {
+    fn new(dir: &'a Path, output_name: &'a OsStr) -> Result<Self, LinkerError> {
+        fs::create_dir_all(dir).map_err(|err| LinkerError::IoError(dir.to_owned(), err))?;
+        Ok(Self {
+            dir,
+            output_name,
+            index: 0,
+        })
+    }
+
+    /// Writes the module parsed from the input `name` to `<file stem>.<index>.ll`, where `index`
+    /// counts the input modules in the order they are linked.
+    fn save(&mut self, name: &Path, module: &LLVMModule<'_>) -> Result<(), LinkerError> {
+        let mut file_name = name.file_stem().unwrap_or(name.as_os_str()).to_os_string();
+        file_name.push(format!(".{}.ll", self.index));
+        self.index += 1;
+
+        let path = self.dir.join(file_name);
+        info!(
+            "saving input module {} to {}",
+            name.display(),
+            path.display()
+        );
+        write_ir(module, &path)
+    }
+
+    /// Writes the linked module at the end of `stage` to `<output file stem>.<stage>.ll` and
+    /// `<output file stem>.<stage>.bc`.
+    fn save_stage(&self, stage: &str, module: &LLVMModule<'_>) -> Result<(), LinkerError> {
+        let mut path = self.dir.join(self.output_name);
+        path.as_mut_os_string().push(format!(".{stage}.ll"));
+        info!("saving {stage} module to {}", path.display());
+        write_ir(module, &path)?;
+
+        let _: bool = path.set_extension("bc");
+        let c_path = CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+        module
+            .write_bitcode_to_path(&c_path)
+            .map_err(|err| LinkerError::IoError(path, err))
+    }
+}
+
+/// An entry of the cache set by [`Linker::set_incremental_path`].
+struct IncrementalCache {
+    /// The path of the entry, without extension.
+    stem: PathBuf,
+}
+
+impl IncrementalCache {
+    /// Returns the entry of `module` in `dir`, named after the SHA-256 digest of its key.
+    ///
+    /// The key is a sequence of fields, each made of its name and its value prefixed with their
+    /// lengths, see [`hash_field`]. It holds the versions of bpf-linker and LLVM, the optimization
+    /// level, the pass pipeline and the disabled passes, the triple, resolved CPU, features,
+    /// relocation and code models of `target_machine`, the options changing the module around
+    /// the pass pipeline, the export set and finally the bitcode of the linked `module`. Options
+    /// which only report on the link, like the diagnostics or the statistics, are left out.
+    fn new(
+        dir: &Path,
+        options: &LinkerOptions,
+        export_symbols: &ExportSymbols<'_>,
+        target_machine: &LLVMTargetMachine,
+        module: &LLVMModule<'_>,
+    ) -> Result<Self, LinkerError> {
+        let LinkerOptions {
+            reloc_model,
+            code_model,
+            optimize,
+            passes,
+            extra_passes,
+            inline_threshold,
+            unroll_loops,
+            unroll_max_count,
+            allow_undefined,
+            ignore_inline_never,
+            remove_attributes,
+            keep_optnone,
+            llvm_args,
+            disable_expand_memcpy_in_order,
+            verify_module,
+            export_all,
+            keep_symbols,
+            btf,
+            strip_debug,
+            strip_all,
+            strip_line_info,
+            strip_unused_globals,
+            strip_internal_debug_info,
+            allow_bpf_trap,
+            ..
+        } = options;
+
+        fs::create_dir_all(dir).map_err(|err| LinkerError::IoError(dir.to_owned(), err))?;
+        let disabled_passes = disabled_passes(options);
+        let pipeline = llvm::pass_pipeline(
+            *optimize,
+            passes.as_deref(),
+            &disabled_passes,
+            extra_passes.as_deref(),
+            *verify_module,
+        );
+        let opt_level = match optimize {
+            OptLevel::No => "0",
+            OptLevel::Less => "1",
+            OptLevel::Default => "2",
+            OptLevel::Aggressive => "3",
+            OptLevel::Size => "s",
+            OptLevel::SizeMin => "z",
+        };
+        let list = |items: &[String]| items.join("\n");
+        let flag = |flag: &bool| [u8::from(*flag)];
+
+        let mut hasher = Sha256::new();
+        let mut field = |name: &str, value: &[u8]| hash_field(&mut hasher, name, value);
+        field("bpf-linker", env!("CARGO_PKG_VERSION").as_bytes());
+        field("llvm", llvm::version().as_bytes());
+        field("opt-level", opt_level.as_bytes());
+        field("pipeline", pipeline.as_bytes());
+        field("disabled-passes", list(&disabled_passes).as_bytes());
+        field("triple", target_machine.triple().as_bytes());
+        field("cpu", target_machine.cpu().as_bytes());
+        field("features", target_machine.features().as_bytes());
+        field("reloc-model", reloc_model.to_string().as_bytes());
+        field("code-model", code_model.to_string().as_bytes());
+        field(
+            "inline-threshold",
+            &inline_threshold.map(i32::to_le_bytes).unwrap_or_default(),
+        );
+        field("unroll-loops", &flag(unroll_loops));
+        field(
+            "unroll-max-count",
+            &unroll_max_count.map(u32::to_le_bytes).unwrap_or_default(),
+        );
+        field("ignore-inline-never", &flag(ignore_inline_never));
+        field("remove-attributes", list(remove_attributes).as_bytes());
+        field("keep-optnone", &flag(keep_optnone));
+        field(
+            "llvm-args",
+            &llvm_args
+                .iter()
+                .flat_map(|arg| arg.as_bytes_with_nul())
+                .copied()
+                .collect::<Vec<_>>(),
+        );
+        field(
+            "disable-expand-memcpy-in-order",
+            &flag(disable_expand_memcpy_in_order),
+        );
+        field("allow-bpf-trap", &flag(allow_bpf_trap));
+        field("export-all", &flag(export_all));
+        field("keep-symbols", list(keep_symbols).as_bytes());
+        field("allow-undefined", list(allow_undefined).as_bytes());
+        field("btf", btf.to_string().as_bytes());
+        field("strip-debug", &flag(strip_debug));
+        field("strip-all", &flag(strip_all));
+        field("strip-line-info", &flag(strip_line_info));
+        field("strip-unused-globals", &flag(strip_unused_globals));
+        field(
+            "strip-internal-debug-info",
+            &flag(strip_internal_debug_info),
+        );
+        export_symbols.hash_into(&mut hasher);
+        hash_field(
+            &mut hasher,
+            "module",
+            module.write_bitcode_to_memory().as_slice(),
+        );
+
+        let digest = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        Ok(Self {
+            stem: dir.join(digest),
+        })
+    }
+
+    /// Returns the cached optimized module, if any.
+    fn load_module<'ctx>(
+        &self,
+        context: &'ctx LLVMContext,
+    ) -> Result<Option<LLVMModule<'ctx>>, LinkerError> {
+        let path = self.stem.with_extension("bc");
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(LinkerError::IoError(path, err)),
+        };
+        let module = context.parse_bitcode(&data, c"incremental_cache");
+        match &module {
+            Some(_) => info!("using the optimized module cached in {}", path.display()),
+            None => warn!("ignoring the invalid cached module {}", path.display()),
+        }
+        Ok(module)
+    }
+
+    fn store_module(&self, module: &LLVMModule<'_>) {
+        self.store("bc", module.write_bitcode_to_memory().as_slice())
+    }
+
+    /// Copies the cached object to `output`, or generates it and caches it.
+    fn codegen_object(
+        &self,
+        module: &LLVMModule<'_>,
+        target_machine: &LLVMTargetMachine,
+        output: &Path,
+    ) -> Result<(), LinkerError> {
+        let cached = self.stem.with_extension("o");
+        if cached.is_file() {
+            info!("copying the object cached in {}", cached.display());
+            let _: u64 = fs::copy(&cached, output)
+                .map_err(|err| LinkerError::IoError(output.to_owned(), err))?;
+            return Ok(());
+        }
+        codegen_to_file(module, target_machine, output, OutputType::Object)?;
+        match fs::read(output) {
+            Ok(object) => self.store("o", &object),
+            Err(err) => warn!("not caching {}: {err}", output.display()),
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to the entry file with `extension`. The data is written to a temporary file
+    /// first and renamed into place, so that concurrent links never see a partial entry.
+    ///
+    /// The output of the link is already produced by then, so failing to write the entry, like on
+    /// a full disk or a read-only cache directory, is only reported as a warning.
+    fn store(&self, extension: &str, data: &[u8]) {
+        let path = self.stem.with_extension(extension);
+        let tmp = self
+            .stem
+            .with_extension(format!("{extension}.{}.tmp", process::id()));
+        if let Err(err) = fs::write(&tmp, data).and_then(|()| fs::rename(&tmp, &path)) {
+            warn!("failed to write the cache entry {}: {err}", path.display());
+            if let Err(err) = fs::remove_file(&tmp)
+                && err.kind() != io::ErrorKind::NotFound
+            {
+                warn!("failed to remove {}: {err}", tmp.display());
             }
         }
     }
 }
 
+/// Feeds a field of the key of an [`IncrementalCache`] entry to `hasher`. The name and the value
+/// are each prefixed with their length as a little-endian `u64`, so that the fields can't run into
+/// each other.
+pub(crate) fn hash_field(hasher: &mut Sha256, name: &str, value: &[u8]) {
+    for bytes in [name.as_bytes(), value] {
+        hasher.update(u64::try_from(bytes.len()).unwrap().to_le_bytes());
+        hasher.update(bytes);
+    }
+}
+
+fn write_ir(module: &LLVMModule<'_>, path: &Path) -> Result<(), LinkerError> {
+    let path = CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+    module.write_ir_to_path(&path)
+}
+
 fn create_target_machine(
     options: &LinkerOptions,
     module: &LLVMModule<'_>,
@@ -620,6 +2285,9 @@ fn create_target_machine(
         target,
         cpu,
         cpu_features,
+        reloc_model,
+        code_model,
+        optimize,
         ..
     } = options;
     // Here's how the output target is selected:
@@ -637,7 +2305,19 @@ fn create_target_machine(
     //      endianness)
     let (triple, target) = match target {
         // case 1
-        Some(c_triple) => (c_triple.as_c_str(), llvm::target_from_triple(c_triple)),
+        Some(c_triple) => {
+            // The inputs built for a target other than BPF are already reported while linking.
+            let module_triple = unsafe { CStr::from_ptr(module.get_target()) };
+            if module_triple.to_bytes().starts_with(b"bpf") && module_triple != c_triple.as_c_str()
+            {
+                warn!(
+                    "overriding the target {} of the inputs with {}",
+                    module_triple.to_string_lossy(),
+                    c_triple.to_string_lossy()
+                );
+            }
+            (c_triple.as_c_str(), llvm::target_from_triple(c_triple))
+        }
         None => {
             let c_triple = module.get_target();
             let c_triple = unsafe { CStr::from_ptr(c_triple) };
@@ -657,99 +2337,511 @@ fn create_target_machine(
     };
     let target =
         target.map_err(|_msg| LinkerError::InvalidTarget(triple.to_string_lossy().to_string()))?;
+    let cpu = cpu.resolve(triple);
 
     debug!(
-        "creating target machine: triple: {} cpu: {} features: {}",
+        "creating target machine: triple: {} cpu: {} features: {} relocation model: {} code model: {} opt level: {:?}",
         triple.to_string_lossy(),
         cpu,
         cpu_features.to_string_lossy(),
+        reloc_model,
+        code_model,
+        optimize,
     );
 
-    let target_machine = LLVMTargetMachine::new(target, triple, cpu.as_c_str(), cpu_features)
-        .ok_or_else(|| LinkerError::InvalidTarget(triple.to_string_lossy().to_string()))?;
+    let target_machine = LLVMTargetMachine::new(
+        target,
+        triple,
+        cpu.as_c_str(),
+        cpu_features,
+        optimize.as_llvm_codegen(),
+        reloc_model.as_llvm(),
+        code_model.as_llvm(),
+    )
+    .ok_or_else(|| LinkerError::CreateTargetMachineError {
+        triple: triple.to_string_lossy().to_string(),
+        cpu,
+        features: cpu_features.to_string_lossy().to_string(),
+        reloc_model: *reloc_model,
+        code_model: *code_model,
+    })?;
 
     Ok(target_machine)
 }
 
-fn optimize<'ctx, 'a, E>(
+fn optimize<'ctx>(
     options: &LinkerOptions,
     context: &'ctx LLVMContext,
     target_machine: &LLVMTargetMachine,
     module: &mut LLVMModule<'ctx>,
-    export_symbols: E,
-) -> Result<(), LinkerError>
-where
-    E: IntoIterator<Item = &'a str>,
-{
+    export_symbols: &ExportSymbols<'_>,
+    save_temps: Option<&SaveTemps<'_>>,
+    timings: &Timings<'_>,
+) -> Result<Vec<InternalizedSymbol>, LinkerError> {
     let LinkerOptions {
-        disable_memory_builtins,
         optimize,
         btf,
+        strip_debug,
+        strip_line_info,
+        strip_internal_debug_info,
         ignore_inline_never,
+        allow_missing_exports,
+        export_all,
+        strict_exports,
+        keep_symbols,
+        allow_undefined,
+        disable_dce,
         ..
     } = options;
 
-    let mut export_symbols: HashSet<Cow<'_, [u8]>> = export_symbols
-        .into_iter()
-        .map(|s| Cow::Borrowed(s.as_bytes()))
-        .collect();
+    let undefined_exports = llvm::undefined_exports(module, export_symbols);
+    if !undefined_exports.is_empty() {
+        let undefined_exports = undefined_exports
+            .into_iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        if *allow_missing_exports {
+            for name in undefined_exports {
+                warn!("exported symbol {name} is not defined");
+            }
+        } else {
+            return Err(LinkerError::UndefinedExports(undefined_exports.collect()));
+        }
+    }
+
+    let keep_symbols = ExportSymbols::new(
+        keep_symbols
+            .iter()
+            .chain(allow_undefined)
+            .map(String::as_str),
+    )
+    .map_err(|(pattern, err)| {
+        LinkerError::InvalidExportPattern(pattern.to_owned(), err.to_string())
+    })?;
 
-    if !disable_memory_builtins {
-        export_symbols.extend(
-            ["memcpy", "memmove", "memset", "memcmp", "bcmp"]
-                .into_iter()
-                .map(|s| s.as_bytes().into()),
-        );
-    };
     debug!(
-        "linking exporting symbols {:?}, opt level {:?}",
-        export_symbols, optimize
+        "linking exporting symbols {:?}, keeping symbols {:?}, opt level {:?}",
+        export_symbols, keep_symbols, optimize
     );
-    // run optimizations. Will optionally remove noinline attributes, intern all non exported
-    // programs and maps and remove dead code.
+    // intern all non exported programs and maps.
+    let symbols = if *export_all {
+        Vec::new()
+    } else {
+        llvm::internalize_module(module, export_symbols, &keep_symbols)
+    };
+    let defined: Vec<_> = symbols
+        .iter()
+        .filter(|symbol| !symbol.undefined)
+        .map(|symbol| symbol.name.as_bytes())
+        .collect();
+    let unmatched = export_symbols.unmatched_patterns(&defined);
+    if !unmatched.is_empty() && !*export_all {
+        if *strict_exports {
+            return Err(LinkerError::UnmatchedExportPatterns(unmatched));
+        }
+        for pattern in unmatched {
+            warn!("export pattern {pattern} matches no symbol");
+        }
+    }
 
-    if *btf {
-        // if we want to emit BTF, we need to sanitize the debug information
-        llvm::DISanitizer::new(context, module).run(&export_symbols);
+    if !allow_undefined.is_empty() {
+        let kept = llvm::keep_declarations(module, &allowed_undefined(allow_undefined)?);
+        debug!("keeping the declarations {:?}", kept);
+    }
+
+    if *disable_dce {
+        let kept = llvm::keep_internal_functions(module);
+        debug!("Keeping the internal functions {:?}", kept);
+    }
+    let disabled_passes = disabled_passes(options);
+
+    let btf = if (*strip_debug || options.strip_all) && *btf != BtfMode::None {
+        warn!("stripping the debug info, no BTF is emitted");
+        BtfMode::None
     } else {
-        // if we don't need BTF emission, we can strip DI
-        let ok = module.strip_debug_info();
-        debug!("Stripping DI, changed={}", ok);
+        *btf
+    };
+
+    if btf != BtfMode::None && *strip_internal_debug_info {
+        let stripped = module.strip_internal_debug_info();
+        debug!("Detached the debug info of {stripped} internal functions");
     }
 
-    llvm::optimize(
-        target_machine,
-        module,
-        options.optimize,
-        *ignore_inline_never,
-        &export_symbols,
-    )
-    .map_err(LinkerError::OptimizeError)?;
+    // run optimizations. Will optionally remove noinline attributes and remove dead code.
+    timings.time(Stage::SanitizeDebugInfo, || -> Result<(), LinkerError> {
+        match btf {
+            BtfMode::Full | BtfMode::FuncOnly => {
+                // if we want to emit BTF, we need to sanitize the debug information
+                llvm::DISanitizer::new(context, module).run(export_symbols);
+                if btf == BtfMode::FuncOnly {
+                    // keep the subprograms so that the func info and line info are emitted, but
+                    // drop the types
+                    llvm::strip_non_line_table_debug_info(target_machine, module)?;
+                }
+            }
+            BtfMode::None => {
+                // if we don't need BTF emission, we can strip DI
+                let subprograms = module.subprogram_count();
+                let ok = module.strip_debug_info();
+                debug!(
+                    "Stripping DI, changed={}, removed the debug info of {} functions",
+                    ok, subprograms
+                );
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(save_temps) = save_temps {
+        save_temps.save_stage("post-disanitize", module)?;
+    }
+
+    timings.time(Stage::Optimize, || {
+        llvm::optimize(
+            target_machine,
+            module,
+            options.optimize,
+            options.passes.as_deref(),
+            options.extra_passes.as_deref(),
+            &disabled_passes,
+            options.inline_threshold,
+            *ignore_inline_never,
+            &options.remove_attributes,
+            options.keep_optnone,
+            options.verify_module,
+        )
+    })?;
+
+    if options.unroll_loops {
+        for function in llvm::functions_with_loops(module) {
+            match options.unroll_max_count {
+                Some(count) => warn!(
+                    "function {function} still contains a loop after unrolling at most {count} times, kernels that don't support loops will reject it"
+                ),
+                None => warn!(
+                    "function {function} still contains a loop after unrolling, kernels that don't support loops will reject it"
+                ),
+            }
+        }
+    }
+
+    if btf != BtfMode::None && *strip_line_info {
+        let ok = module.strip_line_info();
+        debug!("Stripping line info, changed={}", ok);
+    }
+
+    if options.strip_unused_globals {
+        let stripped = llvm::strip_unused_globals(module, export_symbols, &keep_symbols);
+        debug!("Stripped unused globals {:?}", stripped);
+    }
+
+    if options.strip_all {
+        let hidden = llvm::make_internal_functions_private(module);
+        debug!(
+            "Left the internal functions {:?} out of the symbol table",
+            hidden
+        );
+    }
+
+    Ok(symbols)
+}
+
+/// Returns [`LinkerOptions::disabled_passes`], along with `dce` when
+/// [`LinkerOptions::disable_dce`] is set.
+fn disabled_passes(options: &LinkerOptions) -> Cow<'_, [String]> {
+    let mut disabled_passes = Cow::Borrowed(options.disabled_passes.as_slice());
+    if options.disable_dce && !disabled_passes.iter().any(|pass| pass == "dce") {
+        disabled_passes.to_mut().push("dce".to_owned());
+    }
+    disabled_passes
+}
+
+/// A stage of the link, as reported by [`Linker::set_time_link`] and [`JsonDiagnostic`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Parsing the bitcode or IR of an input.
+    Parse,
+    /// Extracting the bitcode embedded in an object file.
+    ExtractBitcode,
+    /// Linking a parsed input into the linked module.
+    Link,
+    /// Sanitizing the debug info of the linked module for BTF.
+    SanitizeDebugInfo,
+    /// Running the optimization pipeline.
+    Optimize,
+    /// Generating the outputs.
+    Codegen,
+}
+
+impl Stage {
+    const ALL: [Self; 6] = [
+        Self::Parse,
+        Self::ExtractBitcode,
+        Self::Link,
+        Self::SanitizeDebugInfo,
+        Self::Optimize,
+        Self::Codegen,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Parse => "parse",
+            Self::ExtractBitcode => "extract-bitcode",
+            Self::Link => "link",
+            Self::SanitizeDebugInfo => "sanitize-debug-info",
+            Self::Optimize => "optimize",
+            Self::Codegen => "codegen",
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// The wall clock time spent in each stage of a link.
+struct Timings<'a> {
+    durations: RefCell<[Duration; Stage::ALL.len()]>,
+    /// The time spent in the stages run by the stage being timed.
+    nested: Cell<Duration>,
+    /// Told about each stage entered, for [`Linker::last_stage`] and the diagnostics.
+    diagnostic_handler: &'a llvm::InstalledDiagnosticHandler<DiagnosticHandler>,
+}
+
+impl<'a> Timings<'a> {
+    fn new(diagnostic_handler: &'a llvm::InstalledDiagnosticHandler<DiagnosticHandler>) -> Self {
+        diagnostic_handler.with_view(|h| h.stage.set(None));
+        Self {
+            durations: Default::default(),
+            nested: Default::default(),
+            diagnostic_handler,
+        }
+    }
+
+    /// Runs `f` in a `stage` span and adds the time it took to `stage`, minus the time spent in
+    /// the stages it runs.
+    fn time<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let _span = debug_span!("stage", name = stage.name()).entered();
+        self.diagnostic_handler
+            .with_view(|h| h.stage.set(Some(stage)));
+        let outer = self.nested.replace(Duration::ZERO);
+        let start = Instant::now();
+        let t = f();
+        let elapsed = start.elapsed();
+        let nested = self.nested.replace(outer + elapsed);
+        self.durations.borrow_mut()[stage as usize] += elapsed.saturating_sub(nested);
+        t
+    }
+
+    /// Formats the time spent in each stage and the `total` time of the link as a table.
+    fn format(&self, total: Duration) -> String {
+        let mut table = format!("{:<20} {:>10}\n", "STAGE", "TIME (ms)");
+        let durations = self.durations.borrow();
+        let rows = Stage::ALL
+            .iter()
+            .map(|stage| (stage.name(), durations[*stage as usize]))
+            .chain([("total", total)]);
+        for (name, duration) in rows {
+            table.push_str(&format!(
+                "{name:<20} {:>10.3}\n",
+                duration.as_secs_f64() * 1000.0
+            ));
+        }
+        table
+    }
+}
+
+fn verify_module(module: &LLVMModule<'_>, stage: &'static str) -> Result<(), LinkerError> {
+    module
+        .verify()
+        .map_err(|message| LinkerError::InvalidModule { stage, message })
+}
+
+/// Writes the linkage decisions taken while internalizing as a JSON array to `path`.
+fn write_symbol_manifest(path: &Path, symbols: &[InternalizedSymbol]) -> Result<(), LinkerError> {
+    let mut manifest = String::from("[");
+    for (i, symbol) in symbols.iter().enumerate() {
+        let InternalizedSymbol {
+            name,
+            kind,
+            exported,
+            internalized,
+            undefined,
+        } = symbol;
+        if i != 0 {
+            manifest.push(',');
+        }
+        manifest.push_str(&format!(
+            "\n  {{\"name\": {}, \"kind\": \"{kind}\", \"exported\": {exported}, \
+             \"internalized\": {internalized}, \"undefined\": {undefined}}}",
+            json_string(name)
+        ));
+    }
+    manifest.push_str("\n]\n");
+    fs::write(path, manifest).map_err(|err| LinkerError::IoError(path.to_owned(), err))
+}
+
+/// Returns the set of [`LinkerOptions::allow_undefined`].
+fn allowed_undefined(allow_undefined: &[String]) -> Result<ExportSymbols<'_>, LinkerError> {
+    ExportSymbols::new(allow_undefined.iter().map(String::as_str)).map_err(|(pattern, err)| {
+        LinkerError::InvalidExportPattern(pattern.to_owned(), err.to_string())
+    })
+}
+
+/// Fails when the module calls undefined functions which are neither BPF helpers, memory builtins
+/// nor allowed. The BPF backend otherwise emits calls which the kernel rejects with relocation
+/// errors.
+fn check_undefined_calls(
+    module: &LLVMModule<'_>,
+    allow_undefined: &[String],
+) -> Result<(), LinkerError> {
+    const MEMORY_BUILTINS: &[&str] = &["memcpy", "memmove", "memset", "memcmp", "bcmp"];
 
+    let allowed = allowed_undefined(allow_undefined)?;
+    let calls: Vec<_> = llvm::undefined_calls(module)
+        .into_iter()
+        .filter(|(_, callee, ksym)| {
+            // The kfuncs and ksyms, unlike the helpers, must be allowed explicitly.
+            (*ksym || !callee.starts_with("bpf_"))
+                && !MEMORY_BUILTINS.contains(&callee.as_str())
+                && !allowed.contains(callee.as_bytes())
+        })
+        .map(|(caller, callee, _)| (caller, callee))
+        .collect();
+    if calls.is_empty() {
+        Ok(())
+    } else {
+        Err(LinkerError::UndefinedCalls(calls))
+    }
+}
+
+fn check_stack_sizes(module: &LLVMModule<'_>, limit: u64, error: bool) -> Result<(), LinkerError> {
+    let functions: Vec<_> = llvm::function_stack_sizes(module)
+        .into_iter()
+        .filter(|(_, size)| *size > limit)
+        .collect();
+    if error && !functions.is_empty() {
+        return Err(LinkerError::StackSizeLimitExceeded { limit, functions });
+    }
+    for (name, size) in functions {
+        warn!(
+            "function {name} uses an estimated {size} bytes of stack, more than the limit of {limit} bytes"
+        );
+    }
     Ok(())
 }
 
+fn format_insn_counts(counts: &[(String, usize)]) -> String {
+    let mut table = format!("{:<10} NAME\n", "INSNS");
+    for (name, count) in counts {
+        table.push_str(&format!("{count:<10} {name}\n"));
+    }
+    table
+}
+
+fn format_module_stats(pre_opt: &ModuleStats, post_opt: &ModuleStats) -> String {
+    let mut table = format!("{:<20} {:>10} {:>10}\n", "STAT", "PRE-OPT", "POST-OPT");
+    let rows = [
+        ("functions", pre_opt.functions, post_opt.functions),
+        (
+            "defined functions",
+            pre_opt.defined_functions,
+            post_opt.defined_functions,
+        ),
+        ("globals", pre_opt.globals, post_opt.globals),
+        ("instructions", pre_opt.instructions, post_opt.instructions),
+    ];
+    for (name, pre_opt, post_opt) in rows {
+        table.push_str(&format!("{name:<20} {pre_opt:>10} {post_opt:>10}\n"));
+    }
+    table
+}
+
+/// Formats the symbol table printed by [`Linker::set_print_symbols`].
+fn format_symbol_table(symbols: &[ModuleSymbol]) -> String {
+    let mut table = String::new();
+    for ModuleSymbol {
+        name,
+        linkage,
+        visibility,
+        ..
+    } in symbols
+    {
+        table.push_str(&format!("{name} {linkage} {visibility}\n"));
+    }
+    table
+}
+
+/// Formats the symbol table printed by [`Linker::set_print_symbols`] as a JSON array.
+fn format_symbol_table_json(symbols: &[ModuleSymbol]) -> String {
+    let mut table = String::from("[");
+    for (i, symbol) in symbols.iter().enumerate() {
+        let ModuleSymbol {
+            name,
+            kind,
+            linkage,
+            visibility,
+            declaration,
+            exported,
+        } = symbol;
+        if i != 0 {
+            table.push(',');
+        }
+        table.push_str(&format!(
+            "\n  {{\"name\": {}, \"kind\": \"{kind}\", \"linkage\": \"{linkage}\", \
+             \"visibility\": \"{visibility}\", \"defined\": {}, \"exported\": {exported}}}",
+            json_string(name),
+            !declaration,
+        ));
+    }
+    table.push_str("\n]\n");
+    table
+}
+
+/// Quotes and escapes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 fn codegen_to_file(
     module: &LLVMModule<'_>,
     target_machine: &LLVMTargetMachine,
     output: &Path,
     output_type: OutputType,
 ) -> Result<(), LinkerError> {
+    if output == Path::new("-") {
+        info!("writing {:?} to stdout", output_type);
+        let buffer = codegen_to_buffer(module, target_machine, output_type)?;
+        return io::stdout()
+            .lock()
+            .write_all(&buffer)
+            .map_err(|err| LinkerError::IoError(output.to_owned(), err));
+    }
+
     info!("writing {:?} to {:?}", output_type, output);
     let output = CString::new(output.as_os_str().as_encoded_bytes()).unwrap();
     match output_type {
         OutputType::Bitcode => module
             .write_bitcode_to_path(&output)
             .map_err(LinkerError::WriteBitcodeError),
-        OutputType::LlvmAssembly => module
-            .write_ir_to_path(&output)
-            .map_err(LinkerError::WriteIRError),
-        OutputType::Assembly => target_machine
-            .emit_to_file(module, &output, LLVMCodeGenFileType::LLVMAssemblyFile)
-            .map_err(LinkerError::EmitCodeError),
-        OutputType::Object => target_machine
-            .emit_to_file(module, &output, LLVMCodeGenFileType::LLVMObjectFile)
-            .map_err(LinkerError::EmitCodeError),
+        OutputType::LlvmAssembly => module.write_ir_to_path(&output),
+        OutputType::Assembly => {
+            target_machine.emit_to_file(module, &output, LLVMCodeGenFileType::LLVMAssemblyFile)
+        }
+        OutputType::Object => {
+            target_machine.emit_to_file(module, &output, LLVMCodeGenFileType::LLVMObjectFile)
+        }
     }
 }
 
@@ -761,12 +2853,12 @@ fn codegen_to_buffer(
     let memory_buffer = match output_type {
         OutputType::Bitcode => module.write_bitcode_to_memory(),
         OutputType::LlvmAssembly => module.write_ir_to_memory(),
-        OutputType::Assembly => target_machine
-            .emit_to_memory_buffer(module, LLVMCodeGenFileType::LLVMAssemblyFile)
-            .map_err(LinkerError::EmitCodeError)?,
-        OutputType::Object => target_machine
-            .emit_to_memory_buffer(module, LLVMCodeGenFileType::LLVMObjectFile)
-            .map_err(LinkerError::EmitCodeError)?,
+        OutputType::Assembly => {
+            target_machine.emit_to_memory_buffer(module, LLVMCodeGenFileType::LLVMAssemblyFile)?
+        }
+        OutputType::Object => {
+            target_machine.emit_to_memory_buffer(module, LLVMCodeGenFileType::LLVMObjectFile)?
+        }
     };
 
     Ok(LinkerOutput {
@@ -774,12 +2866,65 @@ fn codegen_to_buffer(
     })
 }
 
+/// The LLVM options accepted in [`LinkerOptions::llvm_args`]. The C API of LLVM can't tell
+/// whether an option exists, and LLVM exits the process when it parses an option it doesn't know,
+/// so the options are checked against this list beforehand. The options only available in LLVM
+/// builds with assertions, like `-debug`, are left out.
+const LLVM_OPTIONS: &[&str] = &[
+    "bpf-disable-avoid-speculation",
+    "bpf-disable-serialize-icmp",
+    "bpf-disable-trap-unreachable",
+    "bpf-expand-memcpy-in-order",
+    "bpf-stack-size",
+    "cold-callsite-rel-freq",
+    "filter-print-funcs",
+    "inline-threshold",
+    "pass-remarks",
+    "pass-remarks-analysis",
+    "pass-remarks-missed",
+    "print-after",
+    "print-after-all",
+    "print-before",
+    "print-before-all",
+    "print-changed",
+    "stats",
+    "time-passes",
+    "unroll-count",
+    "unroll-full-max-count",
+    "unroll-max-count",
+    "unroll-max-upperbound",
+    "unroll-runtime",
+    "unroll-runtime-multi-exit",
+    "unroll-threshold",
+];
+
+/// Checks that `arg` is one of the [`LLVM_OPTIONS`], optionally followed by `=value`.
+fn check_llvm_arg(arg: &CStr) -> Result<(), LinkerError> {
+    let arg = arg.to_string_lossy();
+    if arg.len() < 2 || !arg.starts_with('-') {
+        return Err(LinkerError::InvalidLlvmArg(arg.into_owned()));
+    }
+    let name = arg.trim_start_matches('-');
+    let name = name.split_once('=').map_or(name, |(name, _)| name);
+    if !LLVM_OPTIONS.contains(&name) {
+        return Err(LinkerError::UnknownLlvmArg(arg.into_owned()));
+    }
+    Ok(())
+}
+
 fn llvm_init(
     options: &LinkerOptions,
-) -> (
-    LLVMContext,
-    llvm::InstalledDiagnosticHandler<DiagnosticHandler>,
-) {
+) -> Result<
+    (
+        LLVMContext,
+        llvm::InstalledDiagnosticHandler<DiagnosticHandler>,
+    ),
+    LinkerError,
+> {
+    for arg in &options.llvm_args {
+        check_llvm_arg(arg)?;
+    }
+
     let mut args = Vec::<Cow<'_, CStr>>::new();
     args.push(c"bpf-linker".into());
     // Disable cold call site detection. Many accessors in aya-ebpf return Result<T, E>
@@ -802,6 +2947,22 @@ fn llvm_init(
                 .unwrap()
                 .into(),
         ]);
+        if let Some(count) = options.unroll_max_count {
+            args.extend([
+                CString::new(format!("--unroll-max-count={count}"))
+                    .unwrap()
+                    .into(),
+                CString::new(format!("--unroll-full-max-count={count}"))
+                    .unwrap()
+                    .into(),
+            ]);
+        }
+    }
+    if options.time_passes {
+        args.push(c"--time-passes".into());
+    }
+    if options.stats {
+        args.push(c"--stats".into());
     }
     if !options.disable_expand_memcpy_in_order {
         args.push(c"--bpf-expand-memcpy-in-order".into());
@@ -816,6 +2977,7 @@ fn llvm_init(
         args.push(c"--bpf-disable-trap-unreachable".into());
     }
     args.extend(options.llvm_args.iter().map(Into::into));
+    let args = dedup_llvm_args(args);
     info!("LLVM command line: {:?}", args);
     llvm::init(args.as_slice(), c"BPF linker");
 
@@ -828,21 +2990,126 @@ fn llvm_init(
         LLVMEnablePrettyStackTrace();
     }
 
-    (context, diagnostic_handler)
+    Ok((context, diagnostic_handler))
+}
+
+/// Keeps the last occurrence of every option in `args`, LLVM rejecting most options given more
+/// than once. The first argument is the program name.
+fn dedup_llvm_args(args: Vec<Cow<'_, CStr>>) -> Vec<Cow<'_, CStr>> {
+    let option_name = |arg: &CStr| {
+        let arg = arg.to_bytes();
+        let arg = &arg[arg.iter().take_while(|&&c| c == b'-').count()..];
+        arg.split(|&c| c == b'=').next().unwrap_or(arg).to_vec()
+    };
+    let mut seen = HashSet::new();
+    let mut args = args.into_iter();
+    let program = args.next();
+    let mut options: Vec<_> = args
+        .rev()
+        .filter(|arg| seen.insert(option_name(arg)))
+        .collect();
+    options.reverse();
+    program.into_iter().chain(options).collect()
+}
+
+/// The number of diagnostics of each severity reported by LLVM, as returned by
+/// [`Linker::diagnostic_counts`]. The errors LLVM reports for the calls to the built-in functions
+/// the BPF backend doesn't support are ignored and not counted.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DiagnosticCounts {
+    pub errors: usize,
+    pub warnings: usize,
+    pub remarks: usize,
+    pub notes: usize,
+}
+
+impl std::fmt::Display for DiagnosticCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            errors,
+            warnings,
+            remarks,
+            notes,
+        } = self;
+        write!(
+            f,
+            "errors: {errors}, warnings: {warnings}, remarks: {remarks}, notes: {notes}"
+        )
+    }
 }
 
 #[derive(Default)]
 pub(crate) struct DiagnosticHandler {
-    pub(crate) has_errors: bool,
+    pub(crate) counts: DiagnosticCounts,
+    /// The messages of the errors reported so far.
+    pub(crate) errors: Vec<String>,
+    /// The messages of the warnings reported so far.
+    pub(crate) warnings: Vec<String>,
+    /// Set through a shared reference by [`Linker::set_message_format`].
+    pub(crate) message_format: Cell<MessageFormat>,
+    /// Set through a shared reference by [`Linker::set_remarks_filter`].
+    pub(crate) remarks_filter: RefCell<Option<Regex>>,
+    /// Whether the severity of the diagnostics is colored, set by [`Linker::set_color`].
+    pub(crate) color: Cell<bool>,
+    /// The input being parsed or linked, mentioned by the diagnostics LLVM reports meanwhile.
+    pub(crate) input: RefCell<Option<PathBuf>>,
+    /// The last stage the link entered.
+    pub(crate) stage: Cell<Option<Stage>>,
     // The handler is passed to LLVM as a raw pointer so it must not be moved.
     _marker: std::marker::PhantomPinned,
 }
 
+impl DiagnosticHandler {
+    /// Returns the messages of the errors reported after the first `start` ones.
+    fn errors_since(&self, start: usize) -> String {
+        match &self.errors[start..] {
+            [] => "LLVM did not report any error".to_owned(),
+            errors => errors.join("; "),
+        }
+    }
+
+    /// Formats a diagnostic for the [`MessageFormat::Human`] output: the severity, colored if
+    /// enabled, the message and then the source location and the input the diagnostic is about,
+    /// when they are known.
+    fn format_human(
+        &self,
+        severity: &str,
+        message: &str,
+        location: Option<&llvm::DiagnosticLocation>,
+    ) -> String {
+        let (style, reset) = if self.color.get() {
+            let style = match severity {
+                "error" => "\x1b[1;31m",
+                "warning" => "\x1b[1;33m",
+                "remark" => "\x1b[1;32m",
+                _ => "\x1b[1;36m",
+            };
+            (style, "\x1b[0m")
+        } else {
+            ("", "")
+        };
+        let mut message = message.trim_end();
+        if let Some(location) = location {
+            let prefix = format!("{location}: ");
+            message = message.strip_prefix(&prefix).unwrap_or(message);
+        }
+        let mut formatted = format!("{style}llvm {severity}{reset}: {message}");
+        if let Some(location) = location {
+            formatted.push_str(&format!("\n  --> {location}"));
+        }
+        if let Some(input) = &*self.input.borrow() {
+            formatted.push_str(&format!("\n  in input {}", input.display()));
+        }
+        formatted
+    }
+}
+
 impl llvm::LLVMDiagnosticHandler for DiagnosticHandler {
     fn handle_diagnostic(
         &mut self,
         severity: llvm_sys::LLVMDiagnosticSeverity,
         message: Cow<'_, str>,
+        location: Option<llvm::DiagnosticLocation>,
     ) {
         // TODO(https://reviews.llvm.org/D155894): Remove this when LLVM no longer emits these
         // errors.
@@ -857,55 +3124,91 @@ impl llvm::LLVMDiagnosticHandler for DiagnosticHandler {
             "A call to built-in function 'strlen' is not supported.\n",
         ];
 
-        match severity {
+        let json = self.message_format.get() == MessageFormat::Json;
+        let severity = match severity {
             llvm_sys::LLVMDiagnosticSeverity::LLVMDSError => {
                 if MATCHERS.iter().any(|matcher| message.ends_with(matcher)) {
                     return;
                 }
-                self.has_errors = true;
+                self.counts.errors += 1;
 
-                error!("llvm: {}", message)
+                if !json {
+                    error!(
+                        "{}",
+                        self.format_human("error", &message, location.as_ref())
+                    );
+                }
+                self.errors.push(message.trim_end().to_owned());
+                "error"
+            }
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning => {
+                self.counts.warnings += 1;
+                if !json {
+                    warn!(
+                        "{}",
+                        self.format_human("warning", &message, location.as_ref())
+                    );
+                }
+                self.warnings.push(message.trim_end().to_owned());
+                "warning"
+            }
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSRemark => {
+                if let Some(filter) = &*self.remarks_filter.borrow()
+                    && !filter.is_match(&message)
+                {
+                    return;
+                }
+                self.counts.remarks += 1;
+                if !json {
+                    info!(
+                        "{}",
+                        self.format_human("remark", &message, location.as_ref())
+                    )
+                }
+                "remark"
             }
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning => warn!("llvm: {}", message),
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSRemark => debug!("remark: {}", message),
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSNote => debug!("note: {}", message),
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSNote => {
+                self.counts.notes += 1;
+                if !json {
+                    debug!("{}", self.format_human("note", &message, location.as_ref()))
+                }
+                "note"
+            }
+        };
+        if json {
+            eprintln!(
+                "{}",
+                JsonDiagnostic {
+                    level: severity,
+                    message: &message,
+                    file: self.input.borrow().as_deref(),
+                    stage: self.stage.get(),
+                }
+            );
         }
     }
 }
 
 impl LinkerInputKind {
-    fn detect(data: &[u8]) -> Option<Self> {
+    /// Detects the kind of an input from its leading bytes, whatever its file name. Bitcode starts
+    /// with `BC\xC0\xDE`, or with the little endian magic `0x0B17C0DE` of the wrapper header which
+    /// LLVM skips when parsing. Anything without a known magic number is taken as LLVM IR, and the
+    /// IR parser reports why it isn't.
+    fn detect(data: &[u8]) -> Self {
         match data.get(..4) {
-            Some(b"\x42\x43\xC0\xDE" | b"\xDE\xC0\x17\x0b") => Some(Self::Bitcode),
-            Some(b"\x7FELF") => Some(Self::Elf),
-            Some(b"\xcf\xfa\xed\xfe") => Some(Self::MachO),
-            _ => {
-                const PREFIXES: &[&[u8]] = &[
-                    b"; ModuleID",
-                    b"source_filename",
-                    b"target datalayout",
-                    b"target triple",
-                    b"define ",
-                    b"declare ",
-                    b"!llvm",
-                ];
-
-                let trimmed = data.trim_ascii_start();
-
-                PREFIXES
-                    .iter()
-                    .any(|p| trimmed.starts_with(p))
-                    .then_some(Self::Ir)
-            }
+            Some(b"\x42\x43\xC0\xDE" | b"\xDE\xC0\x17\x0b") => Self::Bitcode,
+            Some(b"\x7FELF") => Self::Elf,
+            Some(b"\xcf\xfa\xed\xfe") => Self::MachO,
+            _ => Self::Ir,
         }
     }
 }
 
 impl InputKind {
-    fn detect(data: &[u8]) -> Option<Self> {
+    fn detect(data: &[u8]) -> Self {
         match data.get(..8) {
-            Some(b"!<arch>\x0A") => Some(Self::Archive),
-            _ => LinkerInputKind::detect(data).map(Self::Linker),
+            Some(b"!<arch>\x0A") => Self::Archive,
+            _ => Self::Linker(LinkerInputKind::detect(data)),
         }
     }
 }
@@ -934,3 +3237,121 @@ impl Deref for LinkerOutput {
         self.as_slice()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_opt_level_as_llvm_codegen() {
+        for (opt_level, codegen_level) in [
+            (OptLevel::No, LLVMCodeGenOptLevel::LLVMCodeGenLevelNone),
+            (OptLevel::Less, LLVMCodeGenOptLevel::LLVMCodeGenLevelLess),
+            (
+                OptLevel::Default,
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            ),
+            (
+                OptLevel::Aggressive,
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+            ),
+            (OptLevel::Size, LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault),
+            (
+                OptLevel::SizeMin,
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            ),
+        ] {
+            assert_eq!(opt_level.as_llvm_codegen(), codegen_level, "{opt_level:?}");
+        }
+    }
+
+    #[test]
+    fn test_cpu_for_kernel_release() {
+        for (release, cpu) in [
+            ("4.9.337", "v1"),
+            ("4.14.0", "v2"),
+            ("5.0.21", "v2"),
+            ("5.1.0", "v3"),
+            ("5.15.0-122-generic", "v3"),
+            ("6.5.13", "v3"),
+            ("6.6.0", "v4"),
+            ("6.18.44-fc-v139", "v4"),
+            ("7.0", "v4"),
+        ] {
+            assert_eq!(
+                Cpu::for_kernel_release(release).map(|cpu| cpu.to_string()),
+                Some(cpu.to_owned()),
+                "{release}"
+            );
+        }
+        assert!(Cpu::for_kernel_release("unknown").is_none());
+    }
+
+    #[test]
+    fn test_dedup_llvm_args() {
+        let args = [
+            c"bpf-linker",
+            c"--cold-callsite-rel-freq=0",
+            c"--bpf-expand-memcpy-in-order",
+            c"-debug",
+            c"--cold-callsite-rel-freq=5",
+            c"-bpf-expand-memcpy-in-order",
+        ];
+        assert_eq!(
+            dedup_llvm_args(args.into_iter().map(Into::into).collect()),
+            [
+                c"bpf-linker",
+                c"-debug",
+                c"--cold-callsite-rel-freq=5",
+                c"-bpf-expand-memcpy-in-order",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_llvm_arg() {
+        for arg in [
+            c"--unroll-threshold=100",
+            c"-bpf-stack-size=1024",
+            c"-time-passes",
+        ] {
+            assert!(check_llvm_arg(arg).is_ok(), "{arg:?}");
+        }
+        for arg in [c"", c"-", c"unroll-threshold=100"] {
+            assert!(
+                matches!(check_llvm_arg(arg), Err(LinkerError::InvalidLlvmArg(_))),
+                "{arg:?}"
+            );
+        }
+        for arg in [
+            c"--not-a-real-flag",
+            c"-unroll-thresold=100",
+            c"--=1",
+            c"-debug",
+            c"-debug-only=isel",
+        ] {
+            assert!(
+                matches!(check_llvm_arg(arg), Err(LinkerError::UnknownLlvmArg(a)) if a.as_bytes() == arg.to_bytes()),
+                "{arg:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_hash_field() {
+        let digest = |fields: &[(&str, &[u8])]| {
+            let mut hasher = Sha256::new();
+            for (name, value) in fields {
+                hash_field(&mut hasher, name, value);
+            }
+            hasher.finalize()
+        };
+        assert_eq!(digest(&[("cpu", b"v3")]), digest(&[("cpu", b"v3")]));
+        assert_ne!(digest(&[("cpu", b"v3")]), digest(&[("cpu", b"v2")]));
+        assert_ne!(digest(&[("cpu", b"v3")]), digest(&[("cpuv", b"3")]));
+        assert_ne!(
+            digest(&[("a", b"b"), ("c", b"")]),
+            digest(&[("a", b""), ("bc", b"")])
+        );
+    }
+}
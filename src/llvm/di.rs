@@ -1,5 +1,4 @@
 use std::{
-    borrow::Cow,
     collections::{HashMap, HashSet, hash_map::DefaultHasher},
     hash::Hasher as _,
     io::Write as _,
@@ -9,13 +8,16 @@ use std::{
 
 use gimli::{DW_TAG_pointer_type, DW_TAG_structure_type, DW_TAG_variant_part};
 use llvm_sys::{core::*, debuginfo::*, prelude::*};
-use tracing::{Level, span, trace, warn};
+use tracing::{Level, debug, span, trace, warn};
 
 use super::types::{
-    di::DIType,
+    di::{DICompositeType, DIDerivedType, DIType},
     ir::{Function, MDNode, Metadata, Value},
 };
-use crate::llvm::{LLVMContext, LLVMModule, iter::*, types::di::DISubprogram};
+use crate::{
+    export::ExportSymbols,
+    llvm::{LLVMContext, LLVMModule, iter::*, types::di::DISubprogram},
+};
 
 // KSYM_NAME_LEN from linux kernel intentionally set
 // to lower value found across kernel versions to ensure
@@ -28,7 +30,8 @@ pub(crate) struct DISanitizer<'ctx> {
     builder: LLVMDIBuilderRef,
     visited_nodes: HashSet<u64>,
     replace_operands: HashMap<u64, LLVMMetadataRef>,
-    skipped_types_lossy: Vec<String>,
+    // The fixups planned while auditing the module, `None` when applying them.
+    fixups: Option<Vec<DiFixup<'ctx>>>,
     // TODO: use references of safe wrappers instead of PhantomData
     _marker: PhantomData<LLVMModule<'ctx>>,
 }
@@ -66,14 +69,21 @@ impl<'ctx> DISanitizer<'ctx> {
             builder: unsafe { LLVMCreateDIBuilder(module.as_mut_ptr()) },
             visited_nodes: HashSet::new(),
             replace_operands: HashMap::new(),
-            skipped_types_lossy: Vec::new(),
+            fixups: None,
             _marker: PhantomData,
         }
     }
 
-    fn visit_mdnode(&mut self, mdnode: MDNode<'_>) {
+    fn fixup(&mut self, fixup: DiFixup<'ctx>) {
+        if let Some(fixups) = &mut self.fixups {
+            trace!(?fixup, "planning fixup");
+            fixups.push(fixup);
+        }
+    }
+
+    fn visit_mdnode(&mut self, node: LLVMValueRef, mdnode: MDNode<'_>) {
         match mdnode.try_into().expect("MDNode is not Metadata") {
-            Metadata::DICompositeType(mut di_composite_type) => {
+            Metadata::DICompositeType(di_composite_type) => {
                 #[expect(clippy::single_match)]
                 #[expect(non_upper_case_globals)]
                 match di_composite_type.tag() {
@@ -89,6 +99,7 @@ impl<'ctx> DISanitizer<'ctx> {
                             return;
                         }
 
+                        let mut skipped_name = None;
                         let mut is_data_carrying_enum = false;
                         let mut remove_name = false;
                         let mut members: Vec<DIType<'_>> = Vec::new();
@@ -114,7 +125,7 @@ impl<'ctx> DISanitizer<'ctx> {
                                                     ),
                                                     line = di_composite_type.line(),
                                                 );
-                                                self.skipped_types_lossy.push(name);
+                                                skipped_name = Some(name);
                                             }
 
                                             is_data_carrying_enum = true;
@@ -154,42 +165,60 @@ impl<'ctx> DISanitizer<'ctx> {
                             }
                         }
                         if is_data_carrying_enum {
-                            di_composite_type.replace_elements(MDNode::empty(self.context));
+                            self.fixup(DiFixup::DropEnumMembers {
+                                node: ModuleValue::new(node),
+                                name: skipped_name,
+                            });
                         } else if !members.is_empty() {
                             members.sort_by_cached_key(|di_type| di_type.offset_in_bits());
-                            let sorted_elements =
-                                MDNode::with_elements(self.context, members.as_mut_slice());
-                            di_composite_type.replace_elements(sorted_elements);
+                            self.fixup(DiFixup::ReplaceMembers {
+                                node: ModuleValue::new(node),
+                                members: members
+                                    .iter()
+                                    .map(|member| ModuleValue::new(member.value_ref()))
+                                    .collect(),
+                            });
                         }
                         if remove_name {
                             // `AyaBtfMapMarker` is a type which is used in fields of BTF map
                             // structs. We need to make such structs anonymous in order to get
                             // BTF maps accepted by the Linux kernel.
-                            di_composite_type.replace_name(self.context, &[])
+                            self.fixup(DiFixup::RenameType {
+                                node: ModuleValue::new(node),
+                                name: Vec::new(),
+                            });
                         } else if let Some((_, sanitized_name)) = names {
                             // Clear the name from characters incompatible with C.
-                            di_composite_type.replace_name(self.context, sanitized_name.as_slice())
+                            self.fixup(DiFixup::RenameType {
+                                node: ModuleValue::new(node),
+                                name: sanitized_name,
+                            });
                         }
                     }
                     _ => (),
                 }
             }
-            Metadata::DIDerivedType(mut di_derived_type) => {
+            Metadata::DIDerivedType(di_derived_type) => {
                 #[expect(clippy::single_match)]
                 #[expect(non_upper_case_globals)]
                 match di_derived_type.tag() {
                     DW_TAG_pointer_type => {
                         // remove rust names
-                        di_derived_type.replace_name(self.context, &[])
+                        self.fixup(DiFixup::AnonymizePointer {
+                            node: ModuleValue::new(node),
+                        })
                     }
                     _ => (),
                 }
             }
-            Metadata::DISubprogram(mut di_subprogram) => {
+            Metadata::DISubprogram(di_subprogram) => {
                 // Sanitize function names
                 if let Some(name) = di_subprogram.name() {
                     let name = sanitize_type_name(name);
-                    di_subprogram.replace_name(self.context, name.as_slice())
+                    self.fixup(DiFixup::RenameSubprogram {
+                        node: ModuleValue::new(node),
+                        name,
+                    })
                 }
             }
             _ => (),
@@ -229,8 +258,10 @@ impl<'ctx> DISanitizer<'ctx> {
             return;
         }
 
-        if let Value::MDNode(mdnode) = value.clone() {
-            self.visit_mdnode(mdnode)
+        if self.fixups.is_some()
+            && let Value::MDNode(mdnode) = value.clone()
+        {
+            self.visit_mdnode(value_ref, mdnode)
         }
 
         if let Some(operands) = value.operands() {
@@ -265,10 +296,11 @@ impl<'ctx> DISanitizer<'ctx> {
         }
     }
 
-    pub(crate) fn run(mut self, exported_symbols: &HashSet<Cow<'_, [u8]>>) {
+    // Visits all the items of the module, planning the fixups when auditing and replacing the
+    // operands in `replace_operands`.
+    fn walk(&mut self) {
         let module = self.module;
-
-        self.replace_operands = self.fix_subprogram_linkage(exported_symbols);
+        self.visited_nodes.clear();
 
         for value in module.globals_iter() {
             self.visit_item(Item::GlobalVariable(value));
@@ -280,15 +312,97 @@ impl<'ctx> DISanitizer<'ctx> {
         for function in module.functions_iter() {
             self.visit_item(Item::Function(function));
         }
+    }
+
+    /// Returns the fixups needed to emit BTF for the debug info of the module, without changing
+    /// it.
+    pub(crate) fn audit(&mut self, export_symbols: &ExportSymbols<'_>) -> Vec<DiFixup<'ctx>> {
+        self.fixups = Some(Vec::new());
+
+        let module = self.module;
+        for function in module
+            .functions_iter()
+            .map(|value| unsafe { Function::from_value_ref(value) })
+        {
+            // Skip the exported functions and the ones that don't have subprograms.
+            if export_symbols.contains(function.name())
+                || function.subprogram(self.context).is_none()
+            {
+                continue;
+            }
+            self.fixup(DiFixup::LocalizeSubprogram {
+                function: ModuleValue::new(function.value_ref),
+                name: String::from_utf8_lossy(function.name()).into_owned(),
+            });
+        }
+
+        self.walk();
+        self.fixups.take().unwrap_or_default()
+    }
+
+    /// Applies the fixups returned by [`DISanitizer::audit`] to the module.
+    pub(crate) fn apply(&mut self, fixups: &[DiFixup<'ctx>]) {
+        let mut localized = Vec::new();
+        let mut skipped_types_lossy = Vec::new();
+        for fixup in fixups {
+            match fixup {
+                DiFixup::DropEnumMembers { node, name } => {
+                    let mut di_composite_type =
+                        unsafe { DICompositeType::from_value_ref(node.value_ref) };
+                    di_composite_type.replace_elements(MDNode::empty(self.context));
+                    skipped_types_lossy.extend(name.clone());
+                }
+                DiFixup::ReplaceMembers { node, members } => {
+                    let mut di_composite_type =
+                        unsafe { DICompositeType::from_value_ref(node.value_ref) };
+                    let members = members
+                        .iter()
+                        .map(|member| unsafe { DIType::from_value_ref(member.value_ref) })
+                        .collect::<Vec<_>>();
+                    di_composite_type
+                        .replace_elements(MDNode::with_elements(self.context, &members));
+                }
+                DiFixup::RenameType { node, name } => {
+                    let mut di_composite_type =
+                        unsafe { DICompositeType::from_value_ref(node.value_ref) };
+                    di_composite_type.replace_name(self.context, name);
+                }
+                DiFixup::AnonymizePointer { node } => {
+                    let mut di_derived_type =
+                        unsafe { DIDerivedType::from_value_ref(node.value_ref) };
+                    di_derived_type.replace_name(self.context, &[]);
+                }
+                DiFixup::RenameSubprogram { node, name } => {
+                    let mut di_subprogram = unsafe { DISubprogram::from_value_ref(node.value_ref) };
+                    di_subprogram.replace_name(self.context, name);
+                }
+                DiFixup::LocalizeSubprogram { function, name: _ } => {
+                    localized.push(function.value_ref)
+                }
+            }
+        }
+
+        // The new subprograms are created after renaming the old ones, so they copy the sanitized
+        // names. Then point all the debug info referencing the old subprograms to the new ones.
+        self.replace_operands = self.fix_subprogram_linkage(&localized);
+        if !self.replace_operands.is_empty() {
+            self.walk();
+        }
 
-        if !self.skipped_types_lossy.is_empty() {
+        if !skipped_types_lossy.is_empty() {
             warn!(
                 "debug info was not emitted for the following types: {}",
-                self.skipped_types_lossy.join(", ")
+                skipped_types_lossy.join(", ")
             );
         }
     }
 
+    pub(crate) fn run(mut self, exported_symbols: &ExportSymbols<'_>) {
+        let fixups = self.audit(exported_symbols);
+        debug!(?fixups, "planned {} debug info fixups", fixups.len());
+        self.apply(&fixups);
+    }
+
     // Make it so that only exported symbols (programs marked as #[no_mangle]) get BTF
     // linkage=global. For all other functions we want linkage=static. This avoid issues like:
     //
@@ -304,19 +418,14 @@ impl<'ctx> DISanitizer<'ctx> {
     // See tests/btf/assembly/exported-symbols.rs .
     fn fix_subprogram_linkage(
         &mut self,
-        export_symbols: &HashSet<Cow<'_, [u8]>>,
+        functions: &[LLVMValueRef],
     ) -> HashMap<u64, LLVMMetadataRef> {
         let mut replace = HashMap::new();
 
-        for mut function in self
-            .module
-            .functions_iter()
-            .map(|value| unsafe { Function::from_value_ref(value) })
+        for mut function in functions
+            .iter()
+            .map(|&value| unsafe { Function::from_value_ref(value) })
         {
-            if export_symbols.contains(function.name()) {
-                continue;
-            }
-
             // Skip functions that don't have subprograms.
             let Some(mut subprogram) = function.subprogram(self.context) else {
                 continue;
@@ -391,6 +500,59 @@ impl<'ctx> DISanitizer<'ctx> {
     }
 }
 
+/// A change to the debug info of a module, planned by [`DISanitizer::audit`] and made by
+/// [`DISanitizer::apply`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum DiFixup<'ctx> {
+    /// Drop the members of a data carrying enum, which the kernel doesn't support. `name` is the
+    /// name of the enum, if any.
+    DropEnumMembers {
+        node: ModuleValue<'ctx>,
+        name: Option<String>,
+    },
+    /// Replace the members of a struct with `members`, sorted by offset and without the
+    /// `AyaBtfMapMarker` fields.
+    ReplaceMembers {
+        node: ModuleValue<'ctx>,
+        members: Vec<ModuleValue<'ctx>>,
+    },
+    /// Rename a struct to a name valid in C, or make it anonymous when `name` is empty.
+    RenameType {
+        node: ModuleValue<'ctx>,
+        name: Vec<u8>,
+    },
+    /// Remove the Rust name of a pointer type.
+    AnonymizePointer { node: ModuleValue<'ctx> },
+    /// Rename a subprogram to a name valid in C.
+    RenameSubprogram {
+        node: ModuleValue<'ctx>,
+        name: Vec<u8>,
+    },
+    /// Replace the subprogram of the function `name`, which isn't exported, with one local to the
+    /// unit so that it gets BTF linkage=static.
+    LocalizeSubprogram {
+        function: ModuleValue<'ctx>,
+        name: String,
+    },
+}
+
+/// A value of the module a [`DiFixup`] was planned for. The lifetime keeps a plan from outliving
+/// the context of the module.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct ModuleValue<'ctx> {
+    value_ref: LLVMValueRef,
+    _marker: PhantomData<&'ctx ()>,
+}
+
+impl ModuleValue<'_> {
+    fn new(value_ref: LLVMValueRef) -> Self {
+        Self {
+            value_ref,
+            _marker: PhantomData,
+        }
+    }
+}
+
 impl Drop for DISanitizer<'_> {
     fn drop(&mut self) {
         unsafe { LLVMDisposeDIBuilder(self.builder) };
@@ -453,8 +615,66 @@ impl Item {
 
 #[cfg(test)]
 mod test {
+    use std::ffi::CString;
+
     use super::*;
 
+    #[test]
+    fn test_audit() {
+        let ir = CString::new(
+            r#"target triple = "bpfel"
+
+@VALUE = global { i64, i64 } zeroinitializer, !dbg !0
+@PTR = global ptr null, !dbg !8
+
+!llvm.dbg.cu = !{!2}
+!llvm.module.flags = !{!12, !13}
+
+!0 = !DIGlobalVariableExpression(var: !1, expr: !DIExpression())
+!1 = distinct !DIGlobalVariable(name: "VALUE", scope: !2, file: !3, line: 1, type: !5, isLocal: false, isDefinition: true)
+!2 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !3, producer: "rustc", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug, globals: !4)
+!3 = !DIFile(filename: "src/lib.rs", directory: "/")
+!4 = !{!0, !8}
+!5 = !DICompositeType(tag: DW_TAG_structure_type, name: "Option<u64>", scope: !2, file: !3, size: 128, align: 64, elements: !6, identifier: "option")
+!6 = !{!7}
+!7 = !DICompositeType(tag: DW_TAG_variant_part, file: !3, size: 128, align: 64, elements: !{}, identifier: "option-variant")
+!8 = !DIGlobalVariableExpression(var: !9, expr: !DIExpression())
+!9 = distinct !DIGlobalVariable(name: "PTR", scope: !2, file: !3, line: 2, type: !10, isLocal: false, isDefinition: true)
+!10 = !DIDerivedType(tag: DW_TAG_pointer_type, name: "&u8", baseType: !11, size: 64, align: 64, dwarfAddressSpace: 0)
+!11 = !DIBasicType(name: "u8", size: 8, encoding: DW_ATE_unsigned)
+!12 = !{i32 7, !"Dwarf Version", i32 4}
+!13 = !{i32 2, !"Debug Info Version", i32 3}
+"#,
+        )
+        .unwrap();
+        let context = LLVMContext::new();
+        let mut module = context.parse_ir(&ir, c"test.ll").unwrap();
+        let before = module.write_ir_to_memory().as_slice().to_vec();
+
+        let export_symbols = ExportSymbols::new(["VALUE", "PTR"]).unwrap();
+        let fixups = DISanitizer::new(&context, &mut module).audit(&export_symbols);
+        let [
+            DiFixup::DropEnumMembers {
+                node: enum_node,
+                name: Some(enum_name),
+            },
+            DiFixup::RenameType {
+                node: renamed_node,
+                name: renamed,
+            },
+            DiFixup::AnonymizePointer { .. },
+        ] = fixups.as_slice()
+        else {
+            panic!("unexpected fixups: {fixups:?}");
+        };
+        assert_eq!(enum_name, "Option<u64>");
+        assert_eq!(enum_node, renamed_node);
+        assert_eq!(renamed, b"Option_3C_u64_3E_");
+
+        // Auditing doesn't change the module.
+        assert_eq!(module.write_ir_to_memory().as_slice(), before);
+    }
+
     #[test]
     fn test_strip_generics() {
         let name = "MyStruct<u64>";
@@ -11,20 +11,31 @@ use std::{
 };
 
 pub(crate) use di::DISanitizer;
-use iter::{IterModuleFunctions as _, IterModuleGlobalAliases as _, IterModuleGlobals as _};
+use iter::{
+    IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _,
+    IterModuleGlobalAliases as _, IterModuleGlobals as _,
+};
 use llvm_sys::{
-    LLVMAttributeFunctionIndex, LLVMLinkage, LLVMVisibility,
-    bit_reader::LLVMParseBitcodeInContext2,
+    LLVMAttributeFunctionIndex, LLVMLinkage, LLVMTypeKind, LLVMVisibility,
     core::{
-        LLVMCreateMemoryBufferWithMemoryRange, LLVMDisposeMemoryBuffer, LLVMDisposeMessage,
-        LLVMGetEnumAttributeKindForName, LLVMGetMDString, LLVMGetModuleInlineAsm, LLVMGetTarget,
-        LLVMGetValueName2, LLVMRemoveEnumAttributeAtIndex, LLVMSetLinkage, LLVMSetModuleInlineAsm2,
-        LLVMSetVisibility,
+        LLVMAddGlobal, LLVMConstArray, LLVMConstInt, LLVMConstIntGetZExtValue,
+        LLVMCountBasicBlocks, LLVMCreateMemoryBufferWithMemoryRange, LLVMDeleteFunction,
+        LLVMDeleteGlobal, LLVMDisposeMessage, LLVMGetAllocatedType, LLVMGetAttributeCountAtIndex,
+        LLVMGetAttributesAtIndex, LLVMGetBasicBlockTerminator, LLVMGetCalledValue,
+        LLVMGetEnumAttributeAtIndex, LLVMGetEnumAttributeKindForName, LLVMGetFirstUse,
+        LLVMGetInitializer, LLVMGetIntTypeWidth, LLVMGetLinkage, LLVMGetMDString,
+        LLVMGetModuleContext, LLVMGetModuleInlineAsm, LLVMGetNamedGlobal, LLVMGetNumOperands,
+        LLVMGetNumSuccessors, LLVMGetOperand, LLVMGetSection, LLVMGetStringAttributeKind,
+        LLVMGetStringAttributeValue, LLVMGetSuccessor, LLVMGetTarget, LLVMGetTypeKind,
+        LLVMGetValueName2, LLVMGetVersion, LLVMGetVisibility, LLVMGlobalGetValueType,
+        LLVMInt64TypeInContext, LLVMIsAAllocaInst, LLVMIsACallInst, LLVMIsAConstantInt,
+        LLVMIsAFunction, LLVMIsDeclaration, LLVMIsStringAttribute, LLVMPointerTypeInContext,
+        LLVMRemoveEnumAttributeAtIndex, LLVMSetGlobalConstant, LLVMSetInitializer, LLVMSetLinkage,
+        LLVMSetModuleInlineAsm2, LLVMSetOperand, LLVMSetSection, LLVMSetVisibility, LLVMTypeOf,
     },
     error::{
         LLVMDisposeErrorMessage, LLVMGetErrorMessage, LLVMGetErrorTypeId, LLVMGetStringErrorTypeId,
     },
-    ir_reader::LLVMParseIRInContext,
     linker::LLVMLinkModules2,
     object::{
         LLVMCreateBinary, LLVMDisposeBinary, LLVMDisposeSectionIterator, LLVMGetSectionContents,
@@ -34,15 +45,19 @@ use llvm_sys::{
     prelude::{LLVMModuleRef, LLVMValueRef},
     support::LLVMParseCommandLineOptions,
     target::{
-        LLVMInitializeBPFAsmParser, LLVMInitializeBPFAsmPrinter, LLVMInitializeBPFDisassembler,
-        LLVMInitializeBPFTarget, LLVMInitializeBPFTargetInfo, LLVMInitializeBPFTargetMC,
+        LLVMABISizeOfType, LLVMGetModuleDataLayout, LLVMInitializeBPFAsmParser,
+        LLVMInitializeBPFAsmPrinter, LLVMInitializeBPFDisassembler, LLVMInitializeBPFTarget,
+        LLVMInitializeBPFTargetInfo, LLVMInitializeBPFTargetMC,
     },
     target_machine::{LLVMGetTargetFromTriple, LLVMTargetRef},
     transforms::pass_builder::{
-        LLVMCreatePassBuilderOptions, LLVMDisposePassBuilderOptions, LLVMRunPasses,
+        LLVMCreatePassBuilderOptions, LLVMDisposePassBuilderOptions, LLVMPassBuilderOptionsRef,
+        LLVMPassBuilderOptionsSetInlinerThreshold, LLVMPassBuilderOptionsSetLoopUnrolling,
+        LLVMPassBuilderOptionsSetLoopVectorization, LLVMPassBuilderOptionsSetSLPVectorization,
+        LLVMRunPasses,
     },
 };
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 pub(crate) use types::{
     context::{InstalledDiagnosticHandler, LLVMContext},
     memory_buffer::MemoryBuffer,
@@ -50,7 +65,7 @@ pub(crate) use types::{
     target_machine::LLVMTargetMachine,
 };
 
-use crate::OptLevel;
+use crate::{LinkerError, OptLevel, export::ExportSymbols};
 
 pub(crate) fn init(args: &[Cow<'_, CStr>], overview: &CStr) {
     unsafe {
@@ -72,10 +87,53 @@ pub(crate) fn init(args: &[Cow<'_, CStr>], overview: &CStr) {
     };
 }
 
+/// Returns the `major.minor.patch` version of the LLVM the linker runs with.
+pub(crate) fn version() -> String {
+    let (mut major, mut minor, mut patch) = (0, 0, 0);
+    unsafe { LLVMGetVersion(&mut major, &mut minor, &mut patch) };
+    format!("{major}.{minor}.{patch}")
+}
+
 pub(crate) fn with_embedded_bitcode<T>(
     context: &LLVMContext,
     data: &[u8],
     f: impl FnOnce(&[u8]) -> T,
+) -> Result<Option<T>, String> {
+    with_section(context, data, c".llvmbc", f)
+}
+
+/// Returns the contents of the `.BTF` section of the object file, if it has one.
+pub(crate) fn find_embedded_btf(
+    context: &LLVMContext,
+    data: &[u8],
+) -> Result<Option<Vec<u8>>, String> {
+    extract_section(context, data, c".BTF")
+}
+
+/// Returns the contents of the `.BTF.ext` section of the object file, holding the func info and
+/// the line info of the programs, if it has one.
+pub(crate) fn find_embedded_btf_ext(
+    context: &LLVMContext,
+    data: &[u8],
+) -> Result<Option<Vec<u8>>, String> {
+    extract_section(context, data, c".BTF.ext")
+}
+
+/// Returns the contents of the section named `section` of the object file, if it has one.
+pub(crate) fn extract_section(
+    context: &LLVMContext,
+    data: &[u8],
+    section: &CStr,
+) -> Result<Option<Vec<u8>>, String> {
+    with_section(context, data, section, <[u8]>::to_vec)
+}
+
+/// Calls `f` with the contents of the section named `section` of the object file, if it has one.
+fn with_section<T>(
+    context: &LLVMContext,
+    data: &[u8],
+    section: &CStr,
+    f: impl FnOnce(&[u8]) -> T,
 ) -> Result<Option<T>, String> {
     let buffer_name = c"mem_buffer";
     let buffer = unsafe {
@@ -103,7 +161,7 @@ pub(crate) fn with_embedded_bitcode<T>(
         let name = unsafe { LLVMGetSectionName(iter) };
         if !name.is_null() {
             let name = unsafe { CStr::from_ptr(name) };
-            if name == c".llvmbc" {
+            if name == section {
                 let buf = unsafe { LLVMGetSectionContents(iter) };
                 let size = unsafe { LLVMGetSectionSize(iter) }.try_into().unwrap();
                 let data = unsafe { slice::from_raw_parts(buf.cast(), size) };
@@ -116,82 +174,6 @@ pub(crate) fn with_embedded_bitcode<T>(
     Ok(None)
 }
 
-#[must_use]
-pub(crate) fn link_bitcode_buffer<'ctx>(
-    context: &'ctx LLVMContext,
-    module: &mut LLVMModule<'ctx>,
-    buffer: &[u8],
-) -> bool {
-    let mut linked = false;
-    let buffer_name = c"mem_buffer";
-    let buffer = unsafe {
-        LLVMCreateMemoryBufferWithMemoryRange(
-            buffer.as_ptr().cast(),
-            buffer.len(),
-            buffer_name.as_ptr(),
-            0,
-        )
-    };
-    scopeguard::defer!(unsafe { LLVMDisposeMemoryBuffer(buffer) });
-
-    let mut temp_module = ptr::null_mut();
-
-    if unsafe { LLVMParseBitcodeInContext2(context.as_mut_ptr(), buffer, &mut temp_module) } == 0 {
-        linked = unsafe { LLVMLinkModules2(module.as_mut_ptr(), temp_module) } == 0;
-    }
-
-    linked
-}
-
-/// Links an LLVM IR buffer into the given module.
-///
-/// The buffer must be null-terminated (hence `CStr`), because LLVM's IR parser
-/// requires `RequiresNullTerminator=true` when creating the memory buffer.
-/// See `getMemBuffer` with default `RequiresNullTerminator = true`:
-/// https://github.com/llvm/llvm-project/blob/bde90624185ea2cead0a8d7231536e2625d78798/llvm/include/llvm/Support/MemoryBuffer.h#L134
-/// Called by `LLVMParseIRInContext` follows this path parseIR => parseAssembly => parseAssemblyInto
-/// Deep inside LLVM parser's they rely on the null termination for performance optimization.
-/// LLVM's C API does not enforce this at the type level, so callers must guarantee the invariant themselves.
-/// See the relevant code inside LLVM's parser:
-/// https://github.com/llvm/llvm-project/blob/bde90624185ea2cead0a8d7231536e2625d78798/llvm/lib/AsmParser/Parser.cpp#L30
-///
-/// Without the null terminator, LLVM hits an assertion in debug builds.
-pub(crate) fn link_ir_buffer<'ctx>(
-    context: &'ctx LLVMContext,
-    module: &mut LLVMModule<'ctx>,
-    buffer: &CStr,
-) -> Result<bool, String> {
-    let buffer_name = c"ir_buffer";
-    let buffer = buffer.to_bytes();
-    let mem_buffer = unsafe {
-        LLVMCreateMemoryBufferWithMemoryRange(
-            buffer.as_ptr().cast(),
-            buffer.len(),
-            buffer_name.as_ptr(),
-            0,
-        )
-    };
-
-    let mut temp_module = ptr::null_mut();
-    let (ret, message) = Message::with(|error_msg| unsafe {
-        // LLVMParseIRInContext takes ownership of mem_buffer, so we don't need to dispose of it ourselves.
-        // https://github.com/llvm/llvm-project/blob/00276b67d36a665119a6a7b39dbba69f45c44e58/llvm/lib/IRReader/IRReader.cpp#L122
-        LLVMParseIRInContext(
-            context.as_mut_ptr(),
-            mem_buffer,
-            &mut temp_module,
-            error_msg,
-        )
-    });
-
-    if ret == 0 {
-        let linked = unsafe { LLVMLinkModules2(module.as_mut_ptr(), temp_module) } == 0;
-        Ok(linked)
-    } else {
-        Err(message.as_string_lossy().to_string())
-    }
-}
-
 pub(crate) fn target_from_triple(triple: &CStr) -> Result<LLVMTargetRef, String> {
     let mut target = ptr::null_mut();
     let (ret, message) = Message::with(|message| unsafe {
@@ -209,34 +191,426 @@ pub(crate) fn target_from_module(module: &LLVMModule<'_>) -> Result<LLVMTargetRe
     unsafe { target_from_triple(CStr::from_ptr(triple)) }
 }
 
-pub(crate) fn optimize(
-    tm: &LLVMTargetMachine,
+/// Gives internal linkage to every defined global, alias and function which is not exported.
+pub(crate) fn internalize_module(
     module: &mut LLVMModule<'_>,
-    opt_level: OptLevel,
-    ignore_inline_never: bool,
-    export_symbols: &HashSet<Cow<'_, [u8]>>,
-) -> Result<(), String> {
-    if module_asm_is_probestack(module.as_mut_ptr()) {
-        unsafe { LLVMSetModuleInlineAsm2(module.as_mut_ptr(), ptr::null_mut(), 0) };
-    }
-
+    export_symbols: &ExportSymbols<'_>,
+    keep_symbols: &ExportSymbols<'_>,
+) -> Vec<InternalizedSymbol> {
+    let mut symbols = Vec::new();
     for sym in module.as_mut_ptr().globals_iter() {
-        internalize(sym, symbol_name(sym), export_symbols);
+        symbols.push(internalize(
+            sym,
+            symbol_name(sym),
+            SymbolKind::Global,
+            export_symbols,
+            keep_symbols,
+        ));
     }
     for sym in module.as_mut_ptr().global_aliases_iter() {
-        internalize(sym, symbol_name(sym), export_symbols);
+        symbols.push(internalize(
+            sym,
+            symbol_name(sym),
+            SymbolKind::Alias,
+            export_symbols,
+            keep_symbols,
+        ));
     }
 
     for function in module.as_mut_ptr().functions_iter() {
         let name = symbol_name(function);
         if !name.starts_with(b"llvm.") {
-            if ignore_inline_never {
-                remove_attribute(function, "noinline");
+            symbols.push(internalize(
+                function,
+                name,
+                SymbolKind::Function,
+                export_symbols,
+                keep_symbols,
+            ));
+        }
+    }
+
+    symbols
+}
+
+/// Deletes the global variables which have no use and are neither exported nor kept, until none
+/// is left, returning their names. The `llvm.*` globals, like `llvm.used`, are never deleted.
+pub(crate) fn strip_unused_globals(
+    module: &mut LLVMModule<'_>,
+    export_symbols: &ExportSymbols<'_>,
+    keep_symbols: &ExportSymbols<'_>,
+) -> Vec<String> {
+    let mut stripped = Vec::new();
+    loop {
+        // Deleting a global can leave the globals its initializer referenced unused.
+        let unused = module
+            .as_mut_ptr()
+            .globals_iter()
+            .filter(|&global| {
+                let name = symbol_name(global);
+                unsafe { LLVMGetFirstUse(global) }.is_null()
+                    && !name.starts_with(b"llvm.")
+                    && !export_symbols.contains(name)
+                    && !keep_symbols.contains(name)
+            })
+            .collect::<Vec<_>>();
+        if unused.is_empty() {
+            return stripped;
+        }
+        for global in unused {
+            stripped.push(String::from_utf8_lossy(symbol_name(global)).into_owned());
+            unsafe { LLVMDeleteGlobal(global) };
+        }
+    }
+}
+
+/// Appends the defined functions with internal or private linkage to `llvm.compiler.used`, so
+/// that the optimization passes don't delete them when they're unreferenced, returning their
+/// names.
+pub(crate) fn keep_internal_functions(module: &mut LLVMModule<'_>) -> Vec<String> {
+    append_to_compiler_used(module, |function| {
+        matches!(
+            unsafe { LLVMGetLinkage(function) },
+            LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+        ) && unsafe { LLVMIsDeclaration(function) } == 0
+    })
+}
+
+/// Gives the defined functions with internal linkage private linkage, so that the backend leaves
+/// their names out of the symbol table, returning their names.
+pub(crate) fn make_internal_functions_private(module: &mut LLVMModule<'_>) -> Vec<String> {
+    let mut hidden = Vec::new();
+    for function in module.as_mut_ptr().functions_iter() {
+        if unsafe { LLVMGetLinkage(function) } == LLVMLinkage::LLVMInternalLinkage
+            && unsafe { LLVMIsDeclaration(function) } == 0
+        {
+            unsafe { LLVMSetLinkage(function, LLVMLinkage::LLVMPrivateLinkage) };
+            hidden.push(String::from_utf8_lossy(symbol_name(function)).into_owned());
+        }
+    }
+    hidden
+}
+
+/// Appends the declared but undefined functions matching `allowed` to `llvm.compiler.used`, so
+/// that the optimization passes don't delete their declarations when they're unreferenced,
+/// returning their names.
+pub(crate) fn keep_declarations(
+    module: &mut LLVMModule<'_>,
+    allowed: &ExportSymbols<'_>,
+) -> Vec<String> {
+    append_to_compiler_used(module, |function| {
+        allowed.contains(symbol_name(function)) && unsafe { LLVMIsDeclaration(function) } != 0
+    })
+}
+
+/// Appends the functions for which `keep` returns true to `llvm.compiler.used`, returning the
+/// names of the ones which weren't already in it.
+fn append_to_compiler_used(
+    module: &mut LLVMModule<'_>,
+    keep: impl Fn(LLVMValueRef) -> bool,
+) -> Vec<String> {
+    let module = module.as_mut_ptr();
+    let name = c"llvm.compiler.used";
+
+    let mut used = Vec::new();
+    let existing = unsafe { LLVMGetNamedGlobal(module, name.as_ptr()) };
+    if !existing.is_null() {
+        let initializer = unsafe { LLVMGetInitializer(existing) };
+        if !initializer.is_null() {
+            let count = unsafe { LLVMGetNumOperands(initializer) }.cast_unsigned();
+            for index in 0..count {
+                used.push(unsafe { LLVMGetOperand(initializer, index) });
+            }
+        }
+    }
+
+    let mut kept = Vec::new();
+    for function in module.functions_iter() {
+        if !symbol_name(function).starts_with(b"llvm.")
+            && keep(function)
+            && !used.contains(&function)
+        {
+            kept.push(String::from_utf8_lossy(symbol_name(function)).into_owned());
+            used.push(function);
+        }
+    }
+    if kept.is_empty() {
+        return kept;
+    }
+
+    if !existing.is_null() {
+        unsafe { LLVMDeleteGlobal(existing) };
+    }
+    unsafe {
+        let ptr = LLVMPointerTypeInContext(LLVMGetModuleContext(module), 0);
+        let array = LLVMConstArray(ptr, used.as_mut_ptr(), used.len().try_into().unwrap());
+        let global = LLVMAddGlobal(module, LLVMTypeOf(array), name.as_ptr());
+        LLVMSetInitializer(global, array);
+        LLVMSetLinkage(global, LLVMLinkage::LLVMAppendingLinkage);
+        LLVMSetSection(global, c"llvm.metadata".as_ptr());
+    }
+    kept
+}
+
+/// Returns the names of the defined functions which contain a loop, that is a branch to a block
+/// laid out before the branch.
+pub(crate) fn functions_with_loops(module: &LLVMModule<'_>) -> Vec<String> {
+    module
+        .as_mut_ptr()
+        .functions_iter()
+        .filter(|&function| {
+            let mut seen = HashSet::new();
+            function.basic_blocks_iter().any(|block| {
+                let _: bool = seen.insert(block);
+                let terminator = unsafe { LLVMGetBasicBlockTerminator(block) };
+                !terminator.is_null()
+                    && (0..unsafe { LLVMGetNumSuccessors(terminator) })
+                        .any(|index| seen.contains(&unsafe { LLVMGetSuccessor(terminator, index) }))
+            })
+        })
+        .map(|function| String::from_utf8_lossy(symbol_name(function)).into_owned())
+        .collect()
+}
+
+/// Returns the name and the estimated stack usage in bytes of every defined function which isn't
+/// internal, that is the total size of its allocas. An alloca of a dynamic number of elements
+/// counts as a single element.
+pub(crate) fn function_stack_sizes(module: &LLVMModule<'_>) -> Vec<(String, u64)> {
+    let module = module.as_mut_ptr();
+    let data_layout = unsafe { LLVMGetModuleDataLayout(module) };
+    module
+        .functions_iter()
+        .filter(|&function| {
+            !matches!(
+                unsafe { LLVMGetLinkage(function) },
+                LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+            ) && unsafe { LLVMIsDeclaration(function) } == 0
+        })
+        .map(|function| {
+            let mut size: u64 = 0;
+            for block in function.basic_blocks_iter() {
+                for alloca in block
+                    .instructions_iter()
+                    .filter(|&instruction| !unsafe { LLVMIsAAllocaInst(instruction) }.is_null())
+                {
+                    let alloca_size =
+                        unsafe { LLVMABISizeOfType(data_layout, LLVMGetAllocatedType(alloca)) };
+                    let count = unsafe { LLVMGetOperand(alloca, 0) };
+                    // The count is any integer, like in `alloca i8, i64 -1`, so saturate instead
+                    // of overflowing.
+                    size = size.saturating_add(if unsafe { LLVMIsAConstantInt(count) }.is_null() {
+                        alloca_size
+                    } else {
+                        alloca_size.saturating_mul(unsafe { LLVMConstIntGetZExtValue(count) })
+                    });
+                }
+            }
+            (
+                String::from_utf8_lossy(symbol_name(function)).into_owned(),
+                size,
+            )
+        })
+        .collect()
+}
+
+/// Returns the names of the callers and the callees of the direct calls to functions which are
+/// declared but not defined, ignoring the LLVM intrinsics, and whether the callee is declared in
+/// the `.ksyms` section, like the kfuncs declared with libbpf's `__ksym`. Every pair is reported
+/// once.
+pub(crate) fn undefined_calls(module: &LLVMModule<'_>) -> Vec<(String, String, bool)> {
+    let mut calls = Vec::new();
+    for caller in module.as_mut_ptr().functions_iter() {
+        let mut callees = HashSet::new();
+        for block in caller.basic_blocks_iter() {
+            for instruction in block.instructions_iter() {
+                if unsafe { LLVMIsACallInst(instruction) }.is_null() {
+                    continue;
+                }
+                let callee = unsafe { LLVMGetCalledValue(instruction) };
+                if !unsafe { LLVMIsAFunction(callee) }.is_null()
+                    && unsafe { LLVMIsDeclaration(callee) } != 0
+                    && !symbol_name(callee).starts_with(b"llvm.")
+                    && callees.insert(callee)
+                {
+                    let section = unsafe { LLVMGetSection(callee) };
+                    let ksym =
+                        !section.is_null() && unsafe { CStr::from_ptr(section) } == c".ksyms";
+                    calls.push((
+                        String::from_utf8_lossy(symbol_name(caller)).into_owned(),
+                        String::from_utf8_lossy(symbol_name(callee)).into_owned(),
+                        ksym,
+                    ));
+                }
             }
-            internalize(function, name, export_symbols);
         }
     }
+    calls
+}
+
+/// Returns the name and the number of instructions of every defined function which isn't internal,
+/// including the instructions of the internal functions it calls, directly or not. An internal
+/// function called from several entry points counts for each of them.
+pub(crate) fn program_instruction_counts(module: &LLVMModule<'_>) -> Vec<(String, usize)> {
+    let is_internal = |function| {
+        matches!(
+            unsafe { LLVMGetLinkage(function) },
+            LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+        )
+    };
+    module
+        .as_mut_ptr()
+        .functions_iter()
+        .filter(|&function| !is_internal(function) && unsafe { LLVMIsDeclaration(function) } == 0)
+        .map(|entry| {
+            let mut count = 0;
+            let mut seen = HashSet::from([entry]);
+            let mut stack = vec![entry];
+            while let Some(function) = stack.pop() {
+                for block in function.basic_blocks_iter() {
+                    for instruction in block.instructions_iter() {
+                        count += 1;
+                        if unsafe { LLVMIsACallInst(instruction) }.is_null() {
+                            continue;
+                        }
+                        let callee = unsafe { LLVMGetCalledValue(instruction) };
+                        if !unsafe { LLVMIsAFunction(callee) }.is_null()
+                            && is_internal(callee)
+                            && unsafe { LLVMIsDeclaration(callee) } == 0
+                            && seen.insert(callee)
+                        {
+                            stack.push(callee);
+                        }
+                    }
+                }
+            }
+            (
+                String::from_utf8_lossy(symbol_name(entry)).into_owned(),
+                count,
+            )
+        })
+        .collect()
+}
 
+#[expect(clippy::too_many_arguments)]
+pub(crate) fn optimize(
+    tm: &LLVMTargetMachine,
+    module: &mut LLVMModule<'_>,
+    opt_level: OptLevel,
+    passes: Option<&str>,
+    extra_passes: Option<&str>,
+    disabled_passes: &[String],
+    inline_threshold: Option<i32>,
+    ignore_inline_never: bool,
+    remove_attributes: &[String],
+    keep_optnone: bool,
+    verify_module: bool,
+) -> Result<(), LinkerError> {
+    if let Some(pass) = disabled_passes
+        .iter()
+        .find(|pass| !DISABLEABLE_PASSES.contains(&pass.as_str()))
+    {
+        return Err(LinkerError::InvalidDisabledPass(pass.clone()));
+    }
+
+    strip_probestack_asm(module.as_mut_ptr());
+
+    let mut removed_attributes = Vec::new();
+    if ignore_inline_never {
+        removed_attributes.push("noinline");
+    }
+    for name in remove_attributes {
+        if unsafe { LLVMGetEnumAttributeKindForName(name.as_ptr().cast(), name.len()) } == 0 {
+            warn!("not removing the attribute `{name}`, it isn't a known function attribute");
+        } else {
+            removed_attributes.push(name);
+        }
+    }
+    if !keep_optnone && !matches!(opt_level, OptLevel::No | OptLevel::Less) {
+        let optnone = "optnone";
+        let kind =
+            unsafe { LLVMGetEnumAttributeKindForName(optnone.as_ptr().cast(), optnone.len()) };
+        let functions: Vec<_> = module
+            .as_mut_ptr()
+            .functions_iter()
+            .filter(|&function| {
+                !unsafe { LLVMGetEnumAttributeAtIndex(function, LLVMAttributeFunctionIndex, kind) }
+                    .is_null()
+            })
+            .collect();
+        for &function in &functions {
+            remove_attribute(function, optnone);
+        }
+        if !functions.is_empty() {
+            debug!(
+                "removed optnone from {:?} to optimize them at {opt_level:?}",
+                functions
+                    .iter()
+                    .map(|&function| String::from_utf8_lossy(symbol_name(function)))
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+    if !removed_attributes.is_empty() {
+        for function in module.as_mut_ptr().functions_iter() {
+            if !symbol_name(function).starts_with(b"llvm.") {
+                for name in &removed_attributes {
+                    remove_attribute(function, name);
+                }
+            }
+        }
+    }
+
+    let passes = pass_pipeline(
+        opt_level,
+        passes,
+        disabled_passes,
+        extra_passes,
+        verify_module,
+    );
+    debug!("running the pass pipeline `{}`", passes.to_string_lossy());
+    run_passes(tm, module, &passes, disabled_passes, inline_threshold)
+}
+
+/// Returns the pipeline run by [`optimize`]: the custom `passes` when given, followed by
+/// `extra_passes` and the `verify` pass, or the one built by [`build_pass_pipeline`] otherwise.
+pub(crate) fn pass_pipeline(
+    opt_level: OptLevel,
+    passes: Option<&str>,
+    disabled_passes: &[String],
+    extra_passes: Option<&str>,
+    verify_module: bool,
+) -> CString {
+    match passes.filter(|passes| !passes.trim().is_empty()) {
+        Some(passes) => {
+            let passes = [passes]
+                .into_iter()
+                .chain(extra_passes)
+                .chain(verify_module.then_some("verify"))
+                .collect::<Vec<_>>();
+            CString::new(passes.join(",")).unwrap()
+        }
+        None => build_pass_pipeline(opt_level, disabled_passes, extra_passes, verify_module),
+    }
+}
+
+/// The passes which can be disabled with [`LinkerOptions::disabled_passes`].
+///
+/// `dce` is appended to the pipeline by [`build_pass_pipeline`]. The other passes run as part of
+/// the `default<_>` pipeline, which can't be edited from a pipeline string, and are disabled
+/// through the pass builder options instead.
+///
+/// [`LinkerOptions::disabled_passes`]: crate::LinkerOptions::disabled_passes
+pub(crate) const DISABLEABLE_PASSES: &[&str] =
+    &["dce", "loop-unroll", "loop-vectorize", "slp-vectorizer"];
+
+/// Returns the pipeline run for `opt_level`, without the passes in `disabled_passes` and followed
+/// by `extra_passes`, then by the `verify` pass when `verify_module` is set.
+pub(crate) fn build_pass_pipeline(
+    opt_level: OptLevel,
+    disabled_passes: &[String],
+    extra_passes: Option<&str>,
+    verify_module: bool,
+) -> CString {
     let passes = [
         // NB: "default<_>" must be the first pass in the list, otherwise it will be ignored.
         match opt_level {
@@ -254,11 +628,41 @@ pub(crate) fn optimize(
         "dce",
     ];
 
-    let passes = passes.join(",");
-    debug!("running passes: {passes}");
-    let passes = CString::new(passes).unwrap();
+    let passes = passes
+        .into_iter()
+        .filter(|pass| !disabled_passes.iter().any(|disabled| disabled == pass))
+        .chain(extra_passes)
+        .chain(verify_module.then_some("verify"))
+        .collect::<Vec<_>>();
+    CString::new(passes.join(",")).unwrap()
+}
+
+/// Downgrades the debug info of the module to line tables only, dropping the types and variables
+/// while keeping the subprograms and the source locations of instructions.
+pub(crate) fn strip_non_line_table_debug_info(
+    tm: &LLVMTargetMachine,
+    module: &mut LLVMModule<'_>,
+) -> Result<(), LinkerError> {
+    run_passes(tm, module, c"strip-nonlinetable-debuginfo", &[], None)
+}
+
+fn run_passes(
+    tm: &LLVMTargetMachine,
+    module: &mut LLVMModule<'_>,
+    passes: &CStr,
+    disabled_passes: &[String],
+    inline_threshold: Option<i32>,
+) -> Result<(), LinkerError> {
+    debug!("running passes: {}", passes.to_string_lossy());
     let options = unsafe { LLVMCreatePassBuilderOptions() };
     scopeguard::defer!(unsafe { LLVMDisposePassBuilderOptions(options) });
+    for pass in disabled_passes {
+        disable_pass(options, pass);
+    }
+    if let Some(threshold) = inline_threshold {
+        debug!("setting the inliner threshold to {threshold}");
+        unsafe { LLVMPassBuilderOptionsSetInlinerThreshold(options, threshold) };
+    }
 
     let error = unsafe {
         LLVMRunPasses(
@@ -278,22 +682,236 @@ pub(crate) fn optimize(
         let error_string = unsafe { CStr::from_ptr(error_message) }
             .to_string_lossy()
             .to_string();
-        return Err(error_string);
+        return Err(LinkerError::PassError {
+            pipeline: passes.to_string_lossy().into_owned(),
+            message: error_string,
+        });
     }
 
     Ok(())
 }
 
-pub(crate) fn module_asm_is_probestack(module: LLVMModuleRef) -> bool {
+/// Disables a pass of the `default<_>` pipeline through the pass builder options.
+fn disable_pass(options: LLVMPassBuilderOptionsRef, pass: &str) {
+    match pass {
+        "loop-unroll" => unsafe { LLVMPassBuilderOptionsSetLoopUnrolling(options, 0) },
+        "loop-vectorize" => unsafe { LLVMPassBuilderOptionsSetLoopVectorization(options, 0) },
+        "slp-vectorizer" => unsafe { LLVMPassBuilderOptionsSetSLPVectorization(options, 0) },
+        _ => {}
+    }
+}
+
+/// Links the module `src` into `dest`, returning whether linking succeeded. LLVM destroys `src`
+/// in both cases, so it must not be used, nor disposed of, afterwards.
+///
+/// Unlike linking a buffer, the modules are already parsed, so each of them can be transformed on
+/// its own before being merged.
+pub(crate) fn merge_modules(dest: LLVMModuleRef, src: LLVMModuleRef) -> bool {
+    unsafe { LLVMLinkModules2(dest, src) == 0 }
+}
+
+/// Gives the declared integer global `name` the constant initializer `value`, or adds it as an
+/// internal `i64` constant if the module has no such global.
+pub(crate) fn define_symbol(
+    module: &mut LLVMModule<'_>,
+    name: &str,
+    value: u64,
+) -> Result<(), String> {
+    let module = module.as_mut_ptr();
+    let global = module
+        .globals_iter()
+        .find(|&global| symbol_name(global) == name.as_bytes());
+    let global = match global {
+        Some(global) => {
+            if !unsafe { LLVMGetInitializer(global) }.is_null() {
+                return Err("the global is already defined".to_owned());
+            }
+            let ty = unsafe { LLVMGlobalGetValueType(global) };
+            if unsafe { LLVMGetTypeKind(ty) } != LLVMTypeKind::LLVMIntegerTypeKind {
+                return Err("the global is not an integer".to_owned());
+            }
+            let width = unsafe { LLVMGetIntTypeWidth(ty) };
+            if width < u64::BITS && value >> width != 0 {
+                return Err(format!("{value} doesn't fit in i{width}"));
+            }
+            unsafe { LLVMSetInitializer(global, LLVMConstInt(ty, value, 0)) };
+            global
+        }
+        None => {
+            let name = CString::new(name).map_err(|err| err.to_string())?;
+            let ty = unsafe { LLVMInt64TypeInContext(LLVMGetModuleContext(module)) };
+            let global = unsafe { LLVMAddGlobal(module, ty, name.as_ptr()) };
+            unsafe { LLVMSetInitializer(global, LLVMConstInt(ty, value, 0)) };
+            unsafe { LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage) };
+            global
+        }
+    };
+    unsafe { LLVMSetGlobalConstant(global, 1) };
+    Ok(())
+}
+
+/// Redirects the references to each of the `symbols` to `__wrap_<symbol>`, and the references to
+/// `__real_<symbol>` to the original symbol, like the `--wrap` option of GNU ld. Returns the names
+/// of the symbols, or wrappers, which are not in the module, in which case nothing is redirected.
+pub(crate) fn wrap_symbols(module: &mut LLVMModule<'_>, symbols: &[String]) -> Vec<String> {
+    let module = module.as_mut_ptr();
+    let find = |name: &str| {
+        module
+            .functions_iter()
+            .chain(module.globals_iter())
+            .find(|&value| symbol_name(value) == name.as_bytes())
+    };
+
+    let mut missing = Vec::new();
+    for symbol in symbols {
+        let wrapper = format!("__wrap_{symbol}");
+        let (original, wrap) = match (find(symbol), find(&wrapper)) {
+            (Some(original), Some(wrap)) => (original, wrap),
+            (None, _) => {
+                missing.push(symbol.clone());
+                continue;
+            }
+            (Some(_), None) => {
+                missing.push(wrapper);
+                continue;
+            }
+        };
+        replace_references(module, original, wrap);
+        if let Some(real) = find(&format!("__real_{symbol}")) {
+            replace_references(module, real, original);
+            if unsafe { LLVMIsDeclaration(real) } != 0 && unsafe { LLVMGetFirstUse(real) }.is_null()
+            {
+                if unsafe { LLVMIsAFunction(real) }.is_null() {
+                    unsafe { LLVMDeleteGlobal(real) };
+                } else {
+                    unsafe { LLVMDeleteFunction(real) };
+                }
+            }
+        }
+    }
+    missing
+}
+
+/// Replaces `from` with `to` in the operands of the instructions and in the initializers of the
+/// globals, except the LLVM ones like `llvm.used`, which keep referring to the original symbol.
+fn replace_references(module: LLVMModuleRef, from: LLVMValueRef, to: LLVMValueRef) {
+    for function in module.functions_iter() {
+        for block in function.basic_blocks_iter() {
+            for instruction in block.instructions_iter() {
+                let operands = unsafe { LLVMGetNumOperands(instruction) };
+                for index in 0..operands.try_into().unwrap() {
+                    if unsafe { LLVMGetOperand(instruction, index) } == from {
+                        unsafe { LLVMSetOperand(instruction, index, to) };
+                    }
+                }
+            }
+        }
+    }
+    for global in module.globals_iter() {
+        if !symbol_name(global).starts_with(b"llvm.")
+            && unsafe { LLVMGetInitializer(global) } == from
+        {
+            unsafe { LLVMSetInitializer(global, to) };
+        }
+    }
+}
+
+/// Returns the explicitly exported symbols which are not defined in the module, sorted by name.
+pub(crate) fn undefined_exports<'a>(
+    module: &mut LLVMModule<'_>,
+    export_symbols: &ExportSymbols<'a>,
+) -> Vec<&'a [u8]> {
+    let module = module.as_mut_ptr();
+    let mut defined = HashSet::new();
+    for function in module.functions_iter() {
+        if unsafe { LLVMCountBasicBlocks(function) } != 0 {
+            let _: bool = defined.insert(symbol_name(function));
+        }
+    }
+    for global in module.globals_iter() {
+        if !unsafe { LLVMGetInitializer(global) }.is_null() {
+            let _: bool = defined.insert(symbol_name(global));
+        }
+    }
+    for alias in module.global_aliases_iter() {
+        let _: bool = defined.insert(symbol_name(alias));
+    }
+
+    let mut undefined: Vec<_> = export_symbols
+        .required()
+        .filter(|name| !defined.contains(name))
+        .collect();
+    undefined.sort_unstable();
+    undefined
+}
+
+/// Returns the names of the globals, aliases and functions with external linkage defined by the
+/// module. Unlike the other linkages, two such definitions of the same symbol conflict.
+pub(crate) fn external_definitions(module: &LLVMModule<'_>) -> Vec<Vec<u8>> {
+    let module = module.as_mut_ptr();
+    let is_external = |value| unsafe { LLVMGetLinkage(value) } == LLVMLinkage::LLVMExternalLinkage;
+    let functions = module
+        .functions_iter()
+        .filter(|&function| unsafe { LLVMCountBasicBlocks(function) } != 0);
+    let globals = module
+        .globals_iter()
+        .filter(|&global| !unsafe { LLVMGetInitializer(global) }.is_null());
+    functions
+        .chain(globals)
+        .chain(module.global_aliases_iter())
+        .filter(|&value| is_external(value))
+        .map(|value| symbol_name(value).to_vec())
+        .collect()
+}
+
+/// Removes the definition of `__rust_probestack` from the module inline asm, keeping the other
+/// inline asm. The definition is x86 code, which the BPF backend can't assemble.
+pub(crate) fn strip_probestack_asm(module: LLVMModuleRef) {
     let mut len = 0;
     let ptr = unsafe { LLVMGetModuleInlineAsm(module, &mut len) };
     if ptr.is_null() {
-        return false;
+        return;
+    }
+
+    let asm: &[u8] = unsafe { slice::from_raw_parts(ptr.cast(), len) };
+    if let Some(asm) = remove_probestack_lines(&String::from_utf8_lossy(asm)) {
+        unsafe { LLVMSetModuleInlineAsm2(module, asm.as_ptr().cast(), asm.len()) };
     }
+}
+
+/// Returns `asm` without the lines defining `__rust_probestack`, or `None` when there are none.
+/// The definition is usually wrapped in a `.pushsection`/`.popsection` pair, in which case the
+/// whole section is removed, including the instructions not mentioning the symbol.
+fn remove_probestack_lines(asm: &str) -> Option<String> {
+    const SYMBOL: &str = "__rust_probestack";
 
-    let needle = b"__rust_probestack";
-    let haystack: &[u8] = unsafe { slice::from_raw_parts(ptr.cast(), len) };
-    haystack.windows(needle.len()).any(|w| w == needle)
+    let mut removed = false;
+    let mut depth = 0usize;
+    let mut kept = Vec::new();
+    for line in asm.lines() {
+        let directive = line.trim_start();
+        if depth > 0 {
+            if directive.starts_with(".pushsection") {
+                depth += 1;
+            } else if directive.starts_with(".popsection") {
+                depth -= 1;
+            }
+        } else if directive.starts_with(".pushsection") && directive.contains(SYMBOL) {
+            depth = 1;
+            removed = true;
+        } else if directive.contains(SYMBOL) {
+            removed = true;
+        } else {
+            kept.push(line);
+        }
+    }
+    removed.then(|| {
+        let mut asm = kept.join("\n");
+        if !asm.is_empty() {
+            asm.push('\n');
+        }
+        asm
+    })
 }
 
 pub(crate) fn symbol_name<'a>(value: *mut llvm_sys::LLVMValue) -> &'a [u8] {
@@ -307,14 +925,281 @@ pub(crate) fn remove_attribute(function: *mut llvm_sys::LLVMValue, name: &str) {
     unsafe { LLVMRemoveEnumAttributeAtIndex(function, LLVMAttributeFunctionIndex, attr_kind) };
 }
 
+/// The function attributes reported by [`function_attributes`] besides string attributes. The C
+/// API can't map an attribute kind back to its name, so only the attributes that affect inlining
+/// and optimization are looked up.
+const REPORTED_ENUM_ATTRIBUTES: &[&str] = &[
+    "alwaysinline",
+    "cold",
+    "convergent",
+    "hot",
+    "inlinehint",
+    "minsize",
+    "mustprogress",
+    "naked",
+    "nofree",
+    "noinline",
+    "norecurse",
+    "noreturn",
+    "nosync",
+    "nounwind",
+    "optnone",
+    "optsize",
+    "willreturn",
+];
+
+/// Returns the name and the function attributes of every function of the module, except LLVM
+/// intrinsics. String attributes are formatted as `"key"="value"`.
+pub(crate) fn function_attributes(module: &LLVMModule<'_>) -> Vec<(String, Vec<String>)> {
+    module
+        .as_mut_ptr()
+        .functions_iter()
+        .filter(|&function| !symbol_name(function).starts_with(b"llvm."))
+        .map(|function| {
+            let mut attributes: Vec<String> = REPORTED_ENUM_ATTRIBUTES
+                .iter()
+                .filter(|name| {
+                    let kind = unsafe {
+                        LLVMGetEnumAttributeKindForName(name.as_ptr().cast(), name.len())
+                    };
+                    let attribute = unsafe {
+                        LLVMGetEnumAttributeAtIndex(function, LLVMAttributeFunctionIndex, kind)
+                    };
+                    !attribute.is_null()
+                })
+                .map(|name| (*name).to_owned())
+                .collect();
+
+            let count =
+                unsafe { LLVMGetAttributeCountAtIndex(function, LLVMAttributeFunctionIndex) };
+            let mut all = vec![ptr::null_mut(); count.try_into().unwrap()];
+            unsafe {
+                LLVMGetAttributesAtIndex(function, LLVMAttributeFunctionIndex, all.as_mut_ptr())
+            };
+            for attribute in all {
+                if unsafe { LLVMIsStringAttribute(attribute) } == 0 {
+                    continue;
+                }
+                let mut len = 0;
+                let kind = unsafe { LLVMGetStringAttributeKind(attribute, &mut len) };
+                let kind = unsafe { slice::from_raw_parts(kind.cast(), len.try_into().unwrap()) };
+                let mut len = 0;
+                let value = unsafe { LLVMGetStringAttributeValue(attribute, &mut len) };
+                let value = unsafe { slice::from_raw_parts(value.cast(), len.try_into().unwrap()) };
+                attributes.push(format!(
+                    "\"{}\"=\"{}\"",
+                    String::from_utf8_lossy(kind),
+                    String::from_utf8_lossy(value)
+                ));
+            }
+
+            (
+                String::from_utf8_lossy(symbol_name(function)).into_owned(),
+                attributes,
+            )
+        })
+        .collect()
+}
+
+/// The kind of a symbol seen by [`internalize`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SymbolKind {
+    Global,
+    Alias,
+    Function,
+}
+
+impl std::fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Global => "global",
+            Self::Alias => "alias",
+            Self::Function => "function",
+        })
+    }
+}
+
+/// The linkage decision [`internalize`] took for a symbol.
+#[derive(Debug)]
+pub(crate) struct InternalizedSymbol {
+    pub(crate) name: String,
+    pub(crate) kind: SymbolKind,
+    /// Whether the symbol matched an export.
+    pub(crate) exported: bool,
+    /// Whether the symbol was given internal linkage.
+    pub(crate) internalized: bool,
+    /// Whether the symbol is only declared. Undefined symbols are never internalized.
+    pub(crate) undefined: bool,
+}
+
+/// The composition of a module, as reported by [`module_statistics`].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ModuleStats {
+    /// The functions, including the declarations.
+    pub(crate) functions: usize,
+    pub(crate) defined_functions: usize,
+    pub(crate) globals: usize,
+    /// The instructions of all the defined functions.
+    pub(crate) instructions: usize,
+}
+
+/// Counts the functions, the defined functions, the global variables and the instructions of the
+/// module.
+pub(crate) fn module_statistics(module: &LLVMModule<'_>) -> ModuleStats {
+    let module = module.as_mut_ptr();
+    let mut stats = ModuleStats {
+        globals: module.globals_iter().count(),
+        ..ModuleStats::default()
+    };
+    for function in module.functions_iter() {
+        stats.functions += 1;
+        if unsafe { LLVMIsDeclaration(function) } == 0 {
+            stats.defined_functions += 1;
+        }
+        for block in function.basic_blocks_iter() {
+            stats.instructions += block.instructions_iter().count();
+        }
+    }
+    stats
+}
+
+/// A symbol of the module, as reported by [`symbol_table`].
+#[derive(Debug)]
+pub(crate) struct ModuleSymbol {
+    pub(crate) name: String,
+    pub(crate) kind: SymbolKind,
+    /// The linkage, spelled like in the LLVM IR.
+    pub(crate) linkage: &'static str,
+    /// The visibility, spelled like in the LLVM IR.
+    pub(crate) visibility: &'static str,
+    /// Whether the symbol is only declared.
+    pub(crate) declaration: bool,
+    /// Whether the symbol matched an export.
+    pub(crate) exported: bool,
+}
+
+/// Returns every function, global and alias of the module, except LLVM intrinsics, in this order.
+pub(crate) fn symbol_table(
+    module: &LLVMModule<'_>,
+    export_symbols: &ExportSymbols<'_>,
+) -> Vec<ModuleSymbol> {
+    let module = module.as_mut_ptr();
+    let functions = module
+        .functions_iter()
+        .map(|function| (function, SymbolKind::Function));
+    let globals = module
+        .globals_iter()
+        .map(|global| (global, SymbolKind::Global));
+    let aliases = module
+        .global_aliases_iter()
+        .map(|alias| (alias, SymbolKind::Alias));
+    functions
+        .chain(globals)
+        .chain(aliases)
+        .filter(|&(value, _)| !symbol_name(value).starts_with(b"llvm."))
+        .map(|(value, kind)| {
+            let name = symbol_name(value);
+            ModuleSymbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                kind,
+                linkage: linkage_name(unsafe { LLVMGetLinkage(value) }),
+                visibility: match unsafe { LLVMGetVisibility(value) } {
+                    LLVMVisibility::LLVMDefaultVisibility => "default",
+                    LLVMVisibility::LLVMHiddenVisibility => "hidden",
+                    LLVMVisibility::LLVMProtectedVisibility => "protected",
+                },
+                declaration: unsafe { LLVMIsDeclaration(value) } != 0,
+                exported: export_symbols.contains(name),
+            }
+        })
+        .collect()
+}
+
+fn linkage_name(linkage: LLVMLinkage) -> &'static str {
+    match linkage {
+        LLVMLinkage::LLVMExternalLinkage => "external",
+        LLVMLinkage::LLVMAvailableExternallyLinkage => "available_externally",
+        LLVMLinkage::LLVMLinkOnceAnyLinkage => "linkonce",
+        LLVMLinkage::LLVMLinkOnceODRLinkage => "linkonce_odr",
+        LLVMLinkage::LLVMLinkOnceODRAutoHideLinkage => "linkonce_odr_autohide",
+        LLVMLinkage::LLVMWeakAnyLinkage => "weak",
+        LLVMLinkage::LLVMWeakODRLinkage => "weak_odr",
+        LLVMLinkage::LLVMAppendingLinkage => "appending",
+        LLVMLinkage::LLVMInternalLinkage => "internal",
+        LLVMLinkage::LLVMPrivateLinkage => "private",
+        LLVMLinkage::LLVMDLLImportLinkage => "dllimport",
+        LLVMLinkage::LLVMDLLExportLinkage => "dllexport",
+        LLVMLinkage::LLVMExternalWeakLinkage => "extern_weak",
+        LLVMLinkage::LLVMGhostLinkage => "ghost",
+        LLVMLinkage::LLVMCommonLinkage => "common",
+        LLVMLinkage::LLVMLinkerPrivateLinkage => "linker_private",
+        LLVMLinkage::LLVMLinkerPrivateWeakLinkage => "linker_private_weak",
+    }
+}
+
 pub(crate) fn internalize(
     value: LLVMValueRef,
     name: &[u8],
-    export_symbols: &HashSet<Cow<'_, [u8]>>,
-) {
-    if !name.starts_with(b"llvm.") && !export_symbols.contains(name) {
+    kind: SymbolKind,
+    export_symbols: &ExportSymbols<'_>,
+    keep_symbols: &ExportSymbols<'_>,
+) -> InternalizedSymbol {
+    let exported = export_symbols.contains(name);
+    let undefined = unsafe { LLVMIsDeclaration(value) } != 0;
+    let internalized =
+        !name.starts_with(b"llvm.") && !exported && !undefined && !keep_symbols.contains(name);
+    if internalized {
         unsafe { LLVMSetLinkage(value, LLVMLinkage::LLVMInternalLinkage) };
         unsafe { LLVMSetVisibility(value, LLVMVisibility::LLVMDefaultVisibility) };
+    } else if undefined && !exported {
+        info!(
+            "not internalizing undefined {kind} {}",
+            String::from_utf8_lossy(name)
+        );
+    }
+    InternalizedSymbol {
+        name: String::from_utf8_lossy(name).into_owned(),
+        kind,
+        exported,
+        internalized,
+        undefined,
+    }
+}
+
+/// The source location of a diagnostic, which LLVM writes at the start of its description as
+/// `file:line:column: ` when the code it is about has debug info.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DiagnosticLocation {
+    pub(crate) file: String,
+    pub(crate) line: u32,
+    pub(crate) column: u32,
+}
+
+impl DiagnosticLocation {
+    /// Parses the location prefixing `message`. Returns `None` when there is no prefix, or when
+    /// LLVM wrote `<unknown>:0:0` because the code has no debug location.
+    pub(crate) fn parse(message: &str) -> Option<Self> {
+        message
+            .match_indices(": ")
+            .find_map(|(end, _)| {
+                let mut parts = message[..end].rsplitn(3, ':');
+                let column = parts.next()?.parse().ok()?;
+                let line = parts.next()?.parse().ok()?;
+                let file = parts.next()?;
+                (!file.is_empty() && !file.contains('\n')).then(|| Self {
+                    file: file.to_owned(),
+                    line,
+                    column,
+                })
+            })
+            .filter(|location| location.file != "<unknown>" && location.line != 0)
+    }
+}
+
+impl std::fmt::Display for DiagnosticLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { file, line, column } = self;
+        write!(f, "{file}:{line}:{column}")
     }
 }
 
@@ -323,6 +1208,7 @@ pub(crate) trait LLVMDiagnosticHandler {
         &mut self,
         severity: llvm_sys::LLVMDiagnosticSeverity,
         message: Cow<'_, str>,
+        location: Option<DiagnosticLocation>,
     );
 }
 
@@ -366,3 +1252,96 @@ impl Drop for Message {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_function_stack_sizes() {
+        let context = LLVMContext::new();
+        let module = context
+            .parse_ir(
+                c"define void @small() {
+entry:
+  %buf = alloca [2 x i32], i64 3
+  %byte = alloca i8
+  ret void
+}
+
+define void @huge() {
+entry:
+  %buf = alloca i8, i64 -1
+  %more = alloca i64
+  ret void
+}
+
+define internal void @local() {
+entry:
+  %buf = alloca i8
+  ret void
+}
+",
+                c"test.ll",
+            )
+            .unwrap();
+        assert_eq!(
+            function_stack_sizes(&module),
+            [("small".to_owned(), 25), ("huge".to_owned(), u64::MAX)]
+        );
+    }
+
+    #[test]
+    fn test_build_pass_pipeline() {
+        assert_eq!(
+            build_pass_pipeline(OptLevel::Default, &[], None, false).as_c_str(),
+            c"default<O2>,dce"
+        );
+        assert_eq!(
+            build_pass_pipeline(OptLevel::No, &[], None, false).as_c_str(),
+            c"default<O1>,dce"
+        );
+        assert_eq!(
+            build_pass_pipeline(
+                OptLevel::SizeMin,
+                &["dce".to_owned(), "loop-unroll".to_owned()],
+                None,
+                false
+            )
+            .as_c_str(),
+            c"default<Oz>"
+        );
+        assert_eq!(
+            build_pass_pipeline(OptLevel::Default, &[], Some("sroa,instcombine"), false).as_c_str(),
+            c"default<O2>,dce,sroa,instcombine"
+        );
+        assert_eq!(
+            build_pass_pipeline(OptLevel::Default, &[], Some("sroa"), true).as_c_str(),
+            c"default<O2>,dce,sroa,verify"
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_location() {
+        assert_eq!(
+            DiagnosticLocation::parse("src/lib.rs:12:5: in function foo: unsupported"),
+            Some(DiagnosticLocation {
+                file: "src/lib.rs".to_owned(),
+                line: 12,
+                column: 5,
+            })
+        );
+        assert_eq!(
+            DiagnosticLocation::parse("C:/src/lib.rs:3:0: error").map(|l| l.to_string()),
+            Some("C:/src/lib.rs:3:0".to_owned())
+        );
+        assert_eq!(
+            DiagnosticLocation::parse("<unknown>:0:0: in function foo"),
+            None
+        );
+        assert_eq!(
+            DiagnosticLocation::parse("linking module flags 'x': IDs have conflicting values"),
+            None
+        );
+    }
+}
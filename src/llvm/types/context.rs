@@ -8,14 +8,17 @@ use std::{
 };
 
 use llvm_sys::{
+    bit_reader::LLVMParseBitcodeInContext2,
     core::{
         LLVMContextCreate, LLVMContextDispose, LLVMContextSetDiagnosticHandler,
-        LLVMGetDiagInfoDescription, LLVMGetDiagInfoSeverity, LLVMModuleCreateWithNameInContext,
+        LLVMCreateMemoryBufferWithMemoryRange, LLVMDisposeMemoryBuffer, LLVMGetDiagInfoDescription,
+        LLVMGetDiagInfoSeverity, LLVMModuleCreateWithNameInContext,
     },
+    ir_reader::LLVMParseIRInContext,
     prelude::{LLVMContextRef, LLVMDiagnosticInfoRef},
 };
 
-use crate::llvm::{LLVMDiagnosticHandler, Message, types::module::LLVMModule};
+use crate::llvm::{DiagnosticLocation, LLVMDiagnosticHandler, Message, types::module::LLVMModule};
 
 pub(crate) struct LLVMContext {
     context: LLVMContextRef,
@@ -60,6 +63,81 @@ impl LLVMContext {
         })
     }
 
+    /// Parses a bitcode buffer into a new module. `buffer_name` names the buffer in the
+    /// diagnostics.
+    pub(crate) fn parse_bitcode<'ctx>(
+        &'ctx self,
+        buffer: &[u8],
+        buffer_name: &CStr,
+    ) -> Option<LLVMModule<'ctx>> {
+        let buffer = unsafe {
+            LLVMCreateMemoryBufferWithMemoryRange(
+                buffer.as_ptr().cast(),
+                buffer.len(),
+                buffer_name.as_ptr(),
+                0,
+            )
+        };
+        scopeguard::defer!(unsafe { LLVMDisposeMemoryBuffer(buffer) });
+
+        let mut module = ptr::null_mut();
+        if unsafe { LLVMParseBitcodeInContext2(self.context, buffer, &mut module) } != 0 {
+            return None;
+        }
+
+        Some(LLVMModule {
+            module,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Parses an LLVM IR buffer into a new module.
+    ///
+    /// The buffer must be null-terminated (hence `CStr`), because LLVM's IR parser
+    /// requires `RequiresNullTerminator=true` when creating the memory buffer.
+    /// See `getMemBuffer` with default `RequiresNullTerminator = true`:
+    /// https://github.com/llvm/llvm-project/blob/bde90624185ea2cead0a8d7231536e2625d78798/llvm/include/llvm/Support/MemoryBuffer.h#L134
+    /// Called by `LLVMParseIRInContext` follows this path parseIR => parseAssembly => parseAssemblyInto
+    /// Deep inside LLVM parser's they rely on the null termination for performance optimization.
+    /// LLVM's C API does not enforce this at the type level, so callers must guarantee the invariant themselves.
+    /// See the relevant code inside LLVM's parser:
+    /// https://github.com/llvm/llvm-project/blob/bde90624185ea2cead0a8d7231536e2625d78798/llvm/lib/AsmParser/Parser.cpp#L30
+    ///
+    /// Without the null terminator, LLVM hits an assertion in debug builds.
+    ///
+    /// `buffer_name` names the buffer in the parse errors, which point at its lines.
+    pub(crate) fn parse_ir<'ctx>(
+        &'ctx self,
+        buffer: &CStr,
+        buffer_name: &CStr,
+    ) -> Result<LLVMModule<'ctx>, String> {
+        let buffer = buffer.to_bytes();
+        let mem_buffer = unsafe {
+            LLVMCreateMemoryBufferWithMemoryRange(
+                buffer.as_ptr().cast(),
+                buffer.len(),
+                buffer_name.as_ptr(),
+                0,
+            )
+        };
+
+        let mut module = ptr::null_mut();
+        let (ret, message) = Message::with(|error_msg| unsafe {
+            // LLVMParseIRInContext takes ownership of mem_buffer, so we don't need to dispose of it ourselves.
+            // https://github.com/llvm/llvm-project/blob/00276b67d36a665119a6a7b39dbba69f45c44e58/llvm/lib/IRReader/IRReader.cpp#L122
+            LLVMParseIRInContext(self.context, mem_buffer, &mut module, error_msg)
+        });
+
+        if ret == 0 {
+            Ok(LLVMModule {
+                module,
+                _marker: PhantomData,
+            })
+        } else {
+            Err(message.as_string_lossy().to_string())
+        }
+    }
+
     /// Install a context-local diagnostic handler.
     pub(crate) fn set_diagnostic_handler<T>(&mut self, handler: T) -> InstalledDiagnosticHandler<T>
     where
@@ -129,6 +207,8 @@ extern "C" fn diagnostic_handler<T: LLVMDiagnosticHandler>(
     let message = Message {
         ptr: unsafe { LLVMGetDiagInfoDescription(info) },
     };
+    let message = message.as_string_lossy();
+    let location = DiagnosticLocation::parse(&message);
     let handler = handler.cast::<T>();
-    unsafe { &mut *handler }.handle_diagnostic(severity, message.as_string_lossy());
+    unsafe { &mut *handler }.handle_diagnostic(severity, message, location);
 }
@@ -133,6 +133,11 @@ impl DIType<'_> {
         }
     }
 
+    /// Returns the value of the type.
+    pub(crate) fn value_ref(&self) -> LLVMValueRef {
+        self.value_ref
+    }
+
     /// Returns the offset of the type in bits. This offset is used in case the
     /// type is a member of a composite type.
     pub(crate) fn offset_in_bits(&self) -> u64 {
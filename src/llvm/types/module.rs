@@ -1,17 +1,31 @@
-use std::{ffi::CStr, marker::PhantomData};
+use std::{ffi::CStr, marker::PhantomData, mem::ManuallyDrop, ptr};
 
 use libc::c_char;
 use llvm_sys::{
+    LLVMLinkage,
+    analysis::{LLVMVerifierFailureAction, LLVMVerifyModule},
     bit_writer::LLVMWriteBitcodeToFile,
     core::{
-        LLVMCreateMemoryBufferWithMemoryRangeCopy, LLVMDisposeMessage, LLVMDisposeModule,
-        LLVMGetTarget, LLVMPrintModuleToFile, LLVMPrintModuleToString,
+        LLVMCloneModule, LLVMCreateMemoryBufferWithMemoryRangeCopy, LLVMDisposeMessage,
+        LLVMDisposeModule, LLVMGetLinkage, LLVMGetTarget, LLVMInstructionEraseFromParent,
+        LLVMIsADbgInfoIntrinsic, LLVMPrintModuleToFile, LLVMPrintModuleToString,
+    },
+    debuginfo::{
+        LLVMGetSubprogram, LLVMInstructionGetDebugLoc, LLVMInstructionSetDebugLoc,
+        LLVMSetSubprogram, LLVMStripModuleDebugInfo,
     },
-    debuginfo::LLVMStripModuleDebugInfo,
     prelude::LLVMModuleRef,
 };
 
-use crate::llvm::{MemoryBuffer, Message, types::context::LLVMContext};
+use crate::{
+    LinkerError,
+    llvm::{
+        MemoryBuffer, Message,
+        iter::{IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions as _},
+        merge_modules,
+        types::context::LLVMContext,
+    },
+};
 
 pub(crate) struct LLVMModule<'ctx> {
     pub(super) module: LLVMModuleRef,
@@ -27,6 +41,14 @@ impl LLVMModule<'_> {
         self.module
     }
 
+    /// Returns a copy of the module in the same context.
+    pub(crate) fn clone_module(&self) -> Self {
+        Self {
+            module: unsafe { LLVMCloneModule(self.module) },
+            _marker: PhantomData,
+        }
+    }
+
     pub(crate) fn get_target(&self) -> *const c_char {
         unsafe { LLVMGetTarget(self.module) }
     }
@@ -45,7 +67,7 @@ impl LLVMModule<'_> {
         MemoryBuffer::new(buf)
     }
 
-    pub(crate) fn write_ir_to_path(&self, path: &CStr) -> Result<(), String> {
+    pub(crate) fn write_ir_to_path(&self, path: &CStr) -> Result<(), LinkerError> {
         let (ret, message) = unsafe {
             Message::with(|message| LLVMPrintModuleToFile(self.module, path.as_ptr(), message))
         };
@@ -53,7 +75,9 @@ impl LLVMModule<'_> {
         if ret == 0 {
             Ok(())
         } else {
-            Err(message.as_string_lossy().to_string())
+            Err(LinkerError::WriteIRError(
+                message.as_string_lossy().to_string(),
+            ))
         }
     }
 
@@ -79,6 +103,96 @@ impl LLVMModule<'_> {
         }
     }
 
+    /// Runs the LLVM module verifier, returning the description of the invalid constructs it
+    /// found, if any.
+    pub(crate) fn verify(&self) -> Result<(), String> {
+        let (ret, message) = unsafe {
+            Message::with(|message| {
+                LLVMVerifyModule(
+                    self.module,
+                    LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                    message,
+                )
+            })
+        };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(message.as_string_lossy().trim_end().to_owned())
+        }
+    }
+
+    /// Links `other` into this module, returning whether linking succeeded. LLVM destroys
+    /// `other` in both cases.
+    #[must_use]
+    pub(crate) fn link(&mut self, other: Self) -> bool {
+        let other = ManuallyDrop::new(other);
+        merge_modules(self.module, other.module)
+    }
+
+    /// strips the source locations of instructions, which the BPF backend emits as BTF line info,
+    /// while keeping the types and subprograms BTF is generated from. Debug intrinsics keep their
+    /// locations since they require one. Returns true if any location got stripped
+    pub(crate) fn strip_line_info(&mut self) -> bool {
+        let mut stripped = false;
+        for function in self.module.functions_iter() {
+            for basic_block in function.basic_blocks_iter() {
+                for instruction in basic_block.instructions_iter() {
+                    if !unsafe { LLVMIsADbgInfoIntrinsic(instruction) }.is_null()
+                        || unsafe { LLVMInstructionGetDebugLoc(instruction) }.is_null()
+                    {
+                        continue;
+                    }
+                    unsafe { LLVMInstructionSetDebugLoc(instruction, ptr::null_mut()) };
+                    stripped = true;
+                }
+            }
+        }
+        stripped
+    }
+
+    /// Detaches the subprogram of every function with internal or private linkage, along with the
+    /// source locations and debug intrinsics of its instructions, so that only the other functions
+    /// get BTF func info and line info. Returns the number of functions whose subprogram got
+    /// detached.
+    pub(crate) fn strip_internal_debug_info(&mut self) -> usize {
+        let mut stripped = 0;
+        for function in self.module.functions_iter() {
+            if !matches!(
+                unsafe { LLVMGetLinkage(function) },
+                LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+            ) || unsafe { LLVMGetSubprogram(function) }.is_null()
+            {
+                continue;
+            }
+            unsafe { LLVMSetSubprogram(function, ptr::null_mut()) };
+            let mut debug_intrinsics = Vec::new();
+            for basic_block in function.basic_blocks_iter() {
+                for instruction in basic_block.instructions_iter() {
+                    if unsafe { LLVMIsADbgInfoIntrinsic(instruction) }.is_null() {
+                        unsafe { LLVMInstructionSetDebugLoc(instruction, ptr::null_mut()) };
+                    } else {
+                        debug_intrinsics.push(instruction);
+                    }
+                }
+            }
+            for instruction in debug_intrinsics {
+                unsafe { LLVMInstructionEraseFromParent(instruction) };
+            }
+            stripped += 1;
+        }
+        stripped
+    }
+
+    /// Returns the number of functions with a subprogram.
+    pub(crate) fn subprogram_count(&self) -> usize {
+        self.module
+            .functions_iter()
+            .filter(|&function| !unsafe { LLVMGetSubprogram(function) }.is_null())
+            .count()
+    }
+
     /// strips debug information, returns true if DI got stripped
     pub(crate) fn strip_debug_info(&mut self) -> bool {
         unsafe { LLVMStripModuleDebugInfo(self.module) != 0 }
@@ -2,11 +2,15 @@ use std::ffi::CStr;
 
 use llvm_sys::target_machine::{
     LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetMachine,
-    LLVMDisposeTargetMachine, LLVMRelocMode, LLVMTargetMachineEmitToFile,
+    LLVMDisposeTargetMachine, LLVMGetTargetMachineCPU, LLVMGetTargetMachineFeatureString,
+    LLVMGetTargetMachineTriple, LLVMRelocMode, LLVMTargetMachineEmitToFile,
     LLVMTargetMachineEmitToMemoryBuffer, LLVMTargetMachineRef, LLVMTargetRef,
 };
 
-use crate::llvm::{MemoryBuffer, Message, types::module::LLVMModule};
+use crate::{
+    LinkerError,
+    llvm::{MemoryBuffer, Message, types::module::LLVMModule},
+};
 
 pub(crate) struct LLVMTargetMachine {
     target_machine: LLVMTargetMachineRef,
@@ -18,6 +22,9 @@ impl LLVMTargetMachine {
         triple: &CStr,
         cpu: &CStr,
         features: &CStr,
+        opt_level: LLVMCodeGenOptLevel,
+        reloc_mode: LLVMRelocMode,
+        code_model: LLVMCodeModel,
     ) -> Option<Self> {
         let tm = unsafe {
             LLVMCreateTargetMachine(
@@ -25,9 +32,9 @@ impl LLVMTargetMachine {
                 triple.as_ptr(),
                 cpu.as_ptr(),
                 features.as_ptr(),
-                LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
-                LLVMRelocMode::LLVMRelocDefault,
-                LLVMCodeModel::LLVMCodeModelDefault,
+                opt_level,
+                reloc_mode,
+                code_model,
             )
         };
         if tm.is_null() {
@@ -45,12 +52,38 @@ impl LLVMTargetMachine {
         self.target_machine
     }
 
+    /// Returns the target triple of the target machine.
+    pub(crate) fn triple(&self) -> String {
+        let triple = Message {
+            ptr: unsafe { LLVMGetTargetMachineTriple(self.target_machine) },
+        };
+        triple.as_string_lossy().into_owned()
+    }
+
+    /// Returns the CPU of the target machine, after [`Cpu::Auto`] was resolved.
+    ///
+    /// [`Cpu::Auto`]: crate::Cpu::Auto
+    pub(crate) fn cpu(&self) -> String {
+        let cpu = Message {
+            ptr: unsafe { LLVMGetTargetMachineCPU(self.target_machine) },
+        };
+        cpu.as_string_lossy().into_owned()
+    }
+
+    /// Returns the target features of the target machine.
+    pub(crate) fn features(&self) -> String {
+        let features = Message {
+            ptr: unsafe { LLVMGetTargetMachineFeatureString(self.target_machine) },
+        };
+        features.as_string_lossy().into_owned()
+    }
+
     pub(crate) fn emit_to_file(
         &self,
         module: &LLVMModule<'_>,
         path: &CStr,
         output_type: LLVMCodeGenFileType,
-    ) -> Result<(), String> {
+    ) -> Result<(), LinkerError> {
         let (ret, message) = unsafe {
             Message::with(|message| {
                 LLVMTargetMachineEmitToFile(
@@ -65,7 +98,9 @@ impl LLVMTargetMachine {
         if ret == 0 {
             Ok(())
         } else {
-            Err(message.as_string_lossy().to_string())
+            Err(LinkerError::CodegenError(
+                message.as_string_lossy().to_string(),
+            ))
         }
     }
 
@@ -73,7 +108,7 @@ impl LLVMTargetMachine {
         &self,
         module: &LLVMModule<'_>,
         output_type: LLVMCodeGenFileType,
-    ) -> Result<MemoryBuffer, String> {
+    ) -> Result<MemoryBuffer, LinkerError> {
         let mut out_buf = std::ptr::null_mut();
         let (ret, message) = Message::with(|message| unsafe {
             LLVMTargetMachineEmitToMemoryBuffer(
@@ -85,7 +120,9 @@ impl LLVMTargetMachine {
             )
         });
         if ret != 0 {
-            return Err(message.as_string_lossy().to_string());
+            return Err(LinkerError::CodegenError(
+                message.as_string_lossy().to_string(),
+            ));
         }
 
         Ok(MemoryBuffer::new(out_buf))
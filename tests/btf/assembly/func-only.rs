@@ -0,0 +1,30 @@
+// assembly-output: bpf-linker
+// no-prefer-dynamic
+// compile-flags: --crate-type bin -C link-arg=--emit=obj -C link-arg=--btf=func-only -C debuginfo=2
+
+#![no_std]
+#![no_main]
+
+pub struct Event {
+    pub pid: u32,
+    pub len: u32,
+}
+
+#[no_mangle]
+static EVENT: Event = Event { pid: 0, len: 0 };
+
+#[no_mangle]
+#[link_section = "uprobe/connect"]
+pub fn connect() -> u32 {
+    unsafe { core::ptr::read_volatile(&EVENT.pid) }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// Only the functions are kept, the types are dropped.
+// CHECK-NOT: <STRUCT> 'Event'
+// CHECK: <FUNC> 'connect' --> global
+// CHECK-NOT: <STRUCT> 'Event'
@@ -0,0 +1,31 @@
+// assembly-output: bpf-linker
+// no-prefer-dynamic
+// compile-flags: --crate-type bin -C link-arg=--emit=obj -C link-arg=--btf -C link-arg=--strip-line-info -C debuginfo=2
+
+#![no_std]
+#![no_main]
+
+pub struct Event {
+    pub pid: u32,
+    pub len: u32,
+}
+
+#[no_mangle]
+static EVENT: Event = Event { pid: 0, len: 0 };
+
+#[no_mangle]
+#[link_section = "uprobe/connect"]
+pub fn connect() -> u32 {
+    unsafe { core::ptr::read_volatile(&EVENT.pid) }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// Stripping the line info keeps the types and functions.
+// CHECK: <STRUCT> 'Event' sz:8 n:2
+// CHECK-NEXT: 'pid' off:0 --> [{{[0-9]+}}]
+// CHECK-NEXT: 'len' off:32 --> [{{[0-9]+}}]
+// CHECK: <FUNC> 'connect' --> global
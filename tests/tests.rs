@@ -1,13 +1,17 @@
 #![expect(unused_crate_dependencies, reason = "used in lib/bin")]
 
 use std::{
+    collections::HashMap,
     env,
-    ffi::{OsStr, OsString},
+    ffi::{CString, OsStr, OsString},
     fs,
+    io::Write as _,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output, Stdio},
 };
 
+use object::{Object as _, ObjectSection as _, ObjectSymbol as _};
+
 fn rustc_cmd() -> Command {
     Command::new(env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")))
 }
@@ -203,23 +207,91 @@ attributes #0 = {{ noinline nounwind optnone }}
     )
 }
 
-#[test]
-fn test_link_ir_files() {
-    let options = bpf_linker::LinkerOptions {
-        target: None,
-        cpu: bpf_linker::Cpu::Generic,
-        cpu_features: Default::default(),
+/// Returns an IR module defining `i32 name(i32)` for each of the given function names.
+fn create_test_ir_module(name: &str, functions: &[&str]) -> String {
+    let mut ir = format!(
+        r#"; ModuleID = '{name}'
+source_filename = "{name}"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+"#
+    );
+    for function in functions {
+        ir.push_str(&format!(
+            r#"
+define i32 @{function}(i32 %x) {{
+entry:
+  %result = add i32 %x, 1
+  ret i32 %result
+}}
+"#
+        ));
+    }
+    ir
+}
+
+/// Returns the value of the string field `key` of the JSON object printed on `line` with
+/// `--message-format=json`, or `None` if the field is missing or `null`.
+fn json_field(line: &str, key: &str) -> Option<String> {
+    let pattern = format!("\"{key}\":");
+    let start = line.find(&pattern)? + pattern.len();
+    let mut chars = line[start..].strip_prefix('"')?.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Returns the `define` line of `function` in the given textual IR, if any.
+fn find_definition<'a>(ir: &'a str, function: &str) -> Option<&'a str> {
+    let needle = format!("@{function}(");
+    ir.lines()
+        .find(|line| line.starts_with("define ") && line.contains(&needle))
+}
+
+/// Returns the options of a link without optimizations nor BTF, for the tests using the
+/// library.
+fn linker_options() -> bpf_linker::LinkerOptions {
+    bpf_linker::LinkerOptions {
         optimize: bpf_linker::OptLevel::No,
-        unroll_loops: false,
-        ignore_inline_never: false,
-        llvm_args: vec![],
-        disable_expand_memcpy_in_order: false,
-        disable_memory_builtins: false,
-        btf: false,
-        allow_bpf_trap: false,
-    };
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_unknown_llvm_args() {
+    for (arg, expected) in [
+        ("--not-a-real-flag", "unknown"),
+        ("-debug-only=isel", "unknown"),
+        ("unroll-threshold=10", "invalid"),
+    ] {
+        let result = bpf_linker::Linker::new(bpf_linker::LinkerOptions {
+            llvm_args: vec![CString::new(arg).unwrap()],
+            ..linker_options()
+        });
+        match (result, expected) {
+            (Err(bpf_linker::LinkerError::UnknownLlvmArg(a)), "unknown")
+            | (Err(bpf_linker::LinkerError::InvalidLlvmArg(a)), "invalid") => assert_eq!(a, arg),
+            (result, _) => panic!("{arg}: {:?}", result.err()),
+        }
+    }
+}
 
-    let linker = bpf_linker::Linker::new(options);
+#[test]
+fn test_link_ir_files() {
+    let linker = bpf_linker::Linker::new(linker_options()).unwrap();
 
     // Test 1: Valid IR should link successfully
     {
@@ -255,7 +327,3739 @@ fn test_link_ir_files() {
             ),
             // TODO(MSRV 1.91.0): peel away the `AsRef::<OsStr>::as_ref`.
             // See https://doc.rust-lang.org/stable/std/path/struct.PathBuf.html#impl-PartialEq%3Cstr%3E-for-PathBuf.
-            Err(bpf_linker::LinkerError::InvalidInputType(path)) if AsRef::<OsStr>::as_ref(&path) == "in_memory::corrupted.ll"
+            Err(bpf_linker::LinkerError::IRParseError(path, _)) if AsRef::<OsStr>::as_ref(&path) == "in_memory::corrupted.ll"
+        );
+    }
+
+    // Test 3: Failing to write the output is reported with a structured error
+    {
+        let ir_content = create_test_ir_content("unwritable");
+        let output = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join("no-such-directory")
+            .join("output");
+        let link = |output_type| {
+            linker.link_to_file(
+                [bpf_linker::LinkerInput::Buffer {
+                    name: "unwritable.ll",
+                    bytes: ir_content.as_bytes(),
+                }],
+                &output,
+                output_type,
+                ["test_unwritable"],
+            )
+        };
+
+        assert_matches::assert_matches!(
+            link(bpf_linker::OutputType::LlvmAssembly),
+            Err(bpf_linker::LinkerError::WriteIRError(_))
+        );
+        assert_matches::assert_matches!(
+            link(bpf_linker::OutputType::Object),
+            Err(bpf_linker::LinkerError::CodegenError(_))
+        );
+    }
+}
+
+/// Returns an empty directory for the given test under `CARGO_TARGET_TMPDIR`.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)
+            .unwrap_or_else(|err| panic!("could not remove '{}': {err}", dir.display()));
+    }
+    fs::create_dir_all(&dir)
+        .unwrap_or_else(|err| panic!("could not create '{}': {err}", dir.display()));
+    dir
+}
+
+/// Runs the `bpf-linker` binary with the given arguments.
+fn bpf_linker<I, S>(args: I) -> Output
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_bpf-linker"));
+    let _: &mut Command = cmd.args(args);
+    cmd.output()
+        .unwrap_or_else(|err| panic!("could not run {cmd:?}: {err}"))
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "bpf-linker failed with {:?}\nstdout: {}\nstderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_reloc_model() {
+    let dir = test_dir("reloc-model");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_content("reloc");
+    // A constant initialized with the address of a global needs a relocation, and goes to a
+    // section which depends on the relocation model.
+    ir.push_str(
+        r#"
+@test_value = global i32 1
+@test_table = constant ptr @test_value
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    for reloc_model in ["default", "static", "pic"] {
+        let output = dir.join(format!("{reloc_model}.o"));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_reloc,test_value,test_table"),
+            OsStr::new(&format!("--reloc-model={reloc_model}")),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        let object = fs::read(&output).unwrap();
+        assert!(object.starts_with(b"\x7fELF"), "{reloc_model}");
+    }
+
+    // Returns the name of the section and the offset of every relocation of an output. `default`
+    // leaves the model to the BPF target, so the explicit `static` and `pic` models are compared.
+    let relocations = |reloc_model: &str| {
+        let data = fs::read(dir.join(format!("{reloc_model}.o"))).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        object
+            .sections()
+            .flat_map(|section| {
+                let name = section.name().unwrap().to_owned();
+                section
+                    .relocations()
+                    .map(|(offset, _)| (name.clone(), offset))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    let static_relocations = relocations("static");
+    let pic_relocations = relocations("pic");
+    assert!(
+        static_relocations
+            .iter()
+            .any(|(section, _)| section == ".rodata"),
+        "{static_relocations:?}"
+    );
+    assert!(
+        pic_relocations
+            .iter()
+            .any(|(section, _)| section.starts_with(".data.rel.ro")),
+        "{pic_relocations:?}"
+    );
+    assert_ne!(static_relocations, pic_relocations);
+
+    let output = dir.join("relocation-model.o");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_reloc"),
+        OsStr::new("--relocation-model"),
+        OsStr::new("pic"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--reloc-model=ropi"),
+        OsStr::new("-o"),
+        dir.join("ropi.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("expected one of `default`, `static`, `pic`, `dynamic-no-pic`"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_cpu() {
+    const EM_BPF: u16 = 247;
+
+    // The class of the BPF_JMP32 instructions, which v3 introduced.
+    const BPF_JMP32: u8 = 0x06;
+
+    let dir = test_dir("cpu");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("cpu", &[]);
+    // A 32-bit compare, which v3 branches on with jmp32. The volatile store keeps the branch.
+    ir.push_str(
+        r#"
+define void @test_cpu(i32 %a, i32 %b, ptr %p) {
+entry:
+  %cmp = icmp ult i32 %a, %b
+  br i1 %cmp, label %then, label %exit
+
+then:
+  store volatile i32 1, ptr %p
+  br label %exit
+
+exit:
+  ret void
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let has_jmp32 = |cpu: &str| {
+        let data = fs::read(dir.join(format!("{cpu}.o"))).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        let text = object
+            .section_by_name(".text")
+            .unwrap()
+            .data()
+            .unwrap()
+            .to_vec();
+        text.chunks_exact(8).any(|insn| insn[0] & 0x07 == BPF_JMP32)
+    };
+
+    for cpu in ["generic", "v1", "v2", "v3", "v4"] {
+        let output = dir.join(format!("{cpu}.o"));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_cpu"),
+            OsStr::new(&format!("--cpu={cpu}")),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        let object = fs::read(&output).unwrap();
+        assert!(object.starts_with(b"\x7fELF"), "{cpu}");
+        let e_machine = u16::from_le_bytes(object[18..20].try_into().unwrap());
+        assert_eq!(e_machine, EM_BPF, "{cpu}");
+    }
+    assert!(!has_jmp32("v1"));
+    assert!(has_jmp32("v3"));
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--cpu=v9"),
+        OsStr::new("-o"),
+        dir.join("v9.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid CPU v9"), "{stderr}");
+}
+
+#[test]
+fn test_export_patterns() {
+    let dir = test_dir("export-patterns");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("patterns", &["test_a", "test_b", "other"]),
+    )
+    .unwrap();
+
+    for export in ["--export=test_*", "--export-regex=test_[ab]"] {
+        let output = dir.join("output.ll");
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new(export),
+            OsStr::new("--emit=llvm-ir"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        let ir = fs::read_to_string(&output).unwrap();
+        for function in ["test_a", "test_b"] {
+            let definition = find_definition(&ir, function)
+                .unwrap_or_else(|| panic!("{export}: {function} not found in {ir}"));
+            assert!(!definition.contains("internal"), "{export}: {definition}");
+        }
+        if let Some(definition) = find_definition(&ir, "other") {
+            assert!(definition.contains("internal"), "{export}: {definition}");
+        }
+    }
+}
+
+#[test]
+fn test_target_feature() {
+    let dir = test_dir("target-feature");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("feature")).unwrap();
+
+    let output = dir.join("output.o");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_feature"),
+        OsStr::new("--target-feature=+alu32"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+}
+
+#[test]
+fn test_code_model() {
+    let dir = test_dir("code-model");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        r#"; ModuleID = 'code_model'
+source_filename = "code_model"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+@big = global [16777216 x i8] zeroinitializer
+
+define i8 @test_code_model() {
+entry:
+  %ptr = getelementptr inbounds [16777216 x i8], ptr @big, i64 0, i64 16777215
+  %value = load i8, ptr %ptr
+  ret i8 %value
+}
+"#,
+    )
+    .unwrap();
+
+    for code_model in ["default", "small", "large"] {
+        let output = dir.join(format!("{code_model}.o"));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_code_model"),
+            OsStr::new(&format!("--code-model={code_model}")),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        assert!(
+            fs::read(&output).unwrap().starts_with(b"\x7fELF"),
+            "{code_model}"
+        );
+    }
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--code-model=huge"),
+        OsStr::new("-o"),
+        dir.join("huge.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid code model huge"), "{stderr}");
+}
+
+#[test]
+fn test_export_symbols_file() {
+    let dir = test_dir("export-symbols-file");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("export_file", &["test_a", "test_b", "other"]),
+    )
+    .unwrap();
+    let symbols = dir.join("symbols.txt");
+    fs::write(&symbols, "# programs\ntest_a\n\n").unwrap();
+
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export-symbols"),
+        symbols.as_os_str(),
+        OsStr::new("--export=test_b"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    for function in ["test_a", "test_b"] {
+        let definition = find_definition(&ir, function)
+            .unwrap_or_else(|| panic!("{function} not found in {ir}"));
+        assert!(!definition.contains("internal"), "{definition}");
+    }
+    if let Some(definition) = find_definition(&ir, "other") {
+        assert!(definition.contains("internal"), "{definition}");
+    }
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export-symbols"),
+        dir.join("missing.txt").as_os_str(),
+        OsStr::new("-o"),
+        dir.join("missing.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("missing.txt"), "{stderr}");
+}
+
+#[test]
+fn test_emit_asm() {
+    let dir = test_dir("emit-asm");
+    fs::write(dir.join("input.ll"), create_test_ir_content("emit_asm")).unwrap();
+
+    // Without `-o`, the output is named after the input with the extension of the emitted type.
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_bpf-linker"));
+    let _: &mut Command =
+        cmd.current_dir(&dir)
+            .args(["input.ll", "--emit=asm", "--export=test_emit_asm"]);
+    let output = cmd
+        .output()
+        .unwrap_or_else(|err| panic!("could not run {cmd:?}: {err}"));
+    assert_success(&output);
+
+    let asm = fs::read_to_string(dir.join("input.s")).unwrap();
+    assert!(asm.contains("test_emit_asm:"), "{asm}");
+    assert!(asm.lines().any(|line| line.trim() == "exit"), "{asm}");
+}
+
+#[test]
+fn test_undefined_exports() {
+    let dir = test_dir("undefined-exports");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("undefined")).unwrap();
+    let output = dir.join("output.o");
+    let link = |extra_args: &[&str]| {
+        bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_undefined,test_undefnied,test_missing"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(extra_args.iter().map(OsStr::new)),
+        )
+    };
+
+    let result = link(&[]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("exported symbols are not defined: test_missing, test_undefnied"),
+        "{stderr}"
+    );
+
+    let result = link(&["--allow-missing-exports", "--log-level=warn"]);
+    assert_success(&result);
+    let logs = String::from_utf8_lossy(&result.stderr);
+    for name in ["test_missing", "test_undefnied"] {
+        assert!(
+            logs.contains(&format!("exported symbol {name} is not defined")),
+            "{logs}"
+        );
+    }
+}
+
+#[test]
+fn test_codegen_opt_level() {
+    let dir = test_dir("codegen-opt-level");
+    let input = dir.join("input.ll");
+    // The branches keep values live across basic blocks, which the register allocator used at -O0
+    // spills to the stack.
+    fs::write(
+        &input,
+        r#"target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+define i64 @test_opt_level(i64 %x, i64 %y) {
+entry:
+  %cmp = icmp sgt i64 %x, %y
+  br i1 %cmp, label %greater, label %other
+
+greater:
+  %sum = add i64 %x, %y
+  br label %end
+
+other:
+  %diff = sub i64 %x, %y
+  br label %end
+
+end:
+  %value = phi i64 [ %sum, %greater ], [ %diff, %other ]
+  %result = mul i64 %value, %x
+  ret i64 %result
+}
+"#,
+    )
+    .unwrap();
+
+    let [o0, o3] = ["0", "3"].map(|level| {
+        let output = dir.join(format!("output-O{level}.s"));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_opt_level"),
+            OsStr::new("--emit=asm"),
+            OsStr::new(&format!("-O{level}")),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        fs::read_to_string(&output).unwrap()
+    });
+    assert_ne!(o0, o3);
+}
+
+#[test]
+fn test_emit_bitcode_round_trip() {
+    let dir = test_dir("emit-bitcode");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("bitcode")).unwrap();
+
+    let bitcode = dir.join("output.bc");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_bitcode"),
+        OsStr::new("--emit=llvm-bc"),
+        OsStr::new("-o"),
+        bitcode.as_os_str(),
+    ]));
+    assert!(fs::read(&bitcode).unwrap().starts_with(b"BC\xC0\xDE"));
+
+    // Feed the bitcode back to the linker, which parses it as a bitcode input.
+    let ir = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        bitcode.as_os_str(),
+        OsStr::new("--export=test_bitcode"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        ir.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&ir).unwrap();
+    let definition =
+        find_definition(&ir, "test_bitcode").unwrap_or_else(|| panic!("test_bitcode not in {ir}"));
+    assert!(!definition.contains("internal"), "{definition}");
+}
+
+#[test]
+fn test_depfile() {
+    let dir = test_dir("depfile");
+    let first = dir.join("first input.ll");
+    fs::write(&first, create_test_ir_content("first")).unwrap();
+    let second = dir.join("second.ll");
+    fs::write(&second, create_test_ir_content("second")).unwrap();
+
+    let output = dir.join("output.o");
+    let depfile = dir.join("output.d");
+    assert_success(&bpf_linker([
+        first.as_os_str(),
+        second.as_os_str(),
+        OsStr::new("--export=test_first,test_second"),
+        OsStr::new("--depfile"),
+        depfile.as_os_str(),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+
+    let escape = |path: &Path| path.display().to_string().replace(' ', r"\ ");
+    assert_eq!(
+        fs::read_to_string(&depfile).unwrap(),
+        format!(
+            "{}: {} {}\n",
+            escape(&output),
+            escape(&first),
+            escape(&second)
+        )
+    );
+}
+
+#[test]
+fn test_emit_bc_relink() {
+    let dir = test_dir("emit-bc");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("relink")).unwrap();
+
+    let bitcode = dir.join("stage1.bc");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_relink"),
+        OsStr::new("--emit=bc"),
+        OsStr::new("-o"),
+        bitcode.as_os_str(),
+    ]));
+
+    let object = dir.join("stage2.o");
+    assert_success(&bpf_linker([
+        bitcode.as_os_str(),
+        OsStr::new("--export=test_relink"),
+        OsStr::new("-o"),
+        object.as_os_str(),
+    ]));
+    assert!(fs::read(&object).unwrap().starts_with(b"\x7fELF"));
+}
+
+#[test]
+fn test_emit_ir_to() {
+    let dir = test_dir("emit-ir-to");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("emit_ir_to", &["test_a", "test_b", "other"]),
+    )
+    .unwrap();
+
+    let output = dir.join("output.o");
+    let ir = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_a,test_b"),
+        OsStr::new("--emit-ir-to"),
+        ir.as_os_str(),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+
+    let ir_text = fs::read_to_string(&ir).unwrap();
+    for line in ir_text.lines().filter(|line| line.starts_with("define ")) {
+        let exported = line.contains("@test_a(") || line.contains("@test_b(");
+        assert_eq!(!line.contains("internal"), exported, "{line}");
+    }
+
+    // The IR is valid: it can be linked again.
+    assert_success(&bpf_linker([
+        ir.as_os_str(),
+        OsStr::new("--export=test_a,test_b"),
+        OsStr::new("-o"),
+        dir.join("relinked.o").as_os_str(),
+    ]));
+}
+
+#[test]
+fn test_emit_multiple() {
+    let dir = test_dir("emit-multiple");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("emit_multiple")).unwrap();
+
+    let output = dir.join("out.o");
+    let bitcode = dir.join("bitcode").join("prog.bc");
+    fs::create_dir_all(bitcode.parent().unwrap()).unwrap();
+    let mut bitcode_arg = OsString::from("--emit=bc=");
+    bitcode_arg.push(&bitcode);
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_emit_multiple"),
+        OsStr::new("--emit=obj"),
+        OsStr::new("--emit=llvm-ir"),
+        bitcode_arg.as_os_str(),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+    let ir = fs::read_to_string(dir.join("out.ll")).unwrap();
+    assert!(ir.contains("define"), "{ir}");
+    assert!(fs::read(&bitcode).unwrap().starts_with(b"BC"));
+
+    let duplicate = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--emit=obj"),
+        OsStr::new("--emit=obj"),
+        OsStr::new("-o"),
+        dir.join("duplicate.o").as_os_str(),
+    ]);
+    assert!(!duplicate.status.success());
+    assert!(!dir.join("duplicate.o").exists());
+}
+
+#[test]
+fn test_dry_run() {
+    let dir = test_dir("dry-run");
+    let valid = dir.join("valid.ll");
+    fs::write(&valid, create_test_ir_content("dry_run")).unwrap();
+    let invalid = dir.join("invalid.ll");
+    fs::write(&invalid, "define void @broken( {\n").unwrap();
+    let depfile = dir.join("output.d");
+
+    let output = dir.join("valid.o");
+    assert_success(&bpf_linker([
+        valid.as_os_str(),
+        OsStr::new("--dry-run"),
+        OsStr::new("--export=test_dry_run"),
+        OsStr::new("--depfile"),
+        depfile.as_os_str(),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(!output.exists());
+    assert!(!depfile.exists());
+
+    let output = dir.join("invalid.o");
+    let failed = bpf_linker([
+        valid.as_os_str(),
+        invalid.as_os_str(),
+        OsStr::new("--dry-run"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]);
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(stderr.contains("invalid.ll"), "{stderr}");
+    assert!(!output.exists());
+}
+
+#[test]
+fn test_passes() {
+    let dir = test_dir("passes");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("passes")).unwrap();
+
+    let output = dir.join("output.o");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_passes"),
+        OsStr::new("--passes=default<O2>,sroa,dce"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+
+    let invalid = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_passes"),
+        OsStr::new("--passes=default<O2>,no-such-pass"),
+        OsStr::new("-o"),
+        dir.join("invalid.o").as_os_str(),
+    ]);
+    assert!(!invalid.status.success());
+    let stderr = String::from_utf8_lossy(&invalid.stderr);
+    assert!(stderr.contains("default<O2>,no-such-pass"), "{stderr}");
+
+    // `-O` would be ignored, so it can't be combined with a custom pipeline.
+    let conflict = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_passes"),
+        OsStr::new("--passes=default<O2>"),
+        OsStr::new("-O3"),
+        OsStr::new("-o"),
+        dir.join("conflict.o").as_os_str(),
+    ]);
+    assert!(!conflict.status.success());
+    let stderr = String::from_utf8_lossy(&conflict.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+
+    // An explicit `default<O2>` pipeline, or an empty one, behaves like `-O2`.
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        assert_success(&bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_passes"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        ));
+        fs::read_to_string(output).unwrap()
+    };
+    let expected = link(&["-O2"], "o2.ll");
+    assert_eq!(link(&["--passes=default<O2>"], "default-o2.ll"), expected);
+    assert_eq!(link(&["--passes="], "empty.ll"), expected);
+}
+
+#[test]
+fn test_extra_passes() {
+    let dir = test_dir("extra-passes");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("extra_passes")).unwrap();
+
+    let output = dir.join("output.o");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_extra_passes"),
+        OsStr::new("--extra-passes=instcombine"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+
+    let empty = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_extra_passes"),
+        OsStr::new("--extra-passes="),
+        OsStr::new("-o"),
+        dir.join("empty.o").as_os_str(),
+    ]);
+    assert!(!empty.status.success());
+}
+
+/// Returns an IR module with debug info defining the function `connect` and the global `EVENT`
+/// of type `Event`.
+fn create_test_ir_with_debug_info() -> String {
+    r#"; ModuleID = 'btf'
+source_filename = "btf"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+%Event = type { i32, i32 }
+
+@EVENT = global %Event zeroinitializer, align 4, !dbg !0
+
+define i32 @connect() #0 !dbg !12 {
+entry:
+  %pid = load volatile i32, ptr @EVENT, align 4, !dbg !15
+  ret i32 %pid, !dbg !15
+}
+
+attributes #0 = { noinline nounwind optnone }
+
+!llvm.dbg.cu = !{!2}
+!llvm.module.flags = !{!10, !11}
+
+!0 = !DIGlobalVariableExpression(var: !1, expr: !DIExpression())
+!1 = distinct !DIGlobalVariable(name: "EVENT", scope: !2, file: !3, line: 1, type: !5, isLocal: false, isDefinition: true)
+!2 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !3, producer: "test", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug, globals: !4)
+!3 = !DIFile(filename: "btf.rs", directory: "/tmp")
+!4 = !{!0}
+!5 = !DICompositeType(tag: DW_TAG_structure_type, name: "Event", file: !3, size: 64, align: 32, elements: !6, identifier: "Event")
+!6 = !{!7, !8}
+!7 = !DIDerivedType(tag: DW_TAG_member, name: "pid", scope: !5, file: !3, baseType: !9, size: 32, align: 32)
+!8 = !DIDerivedType(tag: DW_TAG_member, name: "len", scope: !5, file: !3, baseType: !9, size: 32, align: 32, offset: 32)
+!9 = !DIBasicType(name: "u32", size: 32, encoding: DW_ATE_unsigned)
+!10 = !{i32 7, !"Dwarf Version", i32 4}
+!11 = !{i32 2, !"Debug Info Version", i32 3}
+!12 = distinct !DISubprogram(name: "connect", scope: !3, file: !3, line: 3, type: !13, scopeLine: 3, flags: DIFlagPrototyped, spFlags: DISPFlagDefinition, unit: !2)
+!13 = !DISubroutineType(types: !14)
+!14 = !{!9}
+!15 = !DILocation(line: 4, column: 5, scope: !12)
+"#
+    .to_owned()
+}
+
+#[test]
+fn test_dump_btf() {
+    let dir = test_dir("dump-btf");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_with_debug_info()).unwrap();
+
+    let output = dir.join("output.o");
+    let btf = dir.join("output.btf");
+    let btf_ext = dir.join("output.btf.ext");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--btf"),
+        OsStr::new("--export=connect,EVENT"),
+        OsStr::new("--dump-btf"),
+        btf.as_os_str(),
+        OsStr::new("--dump-btf-ext"),
+        btf_ext.as_os_str(),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let data = fs::read(&output).unwrap();
+    let object = object::File::parse(data.as_slice()).unwrap();
+    for (name, path) in [(".BTF", &btf), (".BTF.ext", &btf_ext)] {
+        let contents = fs::read(path).unwrap();
+        // The little endian BTF magic, shared by both sections.
+        assert!(contents.starts_with(&[0x9f, 0xeb]), "{name}: {contents:?}");
+        let section = object.section_by_name(name).unwrap();
+        assert_eq!(section.data().unwrap(), contents.as_slice(), "{name}");
+    }
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--btf=none"),
+        OsStr::new("--export=connect,EVENT"),
+        OsStr::new("--dump-btf"),
+        dir.join("none.btf").as_os_str(),
+        OsStr::new("-o"),
+        dir.join("none.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no .BTF section found"), "{stderr}");
+}
+
+#[test]
+fn test_btf_modes() {
+    let dir = test_dir("btf-modes");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_with_debug_info()).unwrap();
+
+    // (mode, has .BTF, has .BTF.ext, has the `Event` type)
+    for (mode, btf, btf_ext, types) in [
+        ("--btf", true, true, true),
+        ("--btf=full", true, true, true),
+        ("--btf=func-only", true, true, false),
+        ("--btf=none", false, false, false),
+    ] {
+        let output = dir.join(format!("{}.o", mode.trim_start_matches('-')));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new(mode),
+            OsStr::new("--export=connect,EVENT"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+
+        let data = fs::read(&output).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        let section = |name| {
+            object
+                .section_by_name(name)
+                .map(|section| section.data().unwrap().to_vec())
+        };
+        let btf_section = section(".BTF");
+        assert_eq!(btf_section.is_some(), btf, "{mode}: .BTF");
+        assert_eq!(section(".BTF.ext").is_some(), btf_ext, "{mode}: .BTF.ext");
+        let has_event = btf_section.is_some_and(|btf| btf.windows(6).any(|w| w == b"Event\0"));
+        assert_eq!(has_event, types, "{mode}: Event type");
+    }
+
+    for flag in ["--strip-debug", "-g0"] {
+        let output = dir.join(format!("{}.o", flag.trim_start_matches('-')));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--btf"),
+            OsStr::new(flag),
+            OsStr::new("--export=connect,EVENT"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        let data = fs::read(&output).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        let debug_sections = object
+            .sections()
+            .filter_map(|section| section.name().ok())
+            .filter(|name| name.starts_with(".debug_") || name.starts_with(".BTF"))
+            .collect::<Vec<_>>();
+        assert!(debug_sections.is_empty(), "{flag}: {debug_sections:?}");
+        let with_btf = fs::metadata(dir.join("btf.o")).unwrap().len();
+        assert!(
+            (data.len() as u64) < with_btf,
+            "{flag}: {} >= {with_btf}",
+            data.len()
+        );
+    }
+
+    // The BTF can't be dumped once stripped.
+    let conflict = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--btf"),
+        OsStr::new("--strip-debug"),
+        OsStr::new("--dump-btf"),
+        dir.join("stripped.btf").as_os_str(),
+        OsStr::new("-o"),
+        dir.join("conflict.o").as_os_str(),
+    ]);
+    assert!(!conflict.status.success());
+    let stderr = String::from_utf8_lossy(&conflict.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+
+    let invalid = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--btf=types-only"),
+        OsStr::new("-o"),
+        dir.join("invalid.o").as_os_str(),
+    ]);
+    assert!(!invalid.status.success());
+}
+
+/// Returns the number of records in the func info of the `.BTF.ext` section of a little endian
+/// object file.
+fn btf_ext_func_info_count(object: &object::File<'_>) -> u32 {
+    let btf_ext = object.section_by_name(".BTF.ext").unwrap().data().unwrap();
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(btf_ext[offset..offset + 4].try_into().unwrap());
+    // struct btf_ext_header { magic: u16, version: u8, flags: u8, hdr_len: u32,
+    //                         func_info_off: u32, func_info_len: u32, ... }
+    let hdr_len = u32_at(4) as usize;
+    let func_info = hdr_len + u32_at(8) as usize;
+    let func_info_end = func_info + u32_at(12) as usize;
+    // The func info starts with the record size, followed by, for each section, the section name
+    // offset, the number of records and the records.
+    let rec_size = u32_at(func_info) as usize;
+    let mut offset = func_info + 4;
+    let mut count = 0;
+    while offset < func_info_end {
+        let num_info = u32_at(offset + 4);
+        count += num_info;
+        offset += 8 + num_info as usize * rec_size;
+    }
+    count
+}
+
+#[test]
+fn test_strip_internal_debug_info() {
+    let dir = test_dir("strip-internal-debug-info");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        r#"; ModuleID = 'helper'
+source_filename = "helper"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+define i32 @connect() #0 !dbg !4 {
+entry:
+  %result = call i32 @helper(i32 1), !dbg !8
+  ret i32 %result, !dbg !8
+}
+
+define i32 @helper(i32 %x) #0 !dbg !9 {
+entry:
+  %result = add i32 %x, 1, !dbg !10
+  ret i32 %result, !dbg !10
+}
+
+attributes #0 = { noinline nounwind optnone }
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2, !3}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "test", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)
+!1 = !DIFile(filename: "helper.rs", directory: "/tmp")
+!2 = !{i32 7, !"Dwarf Version", i32 4}
+!3 = !{i32 2, !"Debug Info Version", i32 3}
+!4 = distinct !DISubprogram(name: "connect", scope: !1, file: !1, line: 1, type: !5, scopeLine: 1, spFlags: DISPFlagDefinition, unit: !0)
+!5 = !DISubroutineType(types: !6)
+!6 = !{!7}
+!7 = !DIBasicType(name: "u32", size: 32, encoding: DW_ATE_unsigned)
+!8 = !DILocation(line: 2, column: 5, scope: !4)
+!9 = distinct !DISubprogram(name: "helper", scope: !1, file: !1, line: 5, type: !11, scopeLine: 5, spFlags: DISPFlagLocalToUnit | DISPFlagDefinition, unit: !0)
+!10 = !DILocation(line: 6, column: 5, scope: !9)
+!11 = !DISubroutineType(types: !12)
+!12 = !{!7, !7}
+"#,
+    )
+    .unwrap();
+
+    for (strip, func_info_count) in [(false, 2), (true, 1)] {
+        let output = dir.join(format!("strip-{strip}.o"));
+        let mut args = vec![
+            input.as_os_str(),
+            OsStr::new("--btf"),
+            OsStr::new("--export=connect"),
+            // Otherwise the call to the helper is folded into a constant.
+            OsStr::new("--keep-optnone"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ];
+        if strip {
+            args.push(OsStr::new("--strip-internal-debug-info"));
+        }
+        assert_success(&bpf_linker(args));
+
+        let data = fs::read(&output).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        assert_eq!(
+            btf_ext_func_info_count(&object),
+            func_info_count,
+            "strip: {strip}"
         );
+        let btf = object.section_by_name(".BTF").unwrap().data().unwrap();
+        let has_helper = btf.windows(7).any(|w| w == b"helper\0");
+        assert_eq!(has_helper, !strip, "strip: {strip}");
+    }
+}
+
+#[test]
+fn test_disable_pass() {
+    let dir = test_dir("disable-pass");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("disable_pass")).unwrap();
+
+    let output = dir.join("output.o");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_disable_pass"),
+        OsStr::new("--disable-pass=loop-unroll"),
+        OsStr::new("--disable-pass=dce"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+
+    let invalid = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_disable_pass"),
+        OsStr::new("--disable-pass=inline"),
+        OsStr::new("-o"),
+        dir.join("invalid.o").as_os_str(),
+    ]);
+    assert!(!invalid.status.success());
+    let stderr = String::from_utf8_lossy(&invalid.stderr);
+    assert!(
+        stderr.contains("pass `inline` can't be disabled"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_no_loop_unroll() {
+    let dir = test_dir("no-loop-unroll");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("no_loop_unroll", &[]);
+    // The volatile store keeps the loop from being folded away without unrolling it.
+    ir.push_str(
+        r#"
+define void @test_loop(ptr %p) {
+entry:
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+  store volatile i32 %i, ptr %p
+  %next = add i32 %i, 1
+  %done = icmp eq i32 %next, 4
+  br i1 %done, label %exit, label %loop
+
+exit:
+  ret void
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        let mut command = vec![input.as_os_str(), OsStr::new("--export=test_loop")];
+        command.extend(args.iter().map(OsStr::new));
+        command.extend([
+            OsStr::new("--emit=llvm-ir"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]);
+        assert_success(&bpf_linker(command));
+        fs::read_to_string(output).unwrap()
+    };
+
+    let ir = link(&[], "unrolled.ll");
+    assert!(!ir.contains("phi "), "{ir}");
+    let ir = link(
+        &["--no-loop-unroll", "--no-vectorize", "--no-slp-vectorize"],
+        "loop.ll",
+    );
+    assert!(ir.contains("phi "), "{ir}");
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--no-loop-unroll"),
+        OsStr::new("--unroll-loops"),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+#[test]
+fn test_library_link_to_file() {
+    let dir = test_dir("library-link-to-file");
+    // Module flags with the `Warning` (2) behavior and conflicting values make LLVM warn while
+    // linking the second input.
+    let mut inputs = Vec::new();
+    for (name, value) in [("first", 1), ("second", 2)] {
+        let mut ir = create_test_ir_module(name, &[&format!("test_{name}")]);
+        ir.push_str(&format!(
+            "\n!llvm.module.flags = !{{!0}}\n!0 = !{{i32 2, !\"bpf-linker-test\", i32 {value}}}\n"
+        ));
+        let input = dir.join(format!("{name}.ll"));
+        fs::write(&input, ir).unwrap();
+        inputs.push(input);
+    }
+
+    let linker = bpf_linker::Linker::new(bpf_linker::LinkerOptions {
+        target: Some(c"bpfel".to_owned()),
+        optimize: bpf_linker::OptLevel::Default,
+        ..linker_options()
+    })
+    .unwrap();
+    let output = dir.join("output.o");
+    linker
+        .link_to_file(
+            inputs
+                .iter()
+                .map(|input| bpf_linker::LinkerInput::new_from_file(input)),
+            &output,
+            bpf_linker::OutputType::Object,
+            ["test_first", "test_second"],
+        )
+        .unwrap();
+
+    let data = fs::read(&output).unwrap();
+    let object = object::File::parse(data.as_slice()).unwrap();
+    assert!(object.is_little_endian());
+    let mut exported: Vec<_> = object
+        .symbols()
+        .filter(|symbol| symbol.is_global() && symbol.is_definition())
+        .map(|symbol| symbol.name().unwrap().to_owned())
+        .collect();
+    exported.sort();
+    assert_eq!(exported, ["test_first", "test_second"]);
+
+    // The diagnostics of LLVM are available to the caller.
+    let warnings = linker.warnings();
+    assert_eq!(warnings.len(), 1, "{warnings:?}");
+    assert!(warnings[0].contains("bpf-linker-test"), "{warnings:?}");
+    assert_eq!(linker.diagnostic_counts().warnings, 1);
+    assert!(linker.errors().is_empty());
+
+    // Linking the same definitions twice fails, and LLVM's error is available too.
+    assert_matches::assert_matches!(
+        linker.link_to_file(
+            [
+                bpf_linker::LinkerInput::new_from_file(&inputs[0]),
+                bpf_linker::LinkerInput::new_from_file(&inputs[0]),
+            ],
+            dir.join("duplicate.o"),
+            bpf_linker::OutputType::Object,
+            ["test_first"],
+        ),
+        Err(bpf_linker::LinkerError::LinkModuleError(path, _)) if path == inputs[0]
+    );
+    assert_eq!(linker.last_stage(), Some(bpf_linker::Stage::Link));
+    assert!(
+        linker
+            .errors()
+            .iter()
+            .any(|error| error.contains("symbol multiply defined")),
+        "{:?}",
+        linker.errors()
+    );
+}
+
+#[test]
+fn test_library_link_buffers() {
+    let dir = test_dir("library-link-buffers");
+    let first = create_test_ir_module("first", &["test_first"]);
+    let second = create_test_ir_module("second", &["test_second"]);
+
+    let linker = bpf_linker::Linker::new(linker_options()).unwrap();
+    let output = dir.join("output.o");
+    linker
+        .link_to_file(
+            [
+                ("first.ll", first.as_bytes()),
+                ("second.ll", second.as_bytes()),
+            ],
+            &output,
+            bpf_linker::OutputType::Object,
+            ["test_first", "test_second"],
+        )
+        .unwrap();
+    let data = fs::read(&output).unwrap();
+    let object = object::File::parse(data.as_slice()).unwrap();
+    let mut exported: Vec<_> = object
+        .symbols()
+        .filter(|symbol| symbol.is_global() && symbol.is_definition())
+        .map(|symbol| symbol.name().unwrap().to_owned())
+        .collect();
+    exported.sort();
+    assert_eq!(exported, ["test_first", "test_second"]);
+
+    // The parse errors point at the name of the buffer.
+    assert_matches::assert_matches!(
+        linker.link_to_file(
+            [("broken.ll", b"define i32 @broken( {\n".as_slice())],
+            dir.join("broken.o"),
+            bpf_linker::OutputType::Object,
+            Vec::<&str>::new(),
+        ),
+        Err(bpf_linker::LinkerError::IRParseError(_, message)) if message.starts_with("broken.ll:1:")
+    );
+}
+
+#[test]
+fn test_link_error_message() {
+    let dir = test_dir("link-error-message");
+    let first = dir.join("first.ll");
+    let second = dir.join("second.ll");
+    // Both modules define `test_link_error`.
+    fs::write(&first, create_test_ir_content("link_error")).unwrap();
+    fs::write(&second, create_test_ir_content("link_error")).unwrap();
+
+    let output = bpf_linker([
+        first.as_os_str(),
+        second.as_os_str(),
+        OsStr::new("--export=test_link_error"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("failure linking module"), "{stderr}");
+    assert!(stderr.contains("second.ll"), "{stderr}");
+    assert!(
+        stderr.contains("Linking globals named 'test_link_error': symbol multiply defined!"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_cpu_and_features() {
+    let dir = test_dir("cpu-and-features");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("cpu_and_features")).unwrap();
+
+    for (i, args) in [
+        ["--cpu=v3", "--features=+alu32"],
+        ["--cpu=probe", "--cpu-features=-alu32"],
+    ]
+    .iter()
+    .enumerate()
+    {
+        let output = dir.join(format!("output-{i}.o"));
+        assert_success(&bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_cpu_and_features"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        ));
+        assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"));
+    }
+}
+
+#[test]
+fn test_cpu_auto() {
+    let dir = test_dir("cpu-auto");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("cpu_auto")).unwrap();
+
+    for (release, target, cpu) in [
+        ("5.15.0-122-generic", "bpfel", "v3"),
+        ("6.8.0", "bpfel", "v4"),
+        ("4.9.337", "bpfel", "v1"),
+        ("6.8.0", "bpfeb", "generic"),
+    ] {
+        let output = dir.join(format!("{release}-{target}.o"));
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_bpf-linker"));
+        let _: &mut Command = cmd
+            .env(bpf_linker::KERNEL_RELEASE_ENV, release)
+            .arg(&input)
+            .args([
+                "--export=test_cpu_auto",
+                "--cpu=auto",
+                "--log-level=info",
+                &format!("--target={target}"),
+                "-o",
+            ])
+            .arg(&output);
+        let output = cmd
+            .output()
+            .unwrap_or_else(|err| panic!("could not run {cmd:?}: {err}"));
+        assert_success(&output);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("resolved CPU auto to {cpu}")),
+            "{release} {target}: {stderr}"
+        );
+    }
+}
+
+#[test]
+fn test_duplicate_symbols() {
+    let dir = test_dir("duplicate-symbols");
+    let first = dir.join("first.ll");
+    let second = dir.join("second.ll");
+    let shared = dir.join("shared.ll");
+    // `first.ll` and `second.ll` both define `test_duplicate`.
+    fs::write(&first, create_test_ir_content("duplicate")).unwrap();
+    fs::write(&second, create_test_ir_content("duplicate")).unwrap();
+    // `linkonce_odr` definitions, like the ones of generic functions, don't conflict.
+    fs::write(
+        &shared,
+        create_test_ir_content("duplicate")
+            .replace("@test_duplicate", "@test_shared")
+            .replace("define i32", "define linkonce_odr i32"),
+    )
+    .unwrap();
+    let link = |inputs: &[&Path], args: &[&str]| {
+        bpf_linker(
+            inputs
+                .iter()
+                .map(|input| input.as_os_str())
+                .chain(args.iter().map(OsStr::new))
+                .chain([
+                    OsStr::new("--export=test_duplicate,test_shared"),
+                    OsStr::new("--allow-missing-exports"),
+                    OsStr::new("-o"),
+                    dir.join("output.o").as_os_str(),
+                ]),
+        )
+    };
+    let message = format!(
+        "duplicate definition of test_duplicate in {} and {}",
+        first.display(),
+        second.display()
+    );
+
+    // By default, the duplicate is reported as a warning before LLVM fails linking.
+    let result = link(&[&first, &second], &["--log-level=warn"]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains(&message), "{stderr}");
+    assert!(stderr.contains("symbol multiply defined"), "{stderr}");
+
+    let result = link(&[&first, &second], &["--no-duplicate-symbols"]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains(&message), "{stderr}");
+    assert!(!stderr.contains("symbol multiply defined"), "{stderr}");
+
+    let result = link(
+        &[&first, &shared, &shared],
+        &["--no-duplicate-symbols", "--log-level=warn"],
+    );
+    assert_success(&result);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(!stderr.contains("duplicate definition"), "{stderr}");
+}
+
+#[test]
+fn test_print_symbols() {
+    let dir = test_dir("print-symbols");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        r#"; ModuleID = 'print_symbols'
+source_filename = "print_symbols"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+define i32 @test_program(i32 %x) #0 {
+entry:
+  %result = call i32 @test_helper(i32 %x)
+  ret i32 %result
+}
+
+define i32 @test_helper(i32 %x) #0 {
+entry:
+  %result = add i32 %x, 1
+  ret i32 %result
+}
+
+attributes #0 = { noinline nounwind optnone }
+"#,
+    )
+    .unwrap();
+
+    // `test_helper` is internalized since it isn't exported, so only `test_program` is printed.
+    let check = |table: &str| {
+        assert_eq!(table, "test_program external default\n");
+    };
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_program"),
+        OsStr::new("--print-symbols"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    check(&String::from_utf8_lossy(&output.stdout));
+
+    let table = dir.join("symbols.txt");
+    let mut print_symbols = OsString::from("--print-symbols=");
+    print_symbols.push(&table);
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_program"),
+        print_symbols.as_os_str(),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]));
+    check(&fs::read_to_string(&table).unwrap());
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_program"),
+        OsStr::new("--print-symbols"),
+        OsStr::new("--output-format=json"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with('['), "{stdout}");
+    assert!(
+        stdout.contains(
+            r#"{"name": "test_program", "kind": "function", "linkage": "external", "visibility": "default", "defined": true, "exported": true}"#
+        ),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("test_helper"), "{stdout}");
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_program"),
+        OsStr::new("--print-symbols"),
+        OsStr::new("-o"),
+        OsStr::new("-"),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("`--print-symbols` needs a path when the output is written to stdout"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_archive_input() {
+    let dir = test_dir("archive-input");
+    let ir = dir.join("first.ll");
+    fs::write(&ir, create_test_ir_content("archive_first")).unwrap();
+    let bitcode = dir.join("first.bc");
+    assert_success(&bpf_linker([
+        ir.as_os_str(),
+        OsStr::new("--export=test_archive_first"),
+        OsStr::new("--emit=llvm-bc"),
+        OsStr::new("-o"),
+        bitcode.as_os_str(),
+    ]));
+
+    // An archive like the rlibs produced by rustc: a bitcode member, an IR member and a member
+    // which is neither and is ignored.
+    let archive = dir.join("libarchive.rlib");
+    let mut builder = ar::Builder::new(fs::File::create(&archive).unwrap());
+    for (name, data) in [
+        ("first.bc", fs::read(&bitcode).unwrap()),
+        (
+            "second.ll",
+            create_test_ir_content("archive_second").into_bytes(),
+        ),
+        ("lib.rmeta", b"rust metadata".to_vec()),
+    ] {
+        let header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+        builder.append(&header, data.as_slice()).unwrap();
+    }
+    drop(builder);
+
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        archive.as_os_str(),
+        OsStr::new("--export=test_archive_first,test_archive_second"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    for function in ["test_archive_first", "test_archive_second"] {
+        assert!(find_definition(&ir, function).is_some(), "{function}: {ir}");
+    }
+}
+
+#[test]
+fn test_llvm_ar_archive_input() {
+    let Some(llvm_ar) = which::which_re(regex::Regex::new(r"^llvm-ar(-\d+)?$").unwrap())
+        .unwrap()
+        .next()
+    else {
+        eprintln!("skipping test_llvm_ar_archive_input: llvm-ar not found");
+        return;
+    };
+
+    let dir = test_dir("llvm-ar-archive-input");
+    let mut members = Vec::new();
+    for name in ["first", "second"] {
+        let ir = dir.join(format!("{name}.ll"));
+        fs::write(&ir, create_test_ir_content(&format!("llvm_ar_{name}"))).unwrap();
+        let bitcode = dir.join(format!("{name}.bc"));
+        assert_success(&bpf_linker([
+            ir.as_os_str(),
+            OsStr::new(&format!("--export=test_llvm_ar_{name}")),
+            OsStr::new("--emit=llvm-bc"),
+            OsStr::new("-o"),
+            bitcode.as_os_str(),
+        ]));
+        members.push(bitcode);
+    }
+    fs::write(dir.join("notes.txt"), "not bitcode").unwrap();
+    members.push(dir.join("notes.txt"));
+
+    let archive = dir.join("libbitcode.a");
+    let output = Command::new(&llvm_ar)
+        .arg("rcs")
+        .arg(&archive)
+        .args(&members)
+        .output()
+        .unwrap();
+    assert_success(&output);
+
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        archive.as_os_str(),
+        OsStr::new("--export=test_llvm_ar_first,test_llvm_ar_second"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    for function in ["test_llvm_ar_first", "test_llvm_ar_second"] {
+        assert!(find_definition(&ir, function).is_some(), "{function}: {ir}");
+    }
+}
+
+#[test]
+fn test_strict_triple() {
+    let dir = test_dir("strict-triple");
+    let bpf = dir.join("bpf.ll");
+    fs::write(&bpf, create_test_ir_module("bpf", &["test_bpf"])).unwrap();
+    let host_ir = create_test_ir_module("host", &["test_host"])
+        .replace("bpfel-unknown-none", "x86_64-unknown-linux-gnu");
+    let host = dir.join("host.ll");
+    fs::write(&host, &host_ir).unwrap();
+
+    let output = bpf_linker([
+        bpf.as_os_str(),
+        host.as_os_str(),
+        OsStr::new("--export=test_bpf,test_host"),
+        OsStr::new("--log-level=warn"),
+        OsStr::new("-o"),
+        dir.join("warn.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("targets x86_64-unknown-linux-gnu, expected a bpf target"),
+        "{stderr}"
+    );
+
+    // A host module in an archive, like an rlib built for the host.
+    let archive = dir.join("libhost.rlib");
+    let mut builder = ar::Builder::new(fs::File::create(&archive).unwrap());
+    let header = ar::Header::new(b"host.ll".to_vec(), host_ir.len() as u64);
+    builder.append(&header, host_ir.as_bytes()).unwrap();
+    drop(builder);
+
+    let output = bpf_linker([
+        bpf.as_os_str(),
+        archive.as_os_str(),
+        OsStr::new("--export=test_bpf,test_host"),
+        OsStr::new("--strict-triple"),
+        OsStr::new("-o"),
+        dir.join("strict.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("host.ll targets x86_64-unknown-linux-gnu, expected a bpf target"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_print_eliminated() {
+    let dir = test_dir("print-eliminated");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("print_eliminated", &["test_kept", "test_unused"]),
+    )
+    .unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_kept"),
+        OsStr::new("--print-eliminated"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    assert!(lines.contains(&"removed: test_unused"), "{stderr}");
+    assert!(
+        !lines.iter().any(|line| line.ends_with(": test_kept")),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_fatal_warnings() {
+    let dir = test_dir("fatal-warnings");
+    // Module flags with the `Warning` (2) behavior and conflicting values make LLVM warn while
+    // linking, once per flag.
+    let mut inputs = Vec::new();
+    for (name, value) in [("first", 1), ("second", 2)] {
+        let mut ir = create_test_ir_module(name, &[&format!("test_{name}")]);
+        ir.push_str(&format!(
+            "\n!llvm.module.flags = !{{!0, !1}}\n!0 = !{{i32 2, !\"bpf-linker-test\", i32 {value}}}\n!1 = !{{i32 2, !\"bpf-linker-other\", i32 {value}}}\n"
+        ));
+        let input = dir.join(format!("{name}.ll"));
+        fs::write(&input, ir).unwrap();
+        inputs.push(input);
+    }
+    let args = |output: &str, fatal_warnings: bool| {
+        let mut args = vec![
+            inputs[0].clone().into_os_string(),
+            inputs[1].clone().into_os_string(),
+            OsString::from("--export=test_first,test_second"),
+            OsString::from("-o"),
+            dir.join(output).into_os_string(),
+        ];
+        if fatal_warnings {
+            args.push(OsString::from("--fatal-warnings"));
+        }
+        args
+    };
+
+    assert_success(&bpf_linker(args("output.o", false)));
+
+    let mut summary_args = args("summary.o", false);
+    summary_args.push(OsString::from("--diagnostics-summary"));
+    let output = bpf_linker(summary_args);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("LLVM diagnostics: errors: 0, warnings: 2,"),
+        "{stderr}"
+    );
+
+    let mut json_args = args("json.o", false);
+    json_args.push(OsString::from("--message-format=json"));
+    let output = bpf_linker(json_args);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.lines().all(|line| line.starts_with('{')), "{stderr}");
+    let warnings = stderr
+        .lines()
+        .filter(|line| json_field(line, "level").as_deref() == Some("warning"))
+        .collect::<Vec<_>>();
+    assert_eq!(warnings.len(), 2, "{stderr}");
+    assert!(
+        warnings.iter().any(|line| json_field(line, "message")
+            .unwrap()
+            .contains("bpf-linker-test")),
+        "{stderr}"
+    );
+    // The flags conflict when linking the second input.
+    for line in warnings {
+        assert_eq!(
+            json_field(line, "file"),
+            Some(inputs[1].display().to_string()),
+            "{line}"
+        );
+        assert_eq!(json_field(line, "stage").as_deref(), Some("link"), "{line}");
+    }
+
+    let output = bpf_linker(args("fatal.o", true));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("LLVM issued diagnostic with warning severity"),
+        "{stderr}"
+    );
+    // All the warnings are reported, not just the first one.
+    assert!(stderr.contains("bpf-linker-test"), "{stderr}");
+    assert!(stderr.contains("bpf-linker-other"), "{stderr}");
+}
+
+#[test]
+fn test_export_all() {
+    let dir = test_dir("export-all");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("export_all", &["test_a", "test_b", "other"]),
+    )
+    .unwrap();
+
+    let global_functions = |args: &[&OsStr], output: &Path| {
+        let mut args = args.to_vec();
+        args.extend([input.as_os_str(), OsStr::new("-o"), output.as_os_str()]);
+        assert_success(&bpf_linker(args));
+        let data = fs::read(output).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        let mut names: Vec<_> = object
+            .symbols()
+            .filter(|symbol| symbol.is_global() && symbol.is_definition())
+            .map(|symbol| symbol.name().unwrap().to_owned())
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        global_functions(&[OsStr::new("--export=test_a")], &dir.join("export.o")),
+        ["test_a"]
+    );
+    assert_eq!(
+        global_functions(&[OsStr::new("--export-all")], &dir.join("export-all.o")),
+        ["other", "test_a", "test_b"]
+    );
+    assert_eq!(
+        global_functions(
+            &[OsStr::new("--no-internalize")],
+            &dir.join("no-internalize.o")
+        ),
+        ["other", "test_a", "test_b"]
+    );
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--no-internalize"),
+        OsStr::new("--export=test_a"),
+        OsStr::new("-o"),
+        dir.join("conflict.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+#[test]
+fn test_keep_symbols() {
+    let dir = test_dir("keep-symbols");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module(
+            "keep_symbols",
+            &["test_exported", "kept_a", "kept_b", "other"],
+        ),
+    )
+    .unwrap();
+
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_exported"),
+        OsStr::new("--keep-symbols=kept_*,missing"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(output).unwrap();
+    for function in ["test_exported", "kept_a", "kept_b"] {
+        let definition = find_definition(&ir, function).unwrap_or_else(|| panic!("{ir}"));
+        assert!(!definition.contains("internal"), "{definition}");
+    }
+    // Not exported nor kept, the unused function is internalized and removed.
+    assert!(find_definition(&ir, "other").is_none(), "{ir}");
+}
+
+#[test]
+fn test_verify() {
+    let dir = test_dir("verify");
+    let input = dir.join("input.ll");
+    // The parser accepts the use of %b before its definition but the verifier doesn't.
+    fs::write(
+        &input,
+        r#"; ModuleID = 'verify'
+source_filename = "verify"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+define i32 @test_verify(i32 %x) {
+entry:
+  %a = add i32 %b, 1
+  %b = add i32 %x, 1
+  ret i32 %a
+}
+"#,
+    )
+    .unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_verify"),
+        OsStr::new("--verify"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("the linked module is invalid after linking"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Instruction does not dominate all uses!"),
+        "{stderr}"
+    );
+
+    let input = dir.join("valid.ll");
+    fs::write(&input, create_test_ir_content("verify")).unwrap();
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_verify"),
+        OsStr::new("--verify"),
+        OsStr::new("-o"),
+        dir.join("valid.o").as_os_str(),
+    ]));
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_verify"),
+        OsStr::new("--verify-module"),
+        OsStr::new("--passes=default<O2>,sroa,instcombine,dce"),
+        OsStr::new("-o"),
+        dir.join("passes.o").as_os_str(),
+    ]));
+
+    // A module flag without a value is linked and skipped by the passes, but the `verify` pass
+    // appended to the pipeline rejects it.
+    let input = dir.join("invalid-flag.ll");
+    let mut ir = create_test_ir_module("verify", &["test_verify"]);
+    ir.push_str("\n!llvm.module.flags = !{!0}\n!0 = !{i32 1, !\"bpf-linker-test\"}\n");
+    fs::write(&input, ir).unwrap();
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_verify"),
+        OsStr::new("--verify-module"),
+        OsStr::new("--passes=globaldce"),
+        OsStr::new("-o"),
+        dir.join("invalid-flag.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("incorrect number of operands in module flag"),
+        "{stderr}"
+    );
+    assert!(!dir.join("invalid-flag.o").exists());
+}
+
+#[test]
+fn test_time_link() {
+    let dir = test_dir("time-link");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("time_link")).unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_time_link"),
+        OsStr::new("--time-link"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let timings: HashMap<_, f64> = stderr
+        .lines()
+        .skip_while(|line| !line.starts_with("STAGE"))
+        .skip(1)
+        .map(|line| {
+            let (stage, time) = line.split_once(' ').unwrap();
+            (stage, time.trim().parse().unwrap())
+        })
+        .collect();
+
+    let stages = [
+        "parse",
+        "extract-bitcode",
+        "link",
+        "sanitize-debug-info",
+        "optimize",
+        "codegen",
+    ];
+    for stage in stages {
+        assert!(timings.contains_key(stage), "{stage}: {stderr}");
+    }
+    let sum: f64 = stages.iter().map(|stage| timings[stage]).sum();
+    // The table rounds every time to the microsecond.
+    assert!(sum <= timings["total"] + 0.01, "{stderr}");
+}
+
+#[test]
+fn test_llvm_args() {
+    let dir = test_dir("llvm-args");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("llvm_args")).unwrap();
+    let link = |llvm_args: &str| {
+        bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_llvm_args"),
+            OsStr::new(&format!("--llvm-args={llvm_args}")),
+            OsStr::new("-o"),
+            dir.join("output.o").as_os_str(),
+        ])
+    };
+
+    // Options given more than once, including the ones passed by the linker, are deduplicated.
+    assert_success(&link(
+        "--bpf-expand-memcpy-in-order,--unroll-threshold=10,--unroll-threshold=20",
+    ));
+
+    let output = link("unroll-threshold=10");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("invalid LLVM argument `unroll-threshold=10`"),
+        "{stderr}"
+    );
+
+    // Unknown options are rejected before LLVM parses them.
+    let output = link("--not-a-real-flag");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("unknown LLVM option `--not-a-real-flag`"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_strip_unused_globals() {
+    let dir = test_dir("strip-unused-globals");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        r#"; ModuleID = 'strip_unused_globals'
+source_filename = "strip_unused_globals"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+@USED = global i32 1
+@UNUSED = global i32 2
+@REFERENCED_BY_UNUSED = global i32 3
+@UNUSED_POINTER = global ptr @REFERENCED_BY_UNUSED
+
+define i32 @test_strip_unused_globals() {
+entry:
+  %value = load i32, ptr @USED
+  ret i32 %value
+}
+"#,
+    )
+    .unwrap();
+
+    // Keep the globals external so that the optimization passes don't delete them.
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        assert_success(&bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export-all"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        ));
+        fs::read_to_string(output).unwrap()
+    };
+    let globals = [
+        "@USED =",
+        "@UNUSED =",
+        "@REFERENCED_BY_UNUSED =",
+        "@UNUSED_POINTER =",
+    ];
+
+    let ir = link(&[], "output.ll");
+    for global in globals {
+        assert!(ir.contains(global), "{global}: {ir}");
+    }
+
+    let ir = link(&["--strip-unused-globals"], "stripped.ll");
+    assert!(ir.contains("@USED ="), "{ir}");
+    for global in &globals[1..] {
+        assert!(!ir.contains(global), "{global}: {ir}");
+    }
+
+    // Exported globals are kept.
+    let ir = link(
+        &["--strip-unused-globals", "--export=UNUSED"],
+        "exported.ll",
+    );
+    assert!(ir.contains("@UNUSED ="), "{ir}");
+}
+
+#[test]
+fn test_endian() {
+    let dir = test_dir("endian");
+    let bpf_ir = create_test_ir_content("endian").replace("bpfel-unknown-none", "bpf");
+    let bpf = dir.join("bpf.ll");
+    fs::write(&bpf, &bpf_ir).unwrap();
+
+    for (endian, ei_data) in [("little", 1), ("big", 2)] {
+        let output = dir.join(format!("{endian}.o"));
+        assert_success(&bpf_linker([
+            bpf.as_os_str(),
+            OsStr::new("--export=test_endian"),
+            OsStr::new(&format!("--endian={endian}")),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        let object = fs::read(&output).unwrap();
+        assert!(object.starts_with(b"\x7fELF"));
+        assert_eq!(object[5], ei_data, "{endian}");
+    }
+
+    let bpfel = dir.join("bpfel.ll");
+    fs::write(&bpfel, create_test_ir_content("endian")).unwrap();
+    let output = bpf_linker([
+        bpfel.as_os_str(),
+        OsStr::new("--export=test_endian"),
+        OsStr::new("--endian=big"),
+        OsStr::new("-o"),
+        dir.join("mismatch.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("bpfel.ll targets bpfel-unknown-none, which doesn't have the endianness of the target bpfeb"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_time_passes_and_stats() {
+    let dir = test_dir("time-passes-and-stats");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("time_passes")).unwrap();
+
+    for flag in ["--time-passes", "--stats"] {
+        let output = dir.join(format!("{flag}.o"));
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_time_passes"),
+            OsStr::new(flag),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        assert!(fs::read(&output).unwrap().starts_with(b"\x7fELF"), "{flag}");
+    }
+}
+
+#[test]
+fn test_disable_dce() {
+    let dir = test_dir("disable-dce");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("disable_dce", &["test_disable_dce", "unused_helper"]),
+    )
+    .unwrap();
+
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        assert_success(&bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_disable_dce"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        ));
+        fs::read_to_string(output).unwrap()
+    };
+
+    let ir = link(&[], "output.ll");
+    assert!(find_definition(&ir, "test_disable_dce").is_some(), "{ir}");
+    assert_eq!(find_definition(&ir, "unused_helper"), None, "{ir}");
+
+    // The function stays internal, but isn't deleted.
+    let ir = link(&["--disable-dce"], "disable-dce.ll");
+    assert!(find_definition(&ir, "test_disable_dce").is_some(), "{ir}");
+    let helper = find_definition(&ir, "unused_helper");
+    assert!(
+        helper.is_some_and(|helper| helper.contains("internal")),
+        "{ir}"
+    );
+}
+
+#[test]
+fn test_unroll_loops() {
+    let dir = test_dir("unroll-loops");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("unroll_loops", &[]);
+    // The volatile stores keep the loops from being folded away without unrolling them.
+    for (function, trip_count) in [("test_small_loop", 4), ("test_big_loop", 64)] {
+        ir.push_str(&format!(
+            r#"
+define void @{function}(ptr %p) {{
+entry:
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+  store volatile i32 %i, ptr %p
+  %next = add i32 %i, 1
+  %done = icmp eq i32 %next, {trip_count}
+  br i1 %done, label %exit, label %loop
+
+exit:
+  ret void
+}}
+"#
+        ));
+    }
+    fs::write(&input, ir).unwrap();
+
+    let link = |unroll_loops: &str, output: &str| {
+        let output = dir.join(output);
+        let result = bpf_linker([
+            input.as_os_str(),
+            OsStr::new(unroll_loops),
+            OsStr::new("--export=test_small_loop,test_big_loop"),
+            OsStr::new("--emit=llvm-ir"),
+            OsStr::new("--log-level=warn"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]);
+        assert_success(&result);
+        let ir = fs::read_to_string(output).unwrap();
+        let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+        (ir, stderr)
+    };
+
+    // The small loop is fully unrolled with the limit, the big one only partially.
+    let (ir, stderr) = link("--unroll-loops=8", "max-count.ll");
+    let small = ir
+        .split("define")
+        .find(|function| function.contains("@test_small_loop("))
+        .unwrap();
+    assert!(!small.contains("phi"), "{ir}");
+    assert_eq!(small.matches("store volatile").count(), 4, "{ir}");
+    assert!(!stderr.contains("test_small_loop"), "{stderr}");
+    assert!(
+        stderr.contains(
+            "function test_big_loop still contains a loop after unrolling at most 8 times"
+        ),
+        "{stderr}"
+    );
+
+    // Without a limit, both loops are fully unrolled.
+    let (ir, stderr) = link("--unroll-loops", "unlimited.ll");
+    assert!(!ir.contains("phi"), "{ir}");
+    assert!(!stderr.contains("still contains a loop"), "{stderr}");
+
+    let invalid = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--unroll-loops=0"),
+        OsStr::new("-o"),
+        dir.join("invalid.o").as_os_str(),
+    ]);
+    assert!(!invalid.status.success());
+}
+
+#[test]
+fn test_target_triple() {
+    let dir = test_dir("target-triple");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("target_triple")).unwrap();
+
+    let link = |target: &str, output: &str| {
+        let output = dir.join(output);
+        let result = bpf_linker([
+            input.as_os_str(),
+            OsStr::new(target),
+            OsStr::new("--export=test_target_triple"),
+            OsStr::new("--log-level=warn"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]);
+        assert_success(&result);
+        assert!(fs::read(output).unwrap().starts_with(b"\x7fELF"));
+        String::from_utf8_lossy(&result.stderr).into_owned()
+    };
+
+    // The input is built for `bpfel-unknown-none`.
+    let stderr = link("--target-triple=bpfel-unknown-none", "same.o");
+    assert!(!stderr.contains("overriding the target"), "{stderr}");
+    let stderr = link("--target-triple=bpf", "override.o");
+    assert!(
+        stderr.contains("overriding the target bpfel-unknown-none of the inputs with bpf"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_inline_threshold() {
+    let dir = test_dir("inline-threshold");
+    let input = dir.join("input.ll");
+    // The helper is called twice and is too big to be inlined with the default threshold.
+    let stores = "  store volatile i32 1, ptr %p\n".repeat(200);
+    let mut ir = create_test_ir_module("inline_threshold", &[]);
+    ir.push_str(&format!(
+        r#"
+define void @helper(ptr %p) {{
+entry:
+{stores}  ret void
+}}
+
+define void @test_inline_threshold(ptr %p) {{
+entry:
+  call void @helper(ptr %p)
+  call void @helper(ptr %p)
+  ret void
+}}
+"#
+    ));
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        assert_success(&bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_inline_threshold"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        ));
+        let ir = fs::read_to_string(output).unwrap();
+        ir.lines()
+            .filter(|line| line.starts_with("define "))
+            .count()
+    };
+
+    assert_eq!(link(&[], "default.ll"), 2);
+    assert_eq!(link(&["--inline-threshold=100000"], "raised.ll"), 1);
+    assert_eq!(
+        link(
+            &["--inline-threshold=100000", "--ignore-inline-never"],
+            "ignore-inline-never.ll"
+        ),
+        1
+    );
+}
+
+#[test]
+fn test_incremental() {
+    let dir = test_dir("incremental");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("incremental")).unwrap();
+    let cache = dir.join("cache");
+
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        let result = bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_incremental"),
+                OsStr::new("--incremental"),
+                cache.as_os_str(),
+                OsStr::new("--log-level=info"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        );
+        assert_success(&result);
+        let stderr = String::from_utf8_lossy(&result.stderr).into_owned();
+        (fs::read(output).unwrap(), stderr)
+    };
+    let cache_entries = |extension: &str| {
+        fs::read_dir(&cache)
+            .unwrap()
+            .filter(|entry| {
+                entry.as_ref().unwrap().path().extension() == Some(OsStr::new(extension))
+            })
+            .count()
+    };
+
+    let (first, stderr) = link(&[], "first.o");
+    assert!(!stderr.contains("cached in"), "{stderr}");
+    assert_eq!((cache_entries("bc"), cache_entries("o")), (1, 1));
+    let object = fs::read_dir(&cache)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some(OsStr::new("o")))
+        .unwrap();
+
+    let (second, stderr) = link(&[], "second.o");
+    assert!(
+        stderr.contains("using the optimized module cached in"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("copying the object cached in"), "{stderr}");
+    assert_eq!(first, second);
+
+    // The optimization level is part of the cache key.
+    let (_, stderr) = link(&["-O1"], "o1.o");
+    assert!(!stderr.contains("cached in"), "{stderr}");
+    assert_eq!((cache_entries("bc"), cache_entries("o")), (2, 2));
+
+    // So is the CPU.
+    let (_, stderr) = link(&["--cpu=v1"], "v1.o");
+    assert!(!stderr.contains("cached in"), "{stderr}");
+    assert_eq!((cache_entries("bc"), cache_entries("o")), (3, 3));
+
+    // Options which only report on the link are not.
+    let (_, stderr) = link(&["--print-stats"], "stats.o");
+    assert!(stderr.contains("copying the object cached in"), "{stderr}");
+
+    // Failing to write an entry, here because a directory is in the way, only warns.
+    fs::remove_file(&object).unwrap();
+    fs::create_dir(&object).unwrap();
+    fs::write(object.join("in-the-way"), "").unwrap();
+    let (third, stderr) = link(&[], "third.o");
+    assert!(
+        stderr.contains("failed to write the cache entry"),
+        "{stderr}"
+    );
+    assert_eq!(first, third);
+    assert_eq!(cache_entries("tmp"), 0);
+}
+
+#[test]
+fn test_print_stats() {
+    let dir = test_dir("print-stats");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("print_stats", &["test_kept", "test_unused"]),
+    )
+    .unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_kept"),
+        OsStr::new("--print-stats"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let row = |stat: &str| -> (usize, usize) {
+        let line = stderr
+            .lines()
+            .find_map(|line| line.strip_prefix(stat)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("missing {stat} row: {stderr}"));
+        let counts: Vec<usize> = line
+            .split_whitespace()
+            .map(|count| count.parse().unwrap())
+            .collect();
+        match counts.as_slice() {
+            [pre_opt, post_opt] => (*pre_opt, *post_opt),
+            counts => panic!("unexpected {stat} row {counts:?}: {stderr}"),
+        }
+    };
+    assert_eq!(row("defined functions"), (2, 1), "{stderr}");
+    let (pre_opt, post_opt) = row("instructions");
+    assert!(post_opt < pre_opt, "{stderr}");
+    let _: (usize, usize) = row("globals");
+}
+
+#[test]
+fn test_embedded_bitcode_object() {
+    let Some(clang) = which::which_re(regex::Regex::new(r"^clang(-\d+)?$").unwrap())
+        .unwrap()
+        .next()
+    else {
+        eprintln!("skipping test_embedded_bitcode_object: clang not found");
+        return;
+    };
+
+    let dir = test_dir("embedded-bitcode-object");
+    let source = dir.join("input.c");
+    fs::write(
+        &source,
+        "int test_embedded_bitcode(int x) { return x + 1; }\n",
+    )
+    .unwrap();
+    let compile = |object: &Path, args: &[&str]| {
+        let output = Command::new(&clang)
+            .args(["-target", "bpf", "-O2", "-c"])
+            .args(args)
+            .arg("-o")
+            .arg(object)
+            .arg(&source)
+            .output()
+            .unwrap();
+        assert_success(&output);
+    };
+
+    let embedded = dir.join("embedded.o");
+    compile(&embedded, &["-fembed-bitcode"]);
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        embedded.as_os_str(),
+        OsStr::new("--export=test_embedded_bitcode"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(output).unwrap();
+    assert!(
+        find_definition(&ir, "test_embedded_bitcode").is_some(),
+        "{ir}"
+    );
+
+    let plain = dir.join("plain.o");
+    compile(&plain, &[]);
+    let output = bpf_linker([
+        plain.as_os_str(),
+        OsStr::new("--export=test_embedded_bitcode"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no bitcode section found in"), "{stderr}");
+    assert!(stderr.contains("-fembed-bitcode"), "{stderr}");
+}
+
+#[test]
+fn test_emit_list() {
+    let dir = test_dir("emit-list");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("emit_list")).unwrap();
+
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_emit_list"),
+        OsStr::new("--emit=obj,llvm-ir"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]));
+    let object = fs::read(dir.join("output.o")).unwrap();
+    let object = object::File::parse(object.as_slice()).unwrap();
+    assert!(
+        object
+            .symbols()
+            .any(|symbol| symbol.name() == Ok("test_emit_list"))
+    );
+    let ir = fs::read_to_string(dir.join("output.ll")).unwrap();
+    assert!(find_definition(&ir, "test_emit_list").is_some(), "{ir}");
+
+    // A failing output doesn't prevent writing the others, but fails the link.
+    let missing = dir.join("missing").join("output.ll");
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_emit_list"),
+        OsStr::new(&format!("--emit=llvm-ir={},bc", missing.display())),
+        OsStr::new("-o"),
+        dir.join("failing.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    assert!(!missing.exists());
+    assert!(dir.join("failing.bc").exists());
+}
+
+#[test]
+fn test_remarks_filter() {
+    let dir = test_dir("remarks-filter");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("remarks_filter", &["small_kept", "small_dropped"]);
+    ir.push_str(
+        r#"
+define i32 @test_remarks_filter(i32 %x) {
+entry:
+  %kept = call i32 @small_kept(i32 %x)
+  %dropped = call i32 @small_dropped(i32 %kept)
+  ret i32 %dropped
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_remarks_filter"),
+        OsStr::new("--llvm-args=-pass-remarks=inline"),
+        OsStr::new("--remarks-filter=small_kept"),
+        OsStr::new("--log-level=info"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let remarks: Vec<_> = stderr
+        .lines()
+        .filter(|line| line.contains("remark: "))
+        .collect();
+    assert!(
+        remarks.iter().any(|line| line.contains("small_kept")),
+        "{stderr}"
+    );
+    assert!(
+        !remarks.iter().any(|line| line.contains("small_dropped")),
+        "{stderr}"
+    );
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--remarks-filter=("),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid remarks filter `(`"), "{stderr}");
+}
+
+#[test]
+fn test_stack_size_limit() {
+    let dir = test_dir("stack-size-limit");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("stack_size_limit", &[]);
+    ir.push_str(
+        r#"
+define void @test_stack_size_limit(i64 %i) {
+entry:
+  %buf = alloca [600 x i8]
+  %p = getelementptr [600 x i8], ptr %buf, i64 0, i64 %i
+  store volatile i8 1, ptr %p
+  ret void
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str]| {
+        bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_stack_size_limit"),
+                OsStr::new("--log-level=warn"),
+                OsStr::new("-o"),
+                dir.join("output.o").as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        )
+    };
+
+    let output = link(&[]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "function test_stack_size_limit uses an estimated 600 bytes of stack, more than the limit of 512 bytes"
+        ),
+        "{stderr}"
+    );
+
+    let output = link(&["--stack-size-limit=1024"]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("bytes of stack"), "{stderr}");
+
+    let output = link(&["--stack-size-limit-error"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "functions use more than 512 bytes of stack: test_stack_size_limit (600 bytes)"
+        ),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_max_insns() {
+    let dir = test_dir("max-insns");
+    let input = dir.join("input.ll");
+    let stores = "  store volatile i32 1, ptr %p\n".repeat(50);
+    let mut ir = create_test_ir_module("max_insns", &[]);
+    ir.push_str(&format!(
+        r#"
+define internal void @helper(ptr %p) noinline {{
+entry:
+{stores}  ret void
+}}
+
+define void @test_max_insns(ptr %p) {{
+entry:
+{stores}  call void @helper(ptr %p)
+  ret void
+}}
+"#
+    ));
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str]| {
+        bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_max_insns"),
+                OsStr::new("-o"),
+                dir.join("output.o").as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        )
+    };
+
+    let output = link(&["--print-insn-count", "--max-insns=1000"]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // The stores of the helper are attributed to the exported function calling it.
+    let count: usize = stderr
+        .lines()
+        .find_map(|line| line.strip_suffix(" test_max_insns"))
+        .unwrap_or_else(|| panic!("{stderr}"))
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(count > 100, "{stderr}");
+    assert!(
+        !stderr.lines().any(|line| line.ends_with(" helper")),
+        "{stderr}"
+    );
+
+    fs::remove_file(dir.join("output.o")).unwrap();
+    let output = link(&["--max-instructions=50"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("functions have more than 50 instructions: test_max_insns ("),
+        "{stderr}"
+    );
+    // The limit is checked before generating code.
+    assert!(!dir.join("output.o").exists());
+}
+
+#[test]
+fn test_undefined_calls() {
+    let dir = test_dir("undefined-calls");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("undefined_calls", &[]);
+    ir.push_str(
+        r#"
+declare ptr @bpf_map_lookup_elem(ptr, ptr)
+declare i32 @missing(i32)
+
+define ptr @test_helper(ptr %map, ptr %key) {
+entry:
+  %value = call ptr @bpf_map_lookup_elem(ptr %map, ptr %key)
+  ret ptr %value
+}
+
+define i32 @test_missing(i32 %x) {
+entry:
+  %y = call i32 @missing(i32 %x)
+  ret i32 %y
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str]| {
+        bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("-o"),
+                dir.join("output.o").as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        )
+    };
+
+    assert_success(&link(&["--export=test_helper"]));
+
+    let output = link(&["--export=test_helper,test_missing"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("calls to undefined functions: test_missing calls missing"),
+        "{stderr}"
+    );
+
+    assert_success(&link(&[
+        "--export=test_helper,test_missing",
+        "--allow-undefined=miss*",
+    ]));
+}
+
+#[test]
+fn test_allow_undefined_kfuncs() {
+    let dir = test_dir("allow-undefined-kfuncs");
+    let input = dir.join("input.ll");
+    let output = dir.join("output.ll");
+    let mut ir = create_test_ir_module("allow_undefined_kfuncs", &[]);
+    ir.push_str(
+        r#"
+declare ptr @bpf_task_acquire(ptr) section ".ksyms"
+declare void @bpf_rcu_read_lock() section ".ksyms"
+
+define ptr @test_kfunc(ptr %task) {
+entry:
+  %acquired = call ptr @bpf_task_acquire(ptr %task)
+  ret ptr %acquired
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str]| {
+        bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_kfunc"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        )
+    };
+
+    // Unlike the helpers, the kfuncs must be allowed explicitly.
+    let result = link(&[]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("calls to undefined functions: test_kfunc calls bpf_task_acquire"),
+        "{stderr}"
+    );
+
+    assert_success(&link(&[
+        "--allow-undefined=bpf_task_acquire,bpf_rcu_read_lock",
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    // The declaration of an allowed kfunc survives even when it's unreferenced.
+    for kfunc in ["bpf_task_acquire", "bpf_rcu_read_lock"] {
+        assert!(
+            ir.lines()
+                .any(|line| line.starts_with("declare ") && line.contains(&format!("@{kfunc}("))),
+            "{kfunc}: {ir}"
+        );
+    }
+
+    let allowed = dir.join("allowed.txt");
+    fs::write(&allowed, "# kfuncs\nbpf_task_*\n").unwrap();
+    assert_success(&link(&[&format!(
+        "--allow-undefined-file={}",
+        allowed.display()
+    )]));
+}
+
+#[test]
+fn test_probestack_asm() {
+    let dir = test_dir("probestack-asm");
+    let input = dir.join("input.ll");
+    let output = dir.join("output.ll");
+    let mut ir = create_test_ir_module("probestack_asm", &["test_probestack"]);
+    ir.push_str(
+        r#"
+module asm ".pushsection .text.__rust_probestack"
+module asm ".globl __rust_probestack"
+module asm "__rust_probestack:"
+module asm "    pushq %rbp"
+module asm "    popq %rbp"
+module asm "    retq"
+module asm ".popsection"
+module asm ".globl test_marker"
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_probestack"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    let asm: Vec<_> = ir
+        .lines()
+        .filter(|line| line.starts_with("module asm "))
+        .collect();
+    assert_eq!(asm, [r#"module asm ".globl test_marker""#], "{ir}");
+}
+
+#[test]
+fn test_wrap() {
+    let dir = test_dir("wrap");
+    let helper = dir.join("helper.ll");
+    let mut ir = create_test_ir_module("wrap_helper", &["helper"]);
+    ir.push_str(
+        r#"
+declare i32 @__real_helper(i32)
+
+define i32 @__wrap_helper(i32 %x) {
+entry:
+  %result = call i32 @__real_helper(i32 %x)
+  %wrapped = mul i32 %result, 2
+  ret i32 %wrapped
+}
+"#,
+    );
+    fs::write(&helper, ir).unwrap();
+    let caller = dir.join("caller.ll");
+    let mut ir = create_test_ir_module("wrap_caller", &[]);
+    ir.push_str(
+        r#"
+declare i32 @helper(i32)
+
+define i32 @test_wrap(i32 %x) {
+entry:
+  %result = call i32 @helper(i32 %x)
+  ret i32 %result
+}
+"#,
+    );
+    fs::write(&caller, ir).unwrap();
+
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        let result = bpf_linker(
+            [
+                helper.as_os_str(),
+                caller.as_os_str(),
+                OsStr::new("--export=test_wrap"),
+                // Without inlining, so that the calls stay observable.
+                OsStr::new("--passes=globaldce"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("--log-level=warn"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        );
+        assert_success(&result);
+        (
+            fs::read_to_string(output).unwrap(),
+            String::from_utf8_lossy(&result.stderr).into_owned(),
+        )
+    };
+
+    let (ir, _) = link(&["--wrap=helper"], "wrap.ll");
+    assert!(ir.contains("call i32 @__wrap_helper(i32 %x)"), "{ir}");
+    assert!(ir.contains("call i32 @helper(i32 %x)"), "{ir}");
+    assert!(!ir.contains("__real_helper"), "{ir}");
+
+    let (ir, _) = link(&[], "nowrap.ll");
+    assert!(!ir.contains("call i32 @__wrap_helper"), "{ir}");
+
+    // Wrapping a symbol which isn't in the module is only a warning.
+    let (_, stderr) = link(&["--wrap=missing"], "missing.ll");
+    assert!(
+        stderr.contains("`missing` is not in the linked module"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_remove_attr() {
+    let dir = test_dir("remove-attr");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("remove_attr", &[]);
+    ir.push_str(
+        r#"
+define i32 @test_optnone(i32 %x) #0 {
+entry:
+  %result = add i32 %x, 1
+  ret i32 %result
+}
+
+attributes #0 = { noinline optnone }
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let link = |args: &[&str], output: &str| {
+        let output = dir.join(output);
+        let result = bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_optnone"),
+                OsStr::new("--emit=llvm-ir"),
+                OsStr::new("--log-level=warn"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        );
+        assert_success(&result);
+        (
+            fs::read_to_string(output).unwrap(),
+            String::from_utf8_lossy(&result.stderr).into_owned(),
+        )
+    };
+
+    let (ir, _) = link(&["--keep-optnone"], "kept.ll");
+    assert!(ir.contains("optnone"), "{ir}");
+
+    // `optnone` requires `noinline`, so both must go.
+    let (ir, _) = link(
+        &["--remove-attr", "optnone", "--remove-attr=noinline"],
+        "removed.ll",
+    );
+    assert!(!ir.contains("optnone"), "{ir}");
+    assert!(!ir.contains("noinline"), "{ir}");
+
+    let (_, stderr) = link(&["--remove-attr=no-such-attr"], "unknown.ll");
+    assert!(
+        stderr.contains("not removing the attribute `no-such-attr`"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_strip_optnone() {
+    let dir = test_dir("strip-optnone");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("strip_optnone", &[]);
+    ir.push_str(
+        r#"
+define i32 @test_optnone(i32 %x) #0 {
+entry:
+  %slot = alloca i32, align 4
+  store i32 %x, ptr %slot, align 4
+  %value = load i32, ptr %slot, align 4
+  %result = add i32 %value, 1
+  ret i32 %result
+}
+
+attributes #0 = { noinline nounwind optnone }
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    // Returns the number of instructions before and after optimization.
+    let instructions = |args: &[&str]| -> (usize, usize) {
+        let output = bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_optnone"),
+                OsStr::new("-O2"),
+                OsStr::new("--print-stats"),
+                OsStr::new("-o"),
+                dir.join("output.o").as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        );
+        assert_success(&output);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let line = stderr
+            .lines()
+            .find_map(|line| line.strip_prefix("instructions "))
+            .unwrap_or_else(|| panic!("missing instructions row: {stderr}"));
+        let counts: Vec<usize> = line
+            .split_whitespace()
+            .map(|count| count.parse().unwrap())
+            .collect();
+        match counts.as_slice() {
+            [pre_opt, post_opt] => (*pre_opt, *post_opt),
+            counts => panic!("unexpected instructions row {counts:?}: {stderr}"),
+        }
+    };
+
+    let (pre_opt, post_opt) = instructions(&["--keep-optnone"]);
+    assert_eq!(post_opt, pre_opt);
+    // Without optnone, the alloca, store and load get promoted to a register.
+    let (pre_opt, post_opt) = instructions(&[]);
+    assert!(post_opt < pre_opt, "{pre_opt} -> {post_opt}");
+}
+
+#[test]
+fn test_defsym() {
+    let dir = test_dir("defsym");
+    let input = dir.join("input.ll");
+    let output = dir.join("output.ll");
+    let mut ir = create_test_ir_module("defsym", &[]);
+    ir.push_str(
+        r#"
+@CONFIG_RATE = external global i32
+
+define i32 @test_rate() {
+entry:
+  %rate = load i32, ptr @CONFIG_RATE, align 4
+  ret i32 %rate
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    let link = |defsym: &str| {
+        bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_rate"),
+            OsStr::new("--defsym"),
+            OsStr::new(defsym),
+            OsStr::new("--emit=llvm-ir"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ])
+    };
+
+    assert_success(&link("CONFIG_RATE=0x64"));
+    let ir = fs::read_to_string(&output).unwrap();
+    assert!(ir.contains("ret i32 100"), "{ir}");
+    assert!(!ir.contains("load"), "{ir}");
+
+    let result = link("CONFIG_RATE=4294967296");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("doesn't fit in i32"), "{stderr}");
+
+    let result = link("CONFIG_RATE");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("invalid symbol definition"), "{stderr}");
+}
+
+#[test]
+fn test_dump_section() {
+    let Some(clang) = which::which_re(regex::Regex::new(r"^clang(-\d+)?$").unwrap())
+        .unwrap()
+        .next()
+    else {
+        eprintln!("skipping test_dump_section: clang not found");
+        return;
+    };
+
+    let dir = test_dir("dump-section");
+    let source = dir.join("input.c");
+    fs::write(
+        &source,
+        r#"__attribute__((section("license"), used)) char LICENSE[] = "GPL";
+int test_dump_section(int x) { return x + 1; }
+"#,
+    )
+    .unwrap();
+    let object = dir.join("input.o");
+    assert_success(
+        &Command::new(&clang)
+            .args(["-target", "bpf", "-O2", "-c", "-fembed-bitcode", "-o"])
+            .arg(&object)
+            .arg(&source)
+            .output()
+            .unwrap(),
+    );
+
+    let link = |section: &str, dump: &Path| {
+        bpf_linker([
+            object.as_os_str(),
+            OsStr::new("--export=test_dump_section"),
+            OsStr::new("--dump-section"),
+            OsStr::new(section),
+            dump.as_os_str(),
+            OsStr::new("-o"),
+            dir.join("output.o").as_os_str(),
+        ])
+    };
+
+    let dump = dir.join("license.bin");
+    assert_success(&link("license", &dump));
+    assert_eq!(fs::read(&dump).unwrap(), b"GPL\0");
+
+    let output = link("no_such_section", &dir.join("missing.bin"));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("no input object has a no_such_section section"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_strip_all() {
+    let dir = test_dir("strip-all");
+    let input = dir.join("input.ll");
+    let mut ir = create_test_ir_module("strip_all", &[]);
+    ir.push_str(
+        r#"
+define i32 @test_program(i32 %x) {
+entry:
+  %result = call i32 @test_helper(i32 %x)
+  ret i32 %result
+}
+
+define i32 @test_helper(i32 %x) noinline {
+entry:
+  %result = mul i32 %x, %x
+  ret i32 %result
+}
+"#,
+    );
+    fs::write(&input, ir).unwrap();
+
+    for (flag, has_helper) in [(None, true), (Some("--strip-all"), false)] {
+        let output = dir.join(format!(
+            "{}.o",
+            flag.unwrap_or("--default").trim_start_matches('-')
+        ));
+        assert_success(&bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=test_program"),
+                OsStr::new("-o"),
+                output.as_os_str(),
+            ]
+            .into_iter()
+            .chain(flag.map(OsStr::new)),
+        ));
+        let data = fs::read(&output).unwrap();
+        let object = object::File::parse(data.as_slice()).unwrap();
+        let names: Vec<_> = object
+            .symbols()
+            .filter_map(|symbol| symbol.name().ok())
+            .collect();
+        assert!(names.contains(&"test_program"), "{flag:?}: {names:?}");
+        assert_eq!(
+            names.contains(&"test_helper"),
+            has_helper,
+            "{flag:?}: {names:?}"
+        );
+    }
+}
+
+#[test]
+fn test_detect_input_kind() {
+    let dir = test_dir("detect-input-kind");
+    let ir = dir.join("first.ll");
+    fs::write(&ir, create_test_ir_content("wrapped")).unwrap();
+    let bitcode = dir.join("first.bc");
+    assert_success(&bpf_linker([
+        ir.as_os_str(),
+        OsStr::new("--export=test_wrapped"),
+        OsStr::new("--emit=llvm-bc"),
+        OsStr::new("-o"),
+        bitcode.as_os_str(),
+    ]));
+
+    // The bitcode wrapper header: the magic, the version, the offset and size of the bitcode and
+    // the CPU type.
+    let bitcode = fs::read(&bitcode).unwrap();
+    let mut wrapped = Vec::new();
+    for field in [0x0B17_C0DE, 0, 20, u32::try_from(bitcode.len()).unwrap(), 0] {
+        wrapped.extend(u32::to_le_bytes(field));
+    }
+    wrapped.extend(bitcode);
+    let wrapped_path = dir.join("wrapped.bin");
+    fs::write(&wrapped_path, wrapped).unwrap();
+
+    // Textual IR with an extension which says nothing about it, starting with a comment.
+    let ir = dir.join("second.dat");
+    fs::write(
+        &ir,
+        format!("; generated\n{}", create_test_ir_content("dat")),
+    )
+    .unwrap();
+
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        wrapped_path.as_os_str(),
+        ir.as_os_str(),
+        OsStr::new("--export=test_wrapped,test_dat"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    for function in ["test_wrapped", "test_dat"] {
+        assert!(find_definition(&ir, function).is_some(), "{function}: {ir}");
+    }
+
+    // Data without a known magic number is parsed as IR, which reports the actual error.
+    let garbage = dir.join("garbage.o");
+    fs::write(&garbage, "not a module\n").unwrap();
+    let result = bpf_linker([garbage.as_os_str(), OsStr::new("-o"), output.as_os_str()]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("failure parsing IR module"), "{stderr}");
+}
+
+#[test]
+fn test_diagnostics_color() {
+    let dir = test_dir("diagnostics-color");
+    // Conflicting module flags with the `Warning` (2) behavior make LLVM warn while linking the
+    // second input.
+    let mut inputs = Vec::new();
+    for (name, value) in [("first", 1), ("second", 2)] {
+        let mut ir = create_test_ir_module(name, &[&format!("test_{name}")]);
+        ir.push_str(&format!(
+            "\n!llvm.module.flags = !{{!0}}\n!0 = !{{i32 2, !\"bpf-linker-test\", i32 {value}}}\n"
+        ));
+        let input = dir.join(format!("{name}.ll"));
+        fs::write(&input, ir).unwrap();
+        inputs.push(input);
+    }
+    let run = |color: &str| {
+        let output = bpf_linker([
+            inputs[0].as_os_str(),
+            inputs[1].as_os_str(),
+            OsStr::new("--export=test_first,test_second"),
+            OsStr::new("--log-level=warn"),
+            OsStr::new(color),
+            OsStr::new("-o"),
+            dir.join("output.o").as_os_str(),
+        ]);
+        assert_success(&output);
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = run("--color=always");
+    assert!(
+        stderr.contains("\x1b[1;33mllvm warning\x1b[0m: "),
+        "{stderr}"
+    );
+    assert!(stderr.contains("bpf-linker-test"), "{stderr}");
+    // The warning is about the input being linked.
+    assert!(
+        stderr.contains(&format!("in input {}", inputs[1].display())),
+        "{stderr}"
+    );
+
+    let stderr = run("--color=never");
+    assert!(stderr.contains("llvm warning: "), "{stderr}");
+    assert!(!stderr.contains('\x1b'), "{stderr}");
+
+    let output = bpf_linker([inputs[0].as_os_str(), OsStr::new("--color=sometimes")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("color needs to be `auto`, `always` or `never`"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_message_format_json_error() {
+    let dir = test_dir("message-format-json-error");
+    let input = dir.join("input.ll");
+    fs::write(&input, "define i32 @broken( {\n").unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--message-format=json"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Only JSON is printed, one object per line.
+    assert!(
+        stderr
+            .lines()
+            .all(|line| line.starts_with('{') && line.ends_with('}')),
+        "{stderr}"
+    );
+    let error = stderr
+        .lines()
+        .rfind(|line| json_field(line, "level").as_deref() == Some("error"))
+        .unwrap_or_else(|| panic!("{stderr}"));
+    assert!(
+        json_field(error, "message")
+            .unwrap()
+            .contains("failure parsing IR module"),
+        "{error}"
+    );
+    assert_eq!(
+        json_field(error, "file"),
+        Some(input.display().to_string()),
+        "{error}"
+    );
+    assert_eq!(
+        json_field(error, "stage").as_deref(),
+        Some("parse"),
+        "{error}"
+    );
+
+    // The errors of the command line are printed as JSON too.
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--message-format"),
+        OsStr::new("json"),
+        OsStr::new("--color=sometimes"),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr.trim_end();
+    assert!(!line.contains('\n'), "{stderr}");
+    assert_eq!(
+        json_field(line, "level").as_deref(),
+        Some("error"),
+        "{line}"
+    );
+    assert!(
+        json_field(line, "message")
+            .unwrap()
+            .contains("color needs to be"),
+        "{line}"
+    );
+    assert_eq!(json_field(line, "stage"), None, "{line}");
+
+    // A `--message-format` given in a response file is honored too.
+    let response_file = dir.join("args.txt");
+    fs::write(&response_file, "--message-format=json\n").unwrap();
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new(&format!("@{}", response_file.display())),
+        OsStr::new("--color=sometimes"),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr.trim_end();
+    assert_eq!(
+        json_field(line, "level").as_deref(),
+        Some("error"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_export_file() {
+    let dir = test_dir("export-file");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("export_file", &["test_a", "test_b", "other"]),
+    )
+    .unwrap();
+    let symbols = dir.join("symbols.txt");
+    fs::write(
+        &symbols,
+        "# programs\ntest_a \t\r\n# test_b is exported by the glob\ntest_?\n",
+    )
+    .unwrap();
+
+    let output = dir.join("output.ll");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export-file"),
+        symbols.as_os_str(),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    for function in ["test_a", "test_b"] {
+        let definition = find_definition(&ir, function).unwrap_or_else(|| panic!("{ir}"));
+        assert!(!definition.contains("internal"), "{definition}");
+    }
+    assert!(
+        find_definition(&ir, "other").is_none_or(|definition| definition.contains("internal")),
+        "{ir}"
+    );
+}
+
+#[test]
+fn test_response_file() {
+    let dir = test_dir("response-file");
+    fs::write(dir.join("input.ll"), create_test_ir_content("foo")).unwrap();
+    fs::write(
+        dir.join("args.rsp"),
+        "--export test_foo input.ll -o response.o\n",
+    )
+    .unwrap();
+    fs::write(dir.join("inputs.rsp"), "input.ll\n").unwrap();
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_bpf-linker"));
+        let _: &mut Command = cmd.current_dir(&dir).args(args);
+        let output = cmd
+            .output()
+            .unwrap_or_else(|err| panic!("could not run {cmd:?}: {err}"));
+        assert_success(&output);
+    };
+    run(&["--export", "test_foo", "input.ll", "-o", "direct.o"]);
+    run(&["@args.rsp"]);
+    assert_eq!(
+        fs::read(dir.join("response.o")).unwrap(),
+        fs::read(dir.join("direct.o")).unwrap()
+    );
+
+    // The arguments of a response file are spliced in place of the `@` argument.
+    run(&["--export", "test_foo", "@inputs.rsp", "-o", "spliced.o"]);
+    assert_eq!(
+        fs::read(dir.join("spliced.o")).unwrap(),
+        fs::read(dir.join("direct.o")).unwrap()
+    );
+
+    let output = bpf_linker(["@missing.rsp"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("missing.rsp"), "{stderr}");
+}
+
+#[test]
+fn test_stdin_input() {
+    let dir = test_dir("stdin-input");
+    let file = dir.join("file.ll");
+    fs::write(&file, create_test_ir_content("file")).unwrap();
+
+    let run_with = |stdin: &str, args: &[&OsStr]| {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_bpf-linker"));
+        let _: &mut Command = cmd
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .unwrap_or_else(|err| panic!("could not run {cmd:?}: {err}"));
+        // The linker may exit without reading stdin, so writing to it is allowed to fail.
+        let _: Option<()> = child.stdin.take().unwrap().write_all(stdin.as_bytes()).ok();
+        child.wait_with_output().unwrap()
+    };
+    let run = |args: &[&OsStr]| run_with(&create_test_ir_content("stdin"), args);
+
+    let output = dir.join("output.ll");
+    assert_success(&run(&[
+        OsStr::new("-"),
+        file.as_os_str(),
+        OsStr::new("--export=test_stdin,test_file"),
+        OsStr::new("--emit=llvm-ir"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+    let ir = fs::read_to_string(&output).unwrap();
+    for function in ["test_stdin", "test_file"] {
+        let definition = find_definition(&ir, function)
+            .unwrap_or_else(|| panic!("{function} not found in {ir}"));
+        assert!(!definition.contains("internal"), "{definition}");
+    }
+
+    let result = run(&[
+        OsStr::new("-"),
+        OsStr::new("-"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]);
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("can only be given once"), "{stderr}");
+
+    let object = dir.join("output.o");
+    assert_success(&run(&[
+        OsStr::new("-"),
+        OsStr::new("--export=test_stdin"),
+        OsStr::new("-o"),
+        object.as_os_str(),
+    ]));
+    assert!(fs::read(&object).unwrap().starts_with(b"\x7fELF"));
+
+    // The parse errors point at stdin.
+    let result = run_with(
+        "define i32 @test_stdin( {\n",
+        &[OsStr::new("-"), OsStr::new("-o"), object.as_os_str()],
+    );
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("<stdin>:1:"), "{stderr}");
+}
+
+#[test]
+fn test_save_temps() {
+    let dir = test_dir("save-temps");
+    let first = dir.join("first.ll");
+    fs::write(&first, create_test_ir_content("first")).unwrap();
+    let second = dir.join("second.ll");
+    fs::write(&second, create_test_ir_content("second")).unwrap();
+
+    let temps = dir.join("temps");
+    let mut save_temps = OsString::from("--save-temps=");
+    save_temps.push(&temps);
+    assert_success(&bpf_linker([
+        first.as_os_str(),
+        second.as_os_str(),
+        OsStr::new("--export=test_first,test_second"),
+        &save_temps,
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]));
+
+    for (file_name, function) in [("first.0.ll", "test_first"), ("second.1.ll", "test_second")] {
+        let ir = fs::read_to_string(temps.join(file_name)).unwrap();
+        assert!(find_definition(&ir, function).is_some(), "{ir}");
+    }
+    for stage in ["post-link", "post-disanitize", "post-opt"] {
+        let ir = fs::read_to_string(temps.join(format!("output.{stage}.ll"))).unwrap();
+        assert!(ir.contains("target triple = \"bpf"), "{ir}");
+        assert!(find_definition(&ir, "test_first").is_some(), "{ir}");
+        let bitcode = fs::read(temps.join(format!("output.{stage}.bc"))).unwrap();
+        assert!(bitcode.starts_with(b"BC\xc0\xde"), "{stage}");
+    }
+}
+
+#[test]
+fn test_export_glob() {
+    let dir = test_dir("export-glob");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module(
+            "export_glob",
+            &[
+                "handle_event_0",
+                "handle_event_1",
+                "handle_event_10",
+                "other",
+            ],
+        ),
+    )
+    .unwrap();
+
+    let link = |export: &str| {
+        let output = dir.join("output.ll");
+        assert_success(&bpf_linker([
+            input.as_os_str(),
+            OsStr::new(export),
+            OsStr::new("--emit=llvm-ir"),
+            OsStr::new("-o"),
+            output.as_os_str(),
+        ]));
+        fs::read_to_string(&output).unwrap()
+    };
+    let is_exported = |ir: &str, function| {
+        find_definition(ir, function).is_some_and(|definition| !definition.contains("internal"))
+    };
+
+    let ir = link("--export=handle_event_*");
+    for function in ["handle_event_0", "handle_event_1", "handle_event_10"] {
+        assert!(is_exported(&ir, function), "{function}: {ir}");
+    }
+    assert!(!is_exported(&ir, "other"), "{ir}");
+
+    let ir = link("--export=handle_event_?");
+    assert!(is_exported(&ir, "handle_event_1"), "{ir}");
+    assert!(!is_exported(&ir, "handle_event_10"), "{ir}");
+
+    // A pattern matching nothing is not an error, unlike an undefined exact symbol.
+    let ir = link("--export=no_such_*");
+    for function in ["handle_event_0", "other"] {
+        assert!(!is_exported(&ir, function), "{function}: {ir}");
+    }
+}
+
+#[test]
+fn test_unmatched_export_patterns() {
+    let dir = test_dir("unmatched-export-patterns");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        create_test_ir_module("unmatched_export_patterns", &["handle_event"]),
+    )
+    .unwrap();
+
+    let link = |args: &[&str]| {
+        bpf_linker(
+            [
+                input.as_os_str(),
+                OsStr::new("--export=handle_*,hadnle_*"),
+                OsStr::new("--export-regex=event_[0-9]+"),
+                OsStr::new("--log-level=warn"),
+                OsStr::new("-o"),
+                dir.join("output.o").as_os_str(),
+            ]
+            .into_iter()
+            .chain(args.iter().map(OsStr::new)),
+        )
+    };
+
+    let output = link(&[]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("export pattern hadnle_* matches no symbol"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("export pattern event_[0-9]+ matches no symbol"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("pattern handle_*"), "{stderr}");
+
+    let output = link(&["--strict-exports"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("export patterns match no symbol: hadnle_*, event_[0-9]+"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_output_to_stdout() {
+    let dir = test_dir("output-stdout");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("stdout")).unwrap();
+
+    let link = |emit: &str| {
+        let output = bpf_linker([
+            input.as_os_str(),
+            OsStr::new("--export=test_stdout"),
+            OsStr::new(emit),
+            OsStr::new("--log-level=info"),
+            OsStr::new("--log-file"),
+            dir.join("log.txt").as_os_str(),
+            OsStr::new("-o"),
+            OsStr::new("-"),
+        ]);
+        assert_success(&output);
+        output.stdout
+    };
+
+    assert!(link("--emit=obj").starts_with(b"\x7fELF"));
+    let ir = String::from_utf8(link("--emit=llvm-ir")).unwrap();
+    assert!(find_definition(&ir, "test_stdout").is_some(), "{ir}");
+    let asm = String::from_utf8(link("--emit=asm")).unwrap();
+    assert!(asm.contains("test_stdout:"), "{asm}");
+}
+
+#[test]
+fn test_symbol_manifest() {
+    let dir = test_dir("symbol-manifest");
+    let input = dir.join("input.ll");
+    fs::write(
+        &input,
+        r#"target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel-unknown-none"
+
+@counter = global i32 0
+@map = global i32 0
+
+declare i32 @helper(i32)
+
+define i32 @prog(i32 %x) {
+entry:
+  %y = call i32 @helper(i32 %x)
+  ret i32 %y
+}
+
+define i32 @unused(i32 %x) {
+entry:
+  ret i32 %x
+}
+"#,
+    )
+    .unwrap();
+
+    let manifest = dir.join("manifest.json");
+    assert_success(&bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=prog,map"),
+        OsStr::new("--allow-undefined=helper"),
+        OsStr::new("--emit-symbol-manifest"),
+        manifest.as_os_str(),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]));
+
+    let manifest = fs::read_to_string(&manifest).unwrap();
+    for entry in [
+        r#"{"name": "counter", "kind": "global", "exported": false, "internalized": true, "undefined": false}"#,
+        r#"{"name": "map", "kind": "global", "exported": true, "internalized": false, "undefined": false}"#,
+        r#"{"name": "helper", "kind": "function", "exported": false, "internalized": false, "undefined": true}"#,
+        r#"{"name": "prog", "kind": "function", "exported": true, "internalized": false, "undefined": false}"#,
+        r#"{"name": "unused", "kind": "function", "exported": false, "internalized": true, "undefined": false}"#,
+    ] {
+        assert!(manifest.contains(entry), "{entry} not found in {manifest}");
+    }
+    assert!(manifest.starts_with('[') && manifest.trim_end().ends_with(']'));
+}
+
+#[test]
+fn test_dump_attributes() {
+    let dir = test_dir("dump-attributes");
+    let input = dir.join("input.ll");
+    fs::write(&input, create_test_ir_content("attributes")).unwrap();
+
+    let output = bpf_linker([
+        input.as_os_str(),
+        OsStr::new("--export=test_attributes"),
+        OsStr::new("--dump-attributes"),
+        OsStr::new("-o"),
+        dir.join("output.o").as_os_str(),
+    ]);
+    assert_success(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with("test_attributes: "))
+        .unwrap_or_else(|| panic!("test_attributes not found in {stderr}"));
+    for attribute in ["noinline", "nounwind", "optnone"] {
+        assert!(line.split(' ').any(|a| a == attribute), "{line}");
     }
 }